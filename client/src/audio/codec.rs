@@ -0,0 +1,141 @@
+//! IMA ADPCM, squeezes 16 bit samples into 4 bits each. Every packet starts with the coder state
+//! so packets can be decoded on their own, a lost packet doesn't garble the ones after it.
+//!
+//! ```text
+//! [ predictor ][ step index ][ reserved ][ 2 samples per byte, low nibble first ]
+//! [  i16 LE   ][     u8     ][    u8    ][                  x                   ]
+//! ```
+
+const HEADER_LEN: usize = 4;
+
+const INDEX_TABLE: [i32; 16] = [-1, -1, -1, -1, 2, 4, 6, 8, -1, -1, -1, -1, 2, 4, 6, 8];
+
+const STEP_TABLE: [i32; 89] = [
+    7, 8, 9, 10, 11, 12, 13, 14, 16, 17, 19, 21, 23, 25, 28, 31, 34, 37, 41, 45, 50, 55, 60, 66,
+    73, 80, 88, 97, 107, 118, 130, 143, 157, 173, 190, 209, 230, 253, 279, 307, 337, 371, 408, 449,
+    494, 544, 598, 658, 724, 796, 876, 963, 1060, 1166, 1282, 1411, 1552, 1707, 1878, 2066, 2272,
+    2499, 2749, 3024, 3327, 3660, 4026, 4428, 4871, 5358, 5894, 6484, 7132, 7845, 8630, 9493,
+    10442, 11487, 12635, 13899, 15289, 16818, 18500, 20350, 22385, 24623, 27086, 29794, 32767,
+];
+
+/// Where the coder is up to, carried from one packet to the next by the encoder.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct AdpcmState {
+    predictor: i16,
+    index: u8,
+}
+
+impl AdpcmState {
+    /// Apply `nibble` and return the sample it decodes to.
+    fn step(&mut self, nibble: u8) -> i16 {
+        let step = STEP_TABLE[self.index as usize];
+        let mut delta = step >> 3;
+        if nibble & 4 != 0 {
+            delta += step;
+        }
+        if nibble & 2 != 0 {
+            delta += step >> 1;
+        }
+        if nibble & 1 != 0 {
+            delta += step >> 2;
+        }
+        let predictor = if nibble & 8 != 0 {
+            self.predictor as i32 - delta
+        } else {
+            self.predictor as i32 + delta
+        };
+        self.predictor = predictor.clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+        self.index = (self.index as i32 + INDEX_TABLE[nibble as usize]).clamp(0, 88) as u8;
+        self.predictor
+    }
+
+    fn nibble_for(&self, sample: i16) -> u8 {
+        let step = STEP_TABLE[self.index as usize];
+        let mut diff = sample as i32 - self.predictor as i32;
+        let mut nibble = 0;
+        if diff < 0 {
+            nibble = 8;
+            diff = -diff;
+        }
+        if diff >= step {
+            nibble |= 4;
+            diff -= step;
+        }
+        if diff >= step >> 1 {
+            nibble |= 2;
+            diff -= step >> 1;
+        }
+        if diff >= step >> 2 {
+            nibble |= 1;
+        }
+        nibble
+    }
+}
+
+/// Compress `samples`, an odd sample count is padded with one more.
+pub fn encode(state: &mut AdpcmState, samples: &[i16]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(HEADER_LEN + samples.len().div_ceil(2));
+    packet.extend(state.predictor.to_le_bytes());
+    packet.extend([state.index, 0]);
+    for pair in samples.chunks(2) {
+        let mut byte = 0;
+        for (i, &sample) in pair.iter().enumerate() {
+            let nibble = state.nibble_for(sample);
+            // stay in step with what the decoder will see
+            state.step(nibble);
+            byte |= nibble << (4 * i);
+        }
+        packet.push(byte);
+    }
+    packet
+}
+
+/// Decompress a packet made by `encode`, `None` if it's too short to have come from there.
+pub fn decode(packet: &[u8]) -> Option<Vec<i16>> {
+    if packet.len() < HEADER_LEN || packet[2] > 88 {
+        return None;
+    }
+    let mut state = AdpcmState {
+        predictor: i16::from_le_bytes([packet[0], packet[1]]),
+        index: packet[2],
+    };
+    let mut samples = Vec::with_capacity((packet.len() - HEADER_LEN) * 2);
+    for byte in &packet[HEADER_LEN..] {
+        samples.push(state.step(byte & 0x0f));
+        samples.push(state.step(byte >> 4));
+    }
+    Some(samples)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(len: usize, offset: usize) -> Vec<i16> {
+        (offset..offset + len)
+            .map(|i| ((i as f32 * 0.05).sin() * 12_000.0) as i16)
+            .collect()
+    }
+
+    #[test]
+    fn round_trip_stays_close() {
+        let mut state = AdpcmState::default();
+        // the first packet is spent catching up with the signal
+        encode(&mut state, &sine(320, 0));
+        let samples = sine(320, 320);
+        let packet = encode(&mut state, &samples);
+        assert_eq!(packet.len(), HEADER_LEN + 160);
+
+        let decoded = decode(&packet).unwrap();
+        assert_eq!(decoded.len(), samples.len());
+        for (original, decoded) in samples.iter().zip(decoded) {
+            assert!((*original as i32 - decoded as i32).abs() < 600);
+        }
+    }
+
+    #[test]
+    fn garbage_is_rejected() {
+        assert_eq!(decode(&[1, 2]), None);
+        assert_eq!(decode(&[0, 0, 200, 0, 0x77]), None);
+    }
+}
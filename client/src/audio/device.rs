@@ -0,0 +1,358 @@
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{FromSample, Sample, SampleFormat, SizedSample, StreamConfig};
+use protocol::{AUDIO_FRAME_SAMPLES, AUDIO_SAMPLE_RATE};
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+use super::{Encoder, Mixer};
+
+/// How long each `AUDIO_FRAME_SAMPLES` frame lasts.
+const FRAME_DURATION: Duration =
+    Duration::from_micros(AUDIO_FRAME_SAMPLES as u64 * 1_000_000 / AUDIO_SAMPLE_RATE as u64);
+
+#[derive(Debug, Error)]
+pub enum AudioError {
+    #[error("no audio device called {0}")]
+    NoDevice(String),
+    #[error("{0} samples aren't supported")]
+    UnsupportedFormat(SampleFormat),
+    #[error("audio device error: {0}")]
+    Device(String),
+    #[error("could not read {0}: {1}")]
+    Wav(PathBuf, hound::Error),
+}
+
+impl AudioError {
+    fn device(e: impl std::fmt::Display) -> Self {
+        AudioError::Device(e.to_string())
+    }
+}
+
+/// Called from the audio thread when a device reports a problem after it started.
+pub type OnError = Box<dyn FnMut(AudioError) + Send>;
+
+/// Keeps audio capture running, stop it by dropping this.
+pub enum AudioInput {
+    Device(cpal::Stream),
+    /// A WAV file played in a loop, stops once the connection is gone.
+    File(JoinHandle<()>),
+}
+
+/// Keeps playback running, stop it by dropping this.
+pub enum AudioOutput {
+    Device(cpal::Stream),
+    /// Mixes everything and throws it away, for machines without speakers.
+    Null(JoinHandle<()>),
+}
+
+/// Start capturing from `spec`, which is `none`, `default`, the name of an input device or the
+/// path of a `.wav` file to loop instead of a microphone. Problems once it's running go to
+/// `on_error`.
+pub fn start_input(
+    spec: &str,
+    encoder: Encoder,
+    on_error: OnError,
+) -> Result<Option<AudioInput>, AudioError> {
+    if spec == "none" {
+        return Ok(None);
+    }
+    if spec.ends_with(".wav") {
+        return start_file_input(Path::new(spec), encoder).map(Some);
+    }
+
+    let host = cpal::default_host();
+    let device = if spec == "default" {
+        host.default_input_device()
+    } else {
+        host.input_devices()
+            .map_err(AudioError::device)?
+            .find(|device| device.name().map(|name| name == spec).unwrap_or(false))
+    }
+    .ok_or_else(|| AudioError::NoDevice(String::from(spec)))?;
+    let supported = device.default_input_config().map_err(AudioError::device)?;
+    let config = supported.config();
+    let stream = match supported.sample_format() {
+        SampleFormat::F32 => build_input_stream::<f32>(&device, &config, encoder, on_error),
+        SampleFormat::I16 => build_input_stream::<i16>(&device, &config, encoder, on_error),
+        SampleFormat::U16 => build_input_stream::<u16>(&device, &config, encoder, on_error),
+        format => return Err(AudioError::UnsupportedFormat(format)),
+    }?;
+    stream.play().map_err(AudioError::device)?;
+    Ok(Some(AudioInput::Device(stream)))
+}
+
+fn build_input_stream<T>(
+    device: &cpal::Device,
+    config: &StreamConfig,
+    mut encoder: Encoder,
+    mut on_error: OnError,
+) -> Result<cpal::Stream, AudioError>
+where
+    T: SizedSample,
+    f32: FromSample<T>,
+{
+    let channels = config.channels as usize;
+    let mut resampler = Resampler::new(config.sample_rate.0, AUDIO_SAMPLE_RATE);
+    let mut mono = Vec::new();
+    let mut resampled = Vec::new();
+    device
+        .build_input_stream(
+            config,
+            move |data: &[T], _: &cpal::InputCallbackInfo| {
+                mono.clear();
+                mono.extend(data.chunks(channels).map(|frame| {
+                    frame.iter().map(|&s| f32::from_sample(s)).sum::<f32>() / channels as f32
+                }));
+                resampled.clear();
+                resampler.process(&mono, &mut resampled);
+                let samples: Vec<i16> = resampled.iter().map(|&s| to_i16(s)).collect();
+                encoder.push(&samples);
+            },
+            move |e| on_error(AudioError::device(e)),
+            None,
+        )
+        .map_err(AudioError::device)
+}
+
+fn start_file_input(path: &Path, mut encoder: Encoder) -> Result<AudioInput, AudioError> {
+    let samples = read_wav(path)?;
+    if samples.is_empty() {
+        return Err(AudioError::Wav(
+            path.to_path_buf(),
+            hound::Error::FormatError("no samples"),
+        ));
+    }
+    let handle = thread::spawn(move || {
+        // pace it like a real microphone would
+        let mut next_frame = Instant::now();
+        for frame in samples.chunks(AUDIO_FRAME_SAMPLES).cycle() {
+            if !encoder.push(frame) {
+                return;
+            }
+            next_frame += FRAME_DURATION;
+            thread::sleep(next_frame.saturating_duration_since(Instant::now()));
+        }
+    });
+    Ok(AudioInput::File(handle))
+}
+
+/// The whole of a WAV file as mono at `AUDIO_SAMPLE_RATE`.
+fn read_wav(path: &Path) -> Result<Vec<i16>, AudioError> {
+    let wav_error = |e| AudioError::Wav(path.to_path_buf(), e);
+    let mut reader = hound::WavReader::open(path).map_err(wav_error)?;
+    let spec = reader.spec();
+    let samples: Vec<f32> = match spec.sample_format {
+        hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>(),
+        hound::SampleFormat::Int => {
+            let scale = (1_i64 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .samples::<i32>()
+                .map(|s| s.map(|s| s as f32 / scale))
+                .collect::<Result<_, _>>()
+        }
+    }
+    .map_err(wav_error)?;
+
+    let channels = spec.channels as usize;
+    let mono: Vec<f32> = samples
+        .chunks(channels)
+        .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+        .collect();
+    let mut resampled = Vec::new();
+    Resampler::new(spec.sample_rate, AUDIO_SAMPLE_RATE).process(&mono, &mut resampled);
+    Ok(resampled.into_iter().map(to_i16).collect())
+}
+
+/// Start playing everything `mixer` has to `spec`, which is `null`, `default` or the name of an
+/// output device. Problems once it's running go to `on_error`.
+pub fn start_output(
+    spec: &str,
+    mixer: Mixer,
+    on_error: OnError,
+) -> Result<AudioOutput, AudioError> {
+    if spec == "null" {
+        return Ok(AudioOutput::Null(start_null_output(mixer)));
+    }
+
+    let host = cpal::default_host();
+    let device = if spec == "default" {
+        host.default_output_device()
+    } else {
+        host.output_devices()
+            .map_err(AudioError::device)?
+            .find(|device| device.name().map(|name| name == spec).unwrap_or(false))
+    }
+    .ok_or_else(|| AudioError::NoDevice(String::from(spec)))?;
+    let supported = device.default_output_config().map_err(AudioError::device)?;
+    let config = supported.config();
+    let stream = match supported.sample_format() {
+        SampleFormat::F32 => build_output_stream::<f32>(&device, &config, mixer, on_error),
+        SampleFormat::I16 => build_output_stream::<i16>(&device, &config, mixer, on_error),
+        SampleFormat::U16 => build_output_stream::<u16>(&device, &config, mixer, on_error),
+        format => return Err(AudioError::UnsupportedFormat(format)),
+    }?;
+    stream.play().map_err(AudioError::device)?;
+    Ok(AudioOutput::Device(stream))
+}
+
+fn build_output_stream<T>(
+    device: &cpal::Device,
+    config: &StreamConfig,
+    mixer: Mixer,
+    mut on_error: OnError,
+) -> Result<cpal::Stream, AudioError>
+where
+    T: SizedSample + FromSample<f32>,
+{
+    let channels = config.channels as usize;
+    let mut resampler = Resampler::new(AUDIO_SAMPLE_RATE, config.sample_rate.0);
+    // mixed samples at the device's rate that didn't fit in the last callback
+    let mut ready = VecDeque::new();
+    let mut frame = [0; AUDIO_FRAME_SAMPLES];
+    let mut resampled = Vec::new();
+    device
+        .build_output_stream(
+            config,
+            move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+                while ready.len() < data.len() / channels {
+                    mixer.mix(&mut frame);
+                    let mono: Vec<f32> = frame.iter().map(|&s| s as f32 / 32768.0).collect();
+                    resampled.clear();
+                    resampler.process(&mono, &mut resampled);
+                    ready.extend(resampled.iter());
+                }
+                for out in data.chunks_mut(channels) {
+                    let sample = T::from_sample(ready.pop_front().unwrap_or(0.0));
+                    out.fill(sample);
+                }
+            },
+            move |e| on_error(AudioError::device(e)),
+            None,
+        )
+        .map_err(AudioError::device)
+}
+
+fn start_null_output(mixer: Mixer) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut frame = [0; AUDIO_FRAME_SAMPLES];
+        let mut next_frame = Instant::now();
+        loop {
+            mixer.mix(&mut frame);
+            next_frame += FRAME_DURATION;
+            thread::sleep(next_frame.saturating_duration_since(Instant::now()));
+        }
+    })
+}
+
+fn to_i16(sample: f32) -> i16 {
+    (sample * 32768.0).clamp(i16::MIN as f32, i16::MAX as f32) as i16
+}
+
+/// Linear interpolation from one sample rate to another, picking up where it left off between
+/// calls so consecutive blocks join up.
+struct Resampler {
+    /// Input samples to move on by for each output sample.
+    step: f64,
+    /// Where the next output sample falls, relative to the start of the next input block. -1 is
+    /// the last sample of the block before.
+    position: f64,
+    last: f32,
+}
+
+impl Resampler {
+    fn new(from_rate: u32, to_rate: u32) -> Self {
+        Resampler {
+            step: from_rate as f64 / to_rate as f64,
+            position: 0.0,
+            last: 0.0,
+        }
+    }
+
+    fn process(&mut self, input: &[f32], output: &mut Vec<f32>) {
+        if input.is_empty() {
+            return;
+        }
+        let last = self.last;
+        let sample = |i: isize| if i < 0 { last } else { input[i as usize] };
+        while self.position < (input.len() - 1) as f64 {
+            let i = self.position.floor();
+            let fraction = (self.position - i) as f32;
+            let (a, b) = (sample(i as isize), sample(i as isize + 1));
+            output.push(a + (b - a) * fraction);
+            self.position += self.step;
+        }
+        self.position -= input.len() as f64;
+        self.last = input[input.len() - 1];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::{ClientChatData, ClientNetworkData};
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    #[test]
+    fn resampling_keeps_the_rate_across_blocks() {
+        let mut resampler = Resampler::new(48_000, 16_000);
+        let mut output = Vec::new();
+        for block in 0..10 {
+            let input: Vec<f32> = (0..480).map(|i| (block * 480 + i) as f32).collect();
+            resampler.process(&input, &mut output);
+        }
+        assert!((1599..=1600).contains(&output.len()));
+        // a ramp stays a ramp, including over the block edges
+        for pair in output.windows(2) {
+            assert!((pair[1] - pair[0] - 3.0).abs() < 0.001);
+        }
+    }
+
+    #[test]
+    fn wav_input_reaches_the_mixer() {
+        let path = std::env::temp_dir().join(format!("tvc-audio-test-{}.wav", std::process::id()));
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 48_000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for _ in 0..48_000 / 10 * 2 {
+            writer.write_sample(8000_i16).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let (outgoing, mut sent) = tokio::sync::mpsc::unbounded_channel();
+        let input = start_input(
+            path.to_str().unwrap(),
+            Encoder::new(outgoing, Arc::new(AtomicBool::new(true))),
+            Box::new(|e| panic!("{}", e)),
+        )
+        .unwrap();
+        let mixer = Mixer::default();
+        for _ in 0..8 {
+            match sent.blocking_recv() {
+                Some(ClientNetworkData {
+                    chat_data: ClientChatData::AudioFrame(sequence, packet),
+                }) => mixer.push(1, sequence, &packet),
+                other => panic!("expected audio, got {:?}", other),
+            }
+        }
+        drop(sent);
+        if let Some(AudioInput::File(handle)) = input {
+            handle.join().unwrap();
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        let mut frame = [0; AUDIO_FRAME_SAMPLES];
+        // the coder needs a few frames to catch up with the level
+        for _ in 0..6 {
+            mixer.mix(&mut frame);
+        }
+        assert!(frame.iter().all(|&s| (s - 8000).abs() < 500));
+    }
+}
@@ -0,0 +1,247 @@
+use protocol::{UserId, AUDIO_FRAME_SAMPLES};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+use super::codec;
+
+/// Frames buffered before playback starts, and again after running dry, to ride out jitter.
+const TARGET_DEPTH: usize = 3;
+/// Frames buffered past which the oldest are dropped so latency can't keep growing.
+const MAX_DEPTH: usize = 25;
+
+/// Puts one speaker's frames back in order and plays them out at a steady rate.
+#[derive(Default)]
+struct JitterBuffer {
+    frames: BTreeMap<u64, Vec<i16>>,
+    /// Sequence number of the next frame to play, `None` while (re)buffering.
+    next: Option<u64>,
+    /// The last frame played, anything up to it arriving afterwards is too late, even while
+    /// we're buffering up again.
+    played: Option<u64>,
+    playing: Vec<i16>,
+    position: usize,
+}
+
+impl JitterBuffer {
+    fn push(&mut self, sequence: u64, samples: Vec<i16>) {
+        if self.played.is_some_and(|played| sequence <= played) {
+            // too late, we've already played past it
+            return;
+        }
+        self.frames.insert(sequence, samples);
+        while self.frames.len() > MAX_DEPTH {
+            self.frames.pop_first();
+            if self.next.is_some() {
+                self.next = self.frames.keys().next().copied();
+            }
+        }
+    }
+
+    /// Add the next `out.len()` samples onto `out`, leaving the rest alone if we run out.
+    fn mix_into(&mut self, out: &mut [i32]) {
+        for sample in out.iter_mut() {
+            if self.position >= self.playing.len() && !self.advance() {
+                return;
+            }
+            *sample += self.playing[self.position] as i32;
+            self.position += 1;
+        }
+    }
+
+    /// Move on to the next frame, false if there isn't one yet.
+    fn advance(&mut self) -> bool {
+        let first = match self.frames.keys().next() {
+            Some(&first) => first,
+            None => {
+                self.next = None;
+                return false;
+            }
+        };
+        let next = match self.next {
+            // the sender skipped ahead further than it's worth filling in
+            Some(next) if first <= next + MAX_DEPTH as u64 => next,
+            Some(_) => first,
+            None if self.frames.len() >= TARGET_DEPTH => first,
+            None => return false,
+        };
+        match self.frames.remove(&next) {
+            Some(frame) => self.playing = frame,
+            // anything missing by now was lost, play silence in its place
+            None => {
+                self.playing.clear();
+                self.playing.resize(AUDIO_FRAME_SAMPLES, 0);
+            }
+        }
+        self.position = 0;
+        self.played = Some(next);
+        self.next = Some(next + 1);
+        true
+    }
+}
+
+#[derive(Default)]
+struct Speakers {
+    buffers: HashMap<UserId, JitterBuffer>,
+    /// Where `mix` adds everyone up, kept so the audio thread doesn't allocate every time.
+    mixed: Vec<i32>,
+}
+
+/// Everyone we can hear, mixed down into one stream for the speakers.
+#[derive(Clone, Default)]
+pub struct Mixer {
+    speakers: Arc<Mutex<Speakers>>,
+}
+
+impl Mixer {
+    /// Queue an encoded frame from `user_id`, frames that don't decode are dropped.
+    pub fn push(&self, user_id: UserId, sequence: u64, packet: &[u8]) {
+        if let Some(samples) = codec::decode(packet) {
+            self.speakers
+                .lock()
+                .unwrap()
+                .buffers
+                .entry(user_id)
+                .or_default()
+                .push(sequence, samples);
+        }
+    }
+
+    /// Forget someone who's left, along with anything of theirs still waiting to play.
+    pub fn remove(&self, user_id: UserId) {
+        self.speakers.lock().unwrap().buffers.remove(&user_id);
+    }
+
+    pub fn clear(&self) {
+        self.speakers.lock().unwrap().buffers.clear();
+    }
+
+    /// Fill `out` with the next samples from everyone, silence where nobody's talking. This runs
+    /// on the audio thread, so rather than wait on frames being pushed it plays silence.
+    pub fn mix(&self, out: &mut [i16]) {
+        let mut speakers = match self.speakers.try_lock() {
+            Ok(speakers) => speakers,
+            Err(_) => {
+                out.fill(0);
+                return;
+            }
+        };
+        let Speakers { buffers, mixed } = &mut *speakers;
+        mixed.clear();
+        mixed.resize(out.len(), 0);
+        for speaker in buffers.values_mut() {
+            speaker.mix_into(mixed);
+        }
+        for (out, mixed) in out.iter_mut().zip(mixed.iter()) {
+            *out = (*mixed).clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::codec::{encode, AdpcmState};
+
+    fn packet(level: i16) -> Vec<u8> {
+        // steady levels take a few frames for the coder to reach, so start it there
+        let mut state = AdpcmState::default();
+        for _ in 0..4 {
+            encode(&mut state, &[level; AUDIO_FRAME_SAMPLES]);
+        }
+        encode(&mut state, &[level; AUDIO_FRAME_SAMPLES])
+    }
+
+    fn next_frame(mixer: &Mixer) -> i16 {
+        let mut out = [0; AUDIO_FRAME_SAMPLES];
+        mixer.mix(&mut out);
+        out[AUDIO_FRAME_SAMPLES / 2]
+    }
+
+    fn near(sample: i16, level: i16) -> bool {
+        (sample as i32 - level as i32).abs() < 500
+    }
+
+    #[test]
+    fn frames_are_reordered_after_buffering() {
+        let mixer = Mixer::default();
+        mixer.push(1, 1, &packet(2000));
+        mixer.push(1, 0, &packet(1000));
+        // still buffering
+        assert_eq!(next_frame(&mixer), 0);
+
+        mixer.push(1, 2, &packet(3000));
+        assert!(near(next_frame(&mixer), 1000));
+        assert!(near(next_frame(&mixer), 2000));
+        assert!(near(next_frame(&mixer), 3000));
+        // ran dry, so it buffers up again before playing anything else
+        assert_eq!(next_frame(&mixer), 0);
+        mixer.push(1, 3, &packet(4000));
+        assert_eq!(next_frame(&mixer), 0);
+    }
+
+    #[test]
+    fn lost_frames_are_silent_and_late_ones_dropped() {
+        let mixer = Mixer::default();
+        for sequence in [0, 2, 3] {
+            mixer.push(1, sequence, &packet(1000));
+        }
+        assert!(near(next_frame(&mixer), 1000));
+        assert_eq!(next_frame(&mixer), 0);
+        mixer.push(1, 1, &packet(1000));
+        assert!(near(next_frame(&mixer), 1000));
+        assert!(near(next_frame(&mixer), 1000));
+        assert_eq!(next_frame(&mixer), 0);
+    }
+
+    #[test]
+    fn late_frames_stay_dropped_while_buffering_again() {
+        let mixer = Mixer::default();
+        for sequence in 0..3 {
+            mixer.push(1, sequence, &packet(1000));
+        }
+        for _ in 0..3 {
+            assert!(near(next_frame(&mixer), 1000));
+        }
+        // ran dry, and these are behind what's been played
+        assert_eq!(next_frame(&mixer), 0);
+        mixer.push(1, 1, &packet(2000));
+        mixer.push(1, 2, &packet(2000));
+        mixer.push(1, 3, &packet(3000));
+        assert_eq!(next_frame(&mixer), 0);
+        mixer.push(1, 4, &packet(3000));
+        mixer.push(1, 5, &packet(3000));
+        assert!(near(next_frame(&mixer), 3000));
+    }
+
+    #[test]
+    fn mixing_plays_silence_instead_of_waiting_for_the_lock() {
+        let mixer = Mixer::default();
+        for sequence in 0..3 {
+            mixer.push(1, sequence, &packet(1000));
+        }
+        let held = mixer.speakers.lock().unwrap();
+        let mut out = [1; AUDIO_FRAME_SAMPLES];
+        mixer.mix(&mut out);
+        assert!(out.iter().all(|&sample| sample == 0));
+        drop(held);
+        assert!(near(next_frame(&mixer), 1000));
+    }
+
+    #[test]
+    fn speakers_are_mixed_without_wrapping() {
+        let mixer = Mixer::default();
+        for sequence in 0..3 {
+            mixer.push(1, sequence, &packet(1000));
+            mixer.push(2, sequence, &packet(2000));
+        }
+        assert!(near(next_frame(&mixer), 3000));
+
+        mixer.remove(2);
+        assert!(near(next_frame(&mixer), 1000));
+
+        for sequence in 0..3 {
+            mixer.push(3, sequence, &packet(32_000));
+        }
+        assert_eq!(next_frame(&mixer), i16::MAX);
+    }
+}
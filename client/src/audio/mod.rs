@@ -0,0 +1,64 @@
+//! Capturing our microphone, squeezing it down to send to the server, and playing everyone else
+//! back through a jitter buffer.
+
+use protocol::{ClientChatData, ClientNetworkData, AUDIO_FRAME_SAMPLES};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc::UnboundedSender;
+
+mod codec;
+mod device;
+mod jitter;
+
+pub use device::{start_input, start_output, AudioError, AudioInput, AudioOutput, OnError};
+pub use jitter::Mixer;
+
+use codec::AdpcmState;
+
+/// Cuts captured audio into `AUDIO_FRAME_SAMPLES` frames, compresses them and queues them for the
+/// server.
+pub struct Encoder {
+    outgoing: UnboundedSender<ClientNetworkData>,
+    /// Cleared while we're muted, the device keeps running so unmuting is instant.
+    unmuted: Arc<AtomicBool>,
+    state: AdpcmState,
+    sequence: u64,
+    pending: Vec<i16>,
+}
+
+impl Encoder {
+    pub fn new(outgoing: UnboundedSender<ClientNetworkData>, unmuted: Arc<AtomicBool>) -> Self {
+        Encoder {
+            outgoing,
+            unmuted,
+            state: AdpcmState::default(),
+            sequence: 0,
+            pending: Vec::with_capacity(AUDIO_FRAME_SAMPLES),
+        }
+    }
+
+    /// Add mono samples at `AUDIO_SAMPLE_RATE`, false once there's no connection task to send
+    /// them to.
+    pub fn push(&mut self, samples: &[i16]) -> bool {
+        if !self.unmuted.load(Ordering::Relaxed) {
+            // the listeners' jitter buffers run dry and rebuffer once we're back
+            self.pending.clear();
+            return !self.outgoing.is_closed();
+        }
+        for &sample in samples {
+            self.pending.push(sample);
+            if self.pending.len() == AUDIO_FRAME_SAMPLES {
+                let packet = codec::encode(&mut self.state, &self.pending);
+                self.pending.clear();
+                let data = ClientNetworkData {
+                    chat_data: ClientChatData::AudioFrame(self.sequence, packet),
+                };
+                self.sequence += 1;
+                if self.outgoing.send(data).is_err() {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+}
@@ -0,0 +1,65 @@
+/// How far back the server has sent the history of the room we're in, so the page before it
+/// can be asked for once the chat history is scrolled back to the top.
+#[derive(Default)]
+pub struct Backlog {
+    /// Id of the oldest stored message we've been sent.
+    oldest: Option<u64>,
+    /// Whether the server has anything from before `oldest`.
+    more: bool,
+    /// A page is on its way, so there's no point asking again.
+    waiting: bool,
+}
+
+impl Backlog {
+    /// Start over for a room we've just joined, the server sends its latest messages unasked.
+    pub fn reset(&mut self) {
+        *self = Backlog {
+            waiting: true,
+            ..Backlog::default()
+        };
+    }
+
+    /// Note a page of history, `oldest` being the id of its first message.
+    pub fn received(&mut self, oldest: Option<u64>, more: bool) {
+        if oldest.is_some() {
+            self.oldest = oldest;
+        }
+        self.more = more && self.oldest.is_some();
+        self.waiting = false;
+    }
+
+    /// The id to ask for messages from before, if there are any and we aren't already waiting
+    /// on them.
+    pub fn next_request(&mut self) -> Option<u64> {
+        if self.waiting || !self.more {
+            return None;
+        }
+        let oldest = self.oldest?;
+        self.waiting = true;
+        Some(oldest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pages_are_asked_for_one_at_a_time() {
+        let mut backlog = Backlog::default();
+        backlog.reset();
+        assert_eq!(backlog.next_request(), None);
+
+        backlog.received(Some(40), true);
+        assert_eq!(backlog.next_request(), Some(40));
+        assert_eq!(backlog.next_request(), None);
+
+        backlog.received(Some(10), false);
+        assert_eq!(backlog.next_request(), None);
+
+        // a room with nothing said in it yet
+        backlog.reset();
+        backlog.received(None, false);
+        assert_eq!(backlog.next_request(), None);
+    }
+}
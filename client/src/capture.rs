@@ -0,0 +1,114 @@
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread;
+
+use crate::frames::LatestFrames;
+use crate::video_source::{EncodedFrame, VideoSource, VideoSourceError};
+use crate::Event;
+
+/// Reported through `Event::Capture` whenever the camera starts or stops.
+#[derive(Debug)]
+pub enum CaptureStatus {
+    Started,
+    Stopped,
+    /// The source couldn't be opened or stopped working, it needs starting again by hand.
+    Failed(VideoSourceError),
+}
+
+enum Command {
+    Start,
+    Stop,
+}
+
+/// Handle to the thread capturing our own video, which stops when this is dropped.
+pub struct Capture {
+    commands: Sender<Command>,
+}
+
+type OpenSource = Box<dyn FnMut() -> Result<Option<Box<dyn VideoSource>>, VideoSourceError> + Send>;
+
+impl Capture {
+    /// Start the capture thread, stopped. Each `start` calls `open_source` afresh, so a camera
+    /// that was unplugged can be picked up again. Frames go into `frames` with an
+    /// `Event::UserInputFrame` whenever the UI has caught up with the last one.
+    pub(crate) fn spawn(
+        open_source: OpenSource,
+        frames: LatestFrames<(), EncodedFrame>,
+        events: mpsc::Sender<Event>,
+    ) -> Self {
+        let (commands, command_rx) = mpsc::channel();
+        thread::spawn(move || run(open_source, frames, events, command_rx));
+        Capture { commands }
+    }
+
+    pub fn start(&self) {
+        let _ = self.commands.send(Command::Start);
+    }
+
+    pub fn stop(&self) {
+        let _ = self.commands.send(Command::Stop);
+    }
+}
+
+fn run(
+    mut open_source: OpenSource,
+    frames: LatestFrames<(), EncodedFrame>,
+    events: mpsc::Sender<Event>,
+    commands: Receiver<Command>,
+) {
+    let mut source: Option<Box<dyn VideoSource>> = None;
+    loop {
+        // while stopped there's nothing to do but wait to be started
+        let command = match &source {
+            Some(_) => match commands.try_recv() {
+                Ok(command) => Some(command),
+                Err(TryRecvError::Empty) => None,
+                Err(TryRecvError::Disconnected) => return,
+            },
+            None => match commands.recv() {
+                Ok(command) => Some(command),
+                Err(_) => return,
+            },
+        };
+        let status = match command {
+            Some(Command::Start) if source.is_none() => match open_source() {
+                Ok(Some(opened)) => {
+                    source = Some(opened);
+                    Some(CaptureStatus::Started)
+                }
+                // video is turned off in the config
+                Ok(None) => None,
+                Err(e) => Some(CaptureStatus::Failed(e)),
+            },
+            Some(Command::Stop) if source.is_some() => {
+                source = None;
+                Some(CaptureStatus::Stopped)
+            }
+            _ => None,
+        };
+        if let Some(status) = status {
+            if events.send(Event::Capture(status)).is_err() {
+                return;
+            }
+        }
+
+        if let Some(capturing) = source.as_mut() {
+            // sources pace themselves, so this blocks until the next frame is due
+            let event = match capturing.next_frame() {
+                Ok(frame) => {
+                    if !frames.put((), frame) {
+                        // the UI hasn't picked up the last one yet, it'll get this instead
+                        continue;
+                    }
+                    Event::UserInputFrame
+                }
+                Err(e) => {
+                    source = None;
+                    Event::Capture(CaptureStatus::Failed(e))
+                }
+            };
+            if events.send(event).is_err() {
+                return;
+            }
+        }
+    }
+}
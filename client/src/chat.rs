@@ -0,0 +1,173 @@
+use chrono::prelude::*;
+use protocol::UserInfo;
+use std::fmt;
+use tui::{
+    style::{Color, Style},
+    text::Span,
+};
+
+use crate::util;
+
+pub enum ChatAuthor {
+    // notices from the client/server itself rather than another user, e.g. room changes
+    System,
+    User(UserInfo),
+    /// Something they did, from `/me`.
+    Action(UserInfo),
+    /// A direct message, only between the two of them.
+    Private {
+        from: UserInfo,
+        to: UserInfo,
+    },
+}
+
+pub struct ChatMessageInfo {
+    pub message: String,
+    pub is_pending: bool,
+    pub author: ChatAuthor,
+    pub uid: usize,
+    pub timestamp: DateTime<Utc>,
+    /// The room it was said in, `None` for notices that belong wherever we are.
+    pub room: Option<String>,
+}
+
+impl fmt::Display for ChatMessageInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let local_time = Local.from_utc_datetime(&self.timestamp.naive_local());
+        let (pm, hour) = local_time.hour12();
+        let minute = local_time.minute();
+        let seconds = local_time.second();
+        let time = format!(
+            "{:0>2}:{:0>2}:{:0>2}{}",
+            hour,
+            minute,
+            seconds,
+            if pm { "PM" } else { "AM" }
+        );
+        match &self.author {
+            ChatAuthor::System => write!(f, "[{time}] * {}", self.message),
+            ChatAuthor::Action(user) => write!(f, "[{time}] * {} {}", user.name, self.message),
+            ChatAuthor::Private { from, to } => {
+                write!(f, "[{time}] {} -> {}: {}", from.name, to.name, self.message)
+            }
+            ChatAuthor::User(user) => write!(
+                f,
+                "[{time}] {}: {}{}",
+                user.name,
+                self.message,
+                if self.is_pending { " (pending...)" } else { "" }
+            ),
+        }
+    }
+}
+
+impl ChatMessageInfo {
+    pub fn new(author: UserInfo, message: String, is_pending: bool) -> Self {
+        ChatMessageInfo {
+            message,
+            is_pending,
+            author: ChatAuthor::User(author),
+            uid: util::get_uid(),
+            timestamp: chrono::offset::Utc::now(),
+            room: None,
+        }
+    }
+
+    pub fn new_system(message: String) -> Self {
+        ChatMessageInfo {
+            message,
+            is_pending: false,
+            author: ChatAuthor::System,
+            uid: util::get_uid(),
+            timestamp: chrono::offset::Utc::now(),
+            room: None,
+        }
+    }
+
+    pub fn new_action(author: UserInfo, action: String, timestamp: DateTime<Utc>) -> Self {
+        ChatMessageInfo {
+            message: action,
+            is_pending: false,
+            author: ChatAuthor::Action(author),
+            uid: util::get_uid(),
+            timestamp,
+            room: None,
+        }
+    }
+
+    pub fn new_private(
+        from: UserInfo,
+        to: UserInfo,
+        message: String,
+        timestamp: DateTime<Utc>,
+    ) -> Self {
+        ChatMessageInfo {
+            message,
+            is_pending: false,
+            author: ChatAuthor::Private { from, to },
+            uid: util::get_uid(),
+            timestamp,
+            room: None,
+        }
+    }
+
+    pub fn new_with_all(
+        author: UserInfo,
+        message: String,
+        is_pending: bool,
+        uid: usize,
+        timestamp: DateTime<Utc>,
+    ) -> Self {
+        ChatMessageInfo {
+            message,
+            is_pending,
+            author: ChatAuthor::User(author),
+            uid,
+            timestamp,
+            room: None,
+        }
+    }
+
+    pub fn new_with_timestamp(
+        author: UserInfo,
+        message: String,
+        is_pending: bool,
+        timestamp: DateTime<Utc>,
+    ) -> Self {
+        ChatMessageInfo {
+            message,
+            is_pending,
+            author: ChatAuthor::User(author),
+            uid: util::get_uid(),
+            timestamp,
+            room: None,
+        }
+    }
+
+    /// Tie the message to `room`, so it's only shown while we're in there.
+    pub fn in_room(mut self, room: &str) -> Self {
+        self.room = Some(room.to_string());
+        self
+    }
+
+    pub fn shown_in(&self, room: &str) -> bool {
+        self.room.as_deref().is_none_or(|said_in| said_in == room)
+    }
+
+    pub fn to_line_spans(&self, line_width: usize) -> Vec<Span<'_>> {
+        textwrap::wrap(&self.to_string(), line_width)
+            .into_iter()
+            .map(|message| {
+                Span::styled(
+                    message.into_owned(),
+                    Style::default().fg(match self.author {
+                        ChatAuthor::System => Color::Yellow,
+                        ChatAuthor::User(_) if self.is_pending => Color::Gray,
+                        ChatAuthor::User(_) | ChatAuthor::Action(_) => Color::White,
+                        ChatAuthor::Private { .. } => Color::LightGreen,
+                    }),
+                )
+            })
+            .collect::<Vec<_>>()
+    }
+}
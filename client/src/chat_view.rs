@@ -0,0 +1,246 @@
+use std::ops::Range;
+
+/// A message's uid and how many lines it wraps to at the current width.
+pub type MessageLines = (usize, usize);
+
+/// Where the chat history is scrolled to and which message is selected.
+///
+/// The position is kept as a message and a line within it rather than a line number, so the same
+/// message stays at the top however the history rewraps when the terminal is resized.
+#[derive(Default)]
+pub struct ChatView {
+    /// The message at the top of the view and how many of its lines are scrolled past it, `None`
+    /// while following the newest messages.
+    anchor: Option<(usize, usize)>,
+    selected: Option<usize>,
+    /// Highest uid that has made it on screen, anything newer hasn't been seen yet.
+    newest_seen: usize,
+    new_below: usize,
+    /// The layout from the last draw, which scrolling works from.
+    layout: Vec<MessageLines>,
+    height: usize,
+}
+
+impl ChatView {
+    /// Lay out the messages for a view `height` lines tall, returning which of their lines fit.
+    pub fn visible(&mut self, layout: Vec<MessageLines>, height: usize) -> Range<usize> {
+        self.layout = layout;
+        self.height = height;
+        let top = self.top();
+        let bottom = std::cmp::min(top + height, self.total_lines());
+
+        let mut start = 0;
+        self.new_below = 0;
+        for &(uid, lines) in &self.layout {
+            if start < bottom && start + lines > top {
+                self.newest_seen = std::cmp::max(self.newest_seen, uid);
+            } else if start >= bottom && uid > self.newest_seen {
+                self.new_below += 1;
+            }
+            start += lines;
+        }
+        top..bottom
+    }
+
+    /// Messages that arrived below the view since it was scrolled up, as of the last draw.
+    pub fn new_below(&self) -> usize {
+        self.new_below
+    }
+
+    /// Whether the first line is in view, as of the last draw.
+    pub fn at_top(&self) -> bool {
+        self.top() == 0
+    }
+
+    pub fn is_following(&self) -> bool {
+        self.anchor.is_none()
+    }
+
+    /// Move the view `lines` down, or up if negative, going back to following the newest
+    /// messages once it reaches the bottom.
+    pub fn scroll_by(&mut self, lines: isize) {
+        let top = std::cmp::min(self.top().saturating_add_signed(lines), self.max_top());
+        self.anchor = self.anchor_at(top);
+    }
+
+    /// Scroll up a screen, keeping a line of what was there for context.
+    pub fn page_up(&mut self) {
+        self.scroll_by(-(self.page_len() as isize));
+    }
+
+    pub fn page_down(&mut self) {
+        self.scroll_by(self.page_len() as isize);
+    }
+
+    pub fn home(&mut self) {
+        self.anchor = self.anchor_at(0);
+    }
+
+    pub fn end(&mut self) {
+        self.anchor = None;
+    }
+
+    pub fn selected(&self) -> Option<usize> {
+        self.selected
+    }
+
+    /// Select `uid`, or let go of it if it already is.
+    pub fn toggle_selected(&mut self, uid: usize) {
+        self.selected = match self.selected {
+            Some(selected) if selected == uid => None,
+            _ => Some(uid),
+        };
+    }
+
+    pub fn clear_selection(&mut self) {
+        self.selected = None;
+    }
+
+    /// The message drawn `row` lines down from the top of the view.
+    pub fn uid_at(&self, row: usize) -> Option<usize> {
+        let line = self.top() + row;
+        let mut start = 0;
+        for &(uid, lines) in &self.layout {
+            if line < start + lines {
+                return Some(uid);
+            }
+            start += lines;
+        }
+        None
+    }
+
+    fn total_lines(&self) -> usize {
+        self.layout.iter().map(|(_, lines)| lines).sum()
+    }
+
+    fn max_top(&self) -> usize {
+        self.total_lines().saturating_sub(self.height)
+    }
+
+    fn page_len(&self) -> usize {
+        std::cmp::max(1, self.height.saturating_sub(1))
+    }
+
+    /// The first line in view.
+    fn top(&self) -> usize {
+        let (anchor_uid, anchor_line) = match self.anchor {
+            Some(anchor) => anchor,
+            None => return self.max_top(),
+        };
+        let mut start = 0;
+        for &(uid, lines) in &self.layout {
+            if uid == anchor_uid {
+                // the message may have wrapped onto fewer lines since
+                let line = std::cmp::min(anchor_line, lines.saturating_sub(1));
+                return std::cmp::min(start + line, self.max_top());
+            }
+            start += lines;
+        }
+        // whatever we were looking at is gone
+        self.max_top()
+    }
+
+    /// Where to anchor the view so `top` is the first line in it.
+    fn anchor_at(&self, top: usize) -> Option<(usize, usize)> {
+        if top >= self.max_top() {
+            return None;
+        }
+        let mut start = 0;
+        for &(uid, lines) in &self.layout {
+            if top < start + lines {
+                return Some((uid, top - start));
+            }
+            start += lines;
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Ten messages with uids 1 to 10, each `lines` long.
+    fn layout(lines: usize) -> Vec<MessageLines> {
+        (1..=10).map(|uid| (uid, lines)).collect()
+    }
+
+    #[test]
+    fn follows_the_bottom_until_scrolled() {
+        let mut view = ChatView::default();
+        assert_eq!(view.visible(layout(1), 4), 6..10);
+
+        view.scroll_by(-2);
+        assert!(!view.is_following());
+        assert_eq!(view.visible(layout(1), 4), 4..8);
+
+        // more arrives, the view stays put
+        let mut longer = layout(1);
+        longer.extend([(11, 1), (12, 1)]);
+        assert_eq!(view.visible(longer.clone(), 4), 4..8);
+        assert_eq!(view.new_below(), 2);
+
+        view.scroll_by(-100);
+        assert!(view.at_top());
+        view.scroll_by(100);
+        assert!(view.is_following());
+        assert!(!view.at_top());
+        assert_eq!(view.visible(longer, 4), 8..12);
+        assert_eq!(view.new_below(), 0);
+    }
+
+    #[test]
+    fn resizing_keeps_the_same_message_at_the_top() {
+        let mut view = ChatView::default();
+        view.visible(layout(2), 4);
+        view.home();
+        view.scroll_by(5);
+        // message 3, second line
+        assert_eq!(view.visible(layout(2), 4), 5..9);
+        assert_eq!(view.uid_at(0), Some(3));
+
+        // narrower, so every message takes three lines
+        assert_eq!(view.visible(layout(3), 4), 7..11);
+        assert_eq!(view.uid_at(0), Some(3));
+        // wider, down to one line each
+        assert_eq!(view.visible(layout(1), 4), 2..6);
+        assert_eq!(view.uid_at(0), Some(3));
+    }
+
+    #[test]
+    fn paging_and_jumping_to_the_ends() {
+        let mut view = ChatView::default();
+        view.visible(layout(1), 4);
+        view.page_up();
+        assert_eq!(view.visible(layout(1), 4), 3..7);
+        view.page_up();
+        view.page_up();
+        assert_eq!(view.visible(layout(1), 4), 0..4);
+        view.page_down();
+        assert_eq!(view.visible(layout(1), 4), 3..7);
+
+        view.end();
+        assert_eq!(view.visible(layout(1), 4), 6..10);
+        view.home();
+        assert_eq!(view.visible(layout(1), 4), 0..4);
+
+        // everything fits, so there's nowhere to scroll to
+        assert_eq!(view.visible(layout(1), 20), 0..10);
+        view.home();
+        assert!(view.is_following());
+    }
+
+    #[test]
+    fn selection_toggles() {
+        let mut view = ChatView::default();
+        view.toggle_selected(3);
+        assert_eq!(view.selected(), Some(3));
+        view.toggle_selected(4);
+        assert_eq!(view.selected(), Some(4));
+        view.toggle_selected(4);
+        assert_eq!(view.selected(), None);
+        view.toggle_selected(5);
+        view.clear_selection();
+        assert_eq!(view.selected(), None);
+    }
+}
@@ -0,0 +1,185 @@
+use protocol::MAX_MESSAGE_LEN;
+use thiserror::Error;
+
+/// What a line typed into the chat input asks for.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    /// Not a command, just something to say. A line starting `//` is sent with a single `/`.
+    Say(String),
+    Nick(String),
+    /// Room and passcode.
+    Join(String, Option<String>),
+    Leave,
+    Me(String),
+    /// Nickname and message.
+    Msg(String, String),
+    Who,
+    Mute,
+    Help,
+    Quit,
+}
+
+/// Every command, how to use it and what it does, for `/help` and usage errors.
+pub const COMMANDS: [(&str, &str); 9] = [
+    ("/nick <name>", "change your nickname"),
+    (
+        "/join <room> [passcode]",
+        "move to another room, a new one is locked with the passcode",
+    ),
+    ("/leave", "go back to the default room"),
+    ("/me <action>", "tell the room what you're doing"),
+    (
+        "/msg <nickname> <message>",
+        "send something only they will see, they have to be in this room",
+    ),
+    ("/who", "list who's in the room"),
+    ("/mute", "mute or unmute the microphone, like Alt-M"),
+    ("/help", "show this list"),
+    ("/quit", "leave, like Ctrl-C"),
+];
+
+#[derive(Debug, PartialEq, Eq, Error)]
+pub enum CommandError {
+    #[error("there's no /{0}, /help lists the commands")]
+    Unknown(String),
+    #[error("usage: {0}")]
+    Usage(&'static str),
+    #[error("that's {0} characters, messages can be at most {MAX_MESSAGE_LEN}")]
+    TooLong(usize),
+}
+
+/// Work out what `line` asks for, anything not starting with `/` is a message. Messages the
+/// server would turn away for being too long are caught here.
+pub fn parse(line: &str) -> Result<Command, CommandError> {
+    let command = parse_command(line)?;
+    let text = match &command {
+        Command::Say(text) | Command::Me(text) | Command::Msg(_, text) => text,
+        _ => return Ok(command),
+    };
+    match text.chars().count() {
+        len if len > MAX_MESSAGE_LEN => Err(CommandError::TooLong(len)),
+        _ => Ok(command),
+    }
+}
+
+fn parse_command(line: &str) -> Result<Command, CommandError> {
+    let command = match line.strip_prefix('/') {
+        Some(escaped) if escaped.starts_with('/') => return Ok(Command::Say(escaped.to_string())),
+        Some(command) => command,
+        None => return Ok(Command::Say(line.to_string())),
+    };
+    let (name, rest) = match command.split_once(char::is_whitespace) {
+        Some((name, rest)) => (name, rest.trim()),
+        None => (command, ""),
+    };
+    let name = name.to_lowercase();
+    let wrong_usage = || CommandError::Usage(usage(&name));
+    let mut args = rest.split_whitespace();
+
+    match name.as_str() {
+        "nick" if !rest.is_empty() => Ok(Command::Nick(rest.to_string())),
+        "join" => match (args.next(), args.next(), args.next()) {
+            (Some(room), passcode, None) => Ok(Command::Join(
+                room.to_string(),
+                passcode.map(str::to_string),
+            )),
+            _ => Err(wrong_usage()),
+        },
+        "me" if !rest.is_empty() => Ok(Command::Me(rest.to_string())),
+        "msg" => match rest.split_once(char::is_whitespace) {
+            Some((nickname, message)) if !message.trim().is_empty() => Ok(Command::Msg(
+                nickname.to_string(),
+                message.trim().to_string(),
+            )),
+            _ => Err(wrong_usage()),
+        },
+        "leave" if rest.is_empty() => Ok(Command::Leave),
+        "who" if rest.is_empty() => Ok(Command::Who),
+        "mute" if rest.is_empty() => Ok(Command::Mute),
+        "help" if rest.is_empty() => Ok(Command::Help),
+        "quit" if rest.is_empty() => Ok(Command::Quit),
+        "nick" | "me" | "leave" | "who" | "mute" | "help" | "quit" => Err(wrong_usage()),
+        _ => Err(CommandError::Unknown(name)),
+    }
+}
+
+/// The usage line for the command called `name`.
+fn usage(name: &str) -> &'static str {
+    COMMANDS
+        .iter()
+        .map(|(usage, _)| *usage)
+        .find(|usage| usage[1..].split(' ').next() == Some(name))
+        .expect("every command is listed")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_and_commands() {
+        assert_eq!(parse("hello"), Ok(Command::Say(String::from("hello"))));
+        assert_eq!(parse("//shrug"), Ok(Command::Say(String::from("/shrug"))));
+        assert_eq!(
+            parse("/nick  New Name "),
+            Ok(Command::Nick(String::from("New Name")))
+        );
+        assert_eq!(
+            parse("/JOIN friends hunter2"),
+            Ok(Command::Join(
+                String::from("friends"),
+                Some(String::from("hunter2"))
+            ))
+        );
+        assert_eq!(
+            parse("/join friends"),
+            Ok(Command::Join(String::from("friends"), None))
+        );
+        assert_eq!(parse("/me waves"), Ok(Command::Me(String::from("waves"))));
+        assert_eq!(
+            parse("/msg bob see you\nlater"),
+            Ok(Command::Msg(
+                String::from("bob"),
+                String::from("see you\nlater")
+            ))
+        );
+        assert_eq!(parse("/leave"), Ok(Command::Leave));
+        assert_eq!(parse("/who"), Ok(Command::Who));
+        assert_eq!(parse("/quit"), Ok(Command::Quit));
+    }
+
+    #[test]
+    fn mistakes_explain_themselves() {
+        assert_eq!(
+            parse("/join"),
+            Err(CommandError::Usage("/join <room> [passcode]"))
+        );
+        assert_eq!(
+            parse("/join a b c"),
+            Err(CommandError::Usage("/join <room> [passcode]"))
+        );
+        assert_eq!(
+            parse("/msg bob"),
+            Err(CommandError::Usage("/msg <nickname> <message>"))
+        );
+        assert_eq!(parse("/nick"), Err(CommandError::Usage("/nick <name>")));
+        assert_eq!(parse("/who is here"), Err(CommandError::Usage("/who")));
+        assert_eq!(
+            parse("/dance"),
+            Err(CommandError::Unknown(String::from("dance")))
+        );
+        assert_eq!(
+            CommandError::Unknown(String::from("dance")).to_string(),
+            "there's no /dance, /help lists the commands"
+        );
+        let too_long = "x".repeat(MAX_MESSAGE_LEN + 1);
+        assert_eq!(
+            parse(&too_long),
+            Err(CommandError::TooLong(MAX_MESSAGE_LEN + 1))
+        );
+        assert_eq!(
+            parse(&format!("/msg bob {}", too_long)),
+            Err(CommandError::TooLong(MAX_MESSAGE_LEN + 1))
+        );
+    }
+}
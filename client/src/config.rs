@@ -0,0 +1,370 @@
+use clap::{Parser, ValueEnum};
+use serde::Deserialize;
+use std::path::PathBuf;
+use std::time::Duration;
+use thiserror::Error;
+
+#[derive(Parser, Debug)]
+#[command(about = "Terminal video chat client")]
+struct Args {
+    /// TOML file to read settings from, command line options take precedence over it
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+    /// Server host to connect to [default: 127.0.0.1]
+    #[arg(long)]
+    host: Option<String>,
+    /// Server port to connect to [default: 8080]
+    #[arg(short, long)]
+    port: Option<u16>,
+    /// Name shown to other users [default: $USER]
+    #[arg(short, long)]
+    nickname: Option<String>,
+    /// Room to join instead of the default one
+    #[arg(short, long)]
+    room: Option<String>,
+    /// Passcode for --room, or to lock the room if we are the first one in it
+    #[arg(long)]
+    passcode: Option<String>,
+    /// Where our video comes from: `camera`, `test-pattern`, `none`, or an image, GIF or .mjpeg
+    /// file to loop [default: camera]
+    #[arg(long)]
+    video_source: Option<String>,
+    /// V4L2 device to capture video from [default: /dev/video0]
+    #[arg(long)]
+    camera_device: Option<String>,
+    /// Capture width in pixels [default: 176]
+    #[arg(long)]
+    capture_width: Option<u32>,
+    /// Capture height in pixels [default: 144]
+    #[arg(long)]
+    capture_height: Option<u32>,
+    /// Frames per second to capture at [default: 15]
+    #[arg(long)]
+    capture_fps: Option<u32>,
+    /// Microphone to capture from, `none`, or a .wav file to loop instead [default: default]
+    #[arg(long)]
+    audio_input: Option<String>,
+    /// Speakers to play to, or `null` to discard everything [default: default]
+    #[arg(long)]
+    audio_output: Option<String>,
+    /// Where to show our own camera [default: pane]
+    #[arg(long, value_enum)]
+    self_view: Option<SelfView>,
+    /// How to draw video, `auto` picks a graphics protocol the terminal is known to support and
+    /// falls back to `blocks` [default: auto]
+    #[arg(long, value_enum)]
+    graphics: Option<VideoGraphics>,
+    /// Characters to draw video with when not using a graphics protocol, Alt-R cycles through
+    /// them [default: half-blocks]
+    #[arg(long, value_enum)]
+    text_mode: Option<TextMode>,
+    /// Colours to draw video with text in, `auto` goes by $COLORTERM and $TERM [default: auto]
+    #[arg(long, value_enum)]
+    colors: Option<Colors>,
+    /// How to make up for colours missing from a 256 or 16 colour palette [default: ordered]
+    #[arg(long, value_enum)]
+    dither: Option<Dithering>,
+    /// Milliseconds between UI ticks [default: 67]
+    #[arg(long)]
+    tick_rate_ms: Option<u64>,
+}
+
+/// How our own camera is shown alongside everyone else's.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum SelfView {
+    /// A pane of its own at the start of the video grid.
+    Pane,
+    /// A small picture-in-picture in the bottom right corner of the video grid.
+    Pip,
+}
+
+/// How video frames are drawn into the terminal.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum VideoGraphics {
+    /// Whichever of the others the terminal looks like it supports.
+    Auto,
+    /// Kitty's graphics protocol, at full resolution.
+    Kitty,
+    /// iTerm2's inline images, at full resolution.
+    Iterm2,
+    /// Sixel images, at full resolution in up to 216 colours.
+    Sixel,
+    /// Half-block characters, two pixels to a cell, which work anywhere.
+    Blocks,
+}
+
+/// Characters video frames are drawn with, when they're drawn with text.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum TextMode {
+    /// Upper-half blocks, two pixels to a cell.
+    HalfBlocks,
+    /// Quadrant blocks, four pixels to a cell in two colours.
+    Quadrants,
+    /// Sextant blocks, six pixels to a cell in two colours, if the font has them.
+    Sextants,
+    /// Braille dots, eight pixels to a cell in one colour.
+    Braille,
+    /// Shade blocks without colour.
+    Shades,
+    /// Plain ASCII characters without colour.
+    Ascii,
+}
+
+impl TextMode {
+    /// The mode after this one, for cycling through them.
+    pub fn next(self) -> Self {
+        match self {
+            TextMode::HalfBlocks => TextMode::Quadrants,
+            TextMode::Quadrants => TextMode::Sextants,
+            TextMode::Sextants => TextMode::Braille,
+            TextMode::Braille => TextMode::Shades,
+            TextMode::Shades => TextMode::Ascii,
+            TextMode::Ascii => TextMode::HalfBlocks,
+        }
+    }
+
+    /// What it's called on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            TextMode::HalfBlocks => "half-blocks",
+            TextMode::Quadrants => "quadrants",
+            TextMode::Sextants => "sextants",
+            TextMode::Braille => "braille",
+            TextMode::Shades => "shades",
+            TextMode::Ascii => "ascii",
+        }
+    }
+}
+
+/// The colours the terminal can show.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Colors {
+    /// Whatever the terminal says it supports.
+    Auto,
+    /// Any 24-bit colour.
+    Truecolor,
+    /// xterm's 256 colours.
+    #[value(name = "256")]
+    #[serde(rename = "256")]
+    Xterm256,
+    /// The 16 standard ANSI colours.
+    #[value(name = "16")]
+    #[serde(rename = "16")]
+    Ansi16,
+}
+
+/// How pixels are spread over a palette's colours when it doesn't have the one they want.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Dithering {
+    /// Each pixel takes the nearest colour.
+    None,
+    /// A fixed pattern that stays still while the video moves.
+    Ordered,
+    /// Error diffusion, smoother but it shimmers.
+    FloydSteinberg,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub host: String,
+    pub port: u16,
+    pub nickname: String,
+    pub room: Option<String>,
+    pub passcode: Option<String>,
+    pub video_source: String,
+    pub camera_device: String,
+    pub capture_width: u32,
+    pub capture_height: u32,
+    pub capture_fps: u32,
+    pub audio_input: String,
+    pub audio_output: String,
+    pub self_view: SelfView,
+    pub graphics: VideoGraphics,
+    pub text_mode: TextMode,
+    pub colors: Colors,
+    pub dither: Dithering,
+    pub tick_rate_ms: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            host: String::from("127.0.0.1"),
+            port: 8080,
+            nickname: std::env::var("USER").unwrap_or_else(|_| String::from("anonymous")),
+            room: None,
+            passcode: None,
+            video_source: String::from("camera"),
+            camera_device: String::from("/dev/video0"),
+            capture_width: 176,
+            capture_height: 144,
+            capture_fps: 15,
+            audio_input: String::from("default"),
+            audio_output: String::from("default"),
+            self_view: SelfView::Pane,
+            graphics: VideoGraphics::Auto,
+            text_mode: TextMode::HalfBlocks,
+            colors: Colors::Auto,
+            dither: Dithering::Ordered,
+            tick_rate_ms: 67,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("could not read config file {0}: {1}")]
+    Read(PathBuf, std::io::Error),
+    #[error("could not parse config file {0}: {1}")]
+    Parse(PathBuf, toml::de::Error),
+    #[error("{0} must be at least 1")]
+    TooSmall(&'static str),
+}
+
+impl Config {
+    /// Build the config from the command line, falling back to the config file then the defaults.
+    pub fn load() -> Result<Self, ConfigError> {
+        Config::from_args(Args::parse())
+    }
+
+    fn from_args(args: Args) -> Result<Self, ConfigError> {
+        let mut config = match &args.config {
+            Some(path) => {
+                let contents = std::fs::read_to_string(path)
+                    .map_err(|e| ConfigError::Read(path.clone(), e))?;
+                toml::from_str(&contents).map_err(|e| ConfigError::Parse(path.clone(), e))?
+            }
+            None => Config::default(),
+        };
+
+        if let Some(host) = args.host {
+            config.host = host;
+        }
+        if let Some(port) = args.port {
+            config.port = port;
+        }
+        if let Some(nickname) = args.nickname {
+            config.nickname = nickname;
+        }
+        if args.room.is_some() {
+            config.room = args.room;
+        }
+        if args.passcode.is_some() {
+            config.passcode = args.passcode;
+        }
+        if let Some(video_source) = args.video_source {
+            config.video_source = video_source;
+        }
+        if let Some(camera_device) = args.camera_device {
+            config.camera_device = camera_device;
+        }
+        if let Some(capture_width) = args.capture_width {
+            config.capture_width = capture_width;
+        }
+        if let Some(capture_height) = args.capture_height {
+            config.capture_height = capture_height;
+        }
+        if let Some(capture_fps) = args.capture_fps {
+            config.capture_fps = capture_fps;
+        }
+        if let Some(audio_input) = args.audio_input {
+            config.audio_input = audio_input;
+        }
+        if let Some(audio_output) = args.audio_output {
+            config.audio_output = audio_output;
+        }
+        if let Some(self_view) = args.self_view {
+            config.self_view = self_view;
+        }
+        if let Some(graphics) = args.graphics {
+            config.graphics = graphics;
+        }
+        if let Some(text_mode) = args.text_mode {
+            config.text_mode = text_mode;
+        }
+        if let Some(colors) = args.colors {
+            config.colors = colors;
+        }
+        if let Some(dither) = args.dither {
+            config.dither = dither;
+        }
+        if let Some(tick_rate_ms) = args.tick_rate_ms {
+            config.tick_rate_ms = tick_rate_ms;
+        }
+
+        // checked once here, for the file and command line alike: the capture thread divides by
+        // these and a zero tick rate would spin the event loop
+        for (name, value) in [
+            ("capture_width", config.capture_width as u64),
+            ("capture_height", config.capture_height as u64),
+            ("capture_fps", config.capture_fps as u64),
+            ("tick_rate_ms", config.tick_rate_ms),
+        ] {
+            if value < 1 {
+                return Err(ConfigError::TooSmall(name));
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn server_addr(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+
+    pub fn tick_rate(&self) -> Duration {
+        Duration::from_millis(self.tick_rate_ms)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Load `toml` as the config file, with `args` after it on the command line.
+    fn load(name: &str, toml: &str, args: &[&str]) -> Result<Config, ConfigError> {
+        let path = std::env::temp_dir().join(format!(
+            "client-config-test-{}-{}.toml",
+            name,
+            std::process::id()
+        ));
+        std::fs::write(&path, toml).unwrap();
+        let mut command_line = vec!["client", "--config", path.to_str().unwrap()];
+        command_line.extend_from_slice(args);
+        let config = Config::from_args(Args::try_parse_from(command_line).unwrap());
+        std::fs::remove_file(&path).unwrap();
+        config
+    }
+
+    #[test]
+    fn zero_capture_settings_are_rejected() {
+        assert!(matches!(
+            load("fps", "capture_fps = 0", &[]),
+            Err(ConfigError::TooSmall("capture_fps"))
+        ));
+        assert!(matches!(
+            load("tick", "tick_rate_ms = 0", &[]),
+            Err(ConfigError::TooSmall("tick_rate_ms"))
+        ));
+        assert!(matches!(
+            load("cli-fps", "", &["--capture-fps", "0"]),
+            Err(ConfigError::TooSmall("capture_fps"))
+        ));
+        assert!(matches!(
+            load("height", "", &["--capture-height", "0"]),
+            Err(ConfigError::TooSmall("capture_height"))
+        ));
+        // the command line can fix what the file gets wrong
+        let config = load("fixed", "capture_width = 0", &["--capture-width", "320"]).unwrap();
+        assert_eq!(config.capture_width, 320);
+        assert_eq!(
+            ConfigError::TooSmall("capture_fps").to_string(),
+            "capture_fps must be at least 1"
+        );
+    }
+}
@@ -0,0 +1,213 @@
+use protocol::{
+    ClientChatData, ClientNetworkData, FrameError, ServerChatData, ServerNetworkData, UserId,
+};
+use std::sync::mpsc;
+use std::time::Duration;
+use tokio::{
+    io::BufReader,
+    net::TcpStream,
+    sync::mpsc::{UnboundedReceiver, UnboundedSender},
+    time::sleep,
+};
+
+use crate::audio::Mixer;
+use crate::frames::LatestFrames;
+use crate::Event;
+
+const INITIAL_RETRY_DELAY: Duration = Duration::from_millis(500);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConnectionStatus {
+    /// Trying to reach the server, either for the first time or straight after losing it.
+    Connecting,
+    Connected,
+    /// Not connected, the `attempt`th connection attempt happens in `retry_in`.
+    Reconnecting {
+        attempt: u32,
+        retry_in: Duration,
+    },
+}
+
+/// Doubles the wait between connection attempts, up to `MAX_RETRY_DELAY`.
+struct Backoff {
+    attempt: u32,
+    delay: Duration,
+}
+
+impl Backoff {
+    fn new() -> Self {
+        Backoff {
+            attempt: 0,
+            delay: INITIAL_RETRY_DELAY,
+        }
+    }
+
+    /// The attempt number and how long to wait before making it.
+    fn next(&mut self) -> (u32, Duration) {
+        self.attempt += 1;
+        let delay = self.delay;
+        self.delay = std::cmp::min(self.delay * 2, MAX_RETRY_DELAY);
+        (self.attempt, delay)
+    }
+}
+
+/// Start the task that owns the server connection.
+///
+/// Anything sent on the returned channel is written to the server while connected and dropped
+/// while not. The task introduces itself with `nickname` on every (re)connect and reports
+/// `Event::Connection` changes, it's up to the UI to rejoin rooms and resend what it still needs.
+///
+/// Video skips the queue: our own frames are taken from `outgoing_video` only when everything else
+/// has been written, and frames from the server are left in `incoming_video` with an
+/// `Event::ServerInputFrame` sent only if the UI has read the ones before them. Audio from the
+/// server goes straight into `incoming_audio` without involving the UI at all.
+pub(crate) fn spawn(
+    server_addr: String,
+    nickname: String,
+    events: mpsc::Sender<Event>,
+    outgoing_video: LatestFrames<(), ClientNetworkData>,
+    incoming_video: LatestFrames<UserId, ServerNetworkData>,
+    incoming_audio: Mixer,
+) -> UnboundedSender<ClientNetworkData> {
+    let (outgoing_tx, outgoing_rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(run(
+        server_addr,
+        nickname,
+        events,
+        outgoing_rx,
+        outgoing_video,
+        incoming_video,
+        incoming_audio,
+    ));
+    outgoing_tx
+}
+
+async fn run(
+    server_addr: String,
+    nickname: String,
+    events: mpsc::Sender<Event>,
+    mut outgoing: UnboundedReceiver<ClientNetworkData>,
+    outgoing_video: LatestFrames<(), ClientNetworkData>,
+    incoming_video: LatestFrames<UserId, ServerNetworkData>,
+    incoming_audio: Mixer,
+) {
+    let hello = ClientNetworkData {
+        chat_data: ClientChatData::Hello(nickname),
+    };
+    let mut backoff = Backoff::new();
+
+    loop {
+        let connection = match TcpStream::connect(&server_addr).await {
+            Ok(stream) => {
+                let (reader, mut writer) = stream.into_split();
+                match protocol::write_frame(&mut writer, &hello).await {
+                    Ok(()) => Some((reader, writer)),
+                    Err(_) => None,
+                }
+            }
+            Err(_) => None,
+        };
+        let (reader, mut writer) = match connection {
+            Some(connection) => connection,
+            None => {
+                let (attempt, retry_in) = backoff.next();
+                let status = ConnectionStatus::Reconnecting { attempt, retry_in };
+                if events.send(Event::Connection(status)).is_err() {
+                    return;
+                }
+                sleep(retry_in).await;
+                continue;
+            }
+        };
+
+        // whatever was queued while we were offline is stale, the UI resends pending chat itself
+        while outgoing.try_recv().is_ok() {}
+        outgoing_video.take();
+        backoff = Backoff::new();
+        if events
+            .send(Event::Connection(ConnectionStatus::Connected))
+            .is_err()
+        {
+            return;
+        }
+
+        // reading gets its own task since a frame read can't be safely cancelled by select!
+        let reader_events = events.clone();
+        let reader_video = incoming_video.clone();
+        let reader_audio = incoming_audio.clone();
+        let mut reader_task = tokio::spawn(async move {
+            let mut buf_reader = BufReader::new(reader);
+            loop {
+                // get incoming message from server
+                match protocol::read_frame::<ServerNetworkData, _>(&mut buf_reader).await {
+                    Ok(data) => {
+                        let event = match &data.chat_data {
+                            ServerChatData::VideoFrame(sender, ..) => {
+                                if !reader_video.put(sender.id, data) {
+                                    continue;
+                                }
+                                Event::ServerInputFrame
+                            }
+                            ServerChatData::AudioFrame(sender, sequence, packet) => {
+                                reader_audio.push(sender.id, *sequence, packet);
+                                continue;
+                            }
+                            _ => Event::ServerInput(data),
+                        };
+                        if reader_events.send(event).is_err() {
+                            break;
+                        }
+                    }
+                    // the whole frame was consumed so we can skip it and keep reading
+                    Err(FrameError::Malformed(_)) => {}
+                    Err(_) => break,
+                }
+            }
+        });
+
+        loop {
+            let data = tokio::select! {
+                // chat and room changes always go ahead of video
+                biased;
+                _ = &mut reader_task => break,
+                data = outgoing.recv() => match data {
+                    Some(data) => data,
+                    // the UI has shut down
+                    None => {
+                        reader_task.abort();
+                        return;
+                    }
+                },
+                // there's only ever our own camera in here
+                mut frames = outgoing_video.wait() => frames.remove(0).1,
+            };
+            if protocol::write_frame(&mut writer, &data).await.is_err() {
+                reader_task.abort();
+                break;
+            }
+        }
+
+        if events
+            .send(Event::Connection(ConnectionStatus::Connecting))
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let mut backoff = Backoff::new();
+        let delays: Vec<_> = (0..9).map(|_| backoff.next()).collect();
+        assert_eq!(delays[0], (1, Duration::from_millis(500)));
+        assert_eq!(delays[1], (2, Duration::from_secs(1)));
+        assert_eq!(delays[3], (4, Duration::from_secs(4)));
+        assert_eq!(delays[8], (9, MAX_RETRY_DELAY));
+    }
+}
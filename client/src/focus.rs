@@ -0,0 +1,114 @@
+use tui::{
+    layout::Rect,
+    style::{Color, Modifier, Style},
+    text::Span,
+    widgets::{Block, BorderType, Borders},
+};
+
+/// The pane keys go to, Tab and Shift-Tab move it along in the order they're declared.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Focus {
+    VideoGrid,
+    ChatHistory,
+    #[default]
+    ChatInput,
+    Participants,
+}
+
+const ORDER: [Focus; 4] = [
+    Focus::VideoGrid,
+    Focus::ChatHistory,
+    Focus::ChatInput,
+    Focus::Participants,
+];
+
+impl Focus {
+    pub fn next(self) -> Self {
+        ORDER[(self.position() + 1) % ORDER.len()]
+    }
+
+    pub fn prev(self) -> Self {
+        ORDER[(self.position() + ORDER.len() - 1) % ORDER.len()]
+    }
+
+    fn position(self) -> usize {
+        ORDER.iter().position(|&focus| focus == self).unwrap()
+    }
+}
+
+/// Where each pane was last drawn, for working out what the mouse is over.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PaneAreas {
+    pub video_grid: Rect,
+    pub chat_history: Rect,
+    pub chat_input: Rect,
+    pub participants: Rect,
+}
+
+impl PaneAreas {
+    pub fn pane_at(&self, column: u16, row: u16) -> Option<Focus> {
+        [
+            (Focus::VideoGrid, self.video_grid),
+            (Focus::ChatHistory, self.chat_history),
+            (Focus::ChatInput, self.chat_input),
+            (Focus::Participants, self.participants),
+        ]
+        .into_iter()
+        .find(|(_, area)| contains(*area, column, row))
+        .map(|(focus, _)| focus)
+    }
+}
+
+pub fn contains(area: Rect, column: u16, row: u16) -> bool {
+    (area.left()..area.right()).contains(&column) && (area.top()..area.bottom()).contains(&row)
+}
+
+/// The border around a pane, picked out in yellow while it has focus.
+pub fn pane_block(title: String, focused: bool) -> Block<'static> {
+    let (colour, title_style) = if focused {
+        (Color::Yellow, Style::default().add_modifier(Modifier::BOLD))
+    } else {
+        (Color::White, Style::default())
+    };
+    Block::default()
+        .title(Span::styled(title, title_style))
+        .borders(Borders::ALL)
+        .border_style(Style::default().fg(colour))
+        .border_type(BorderType::Double)
+        .style(Style::default().bg(Color::Black))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tab_visits_every_pane_and_wraps() {
+        let mut focus = Focus::default();
+        let mut seen = Vec::new();
+        for _ in 0..ORDER.len() {
+            focus = focus.next();
+            seen.push(focus);
+        }
+        assert_eq!(focus, Focus::default());
+        assert!(ORDER.iter().all(|pane| seen.contains(pane)));
+
+        assert_eq!(Focus::VideoGrid.prev(), Focus::Participants);
+        assert_eq!(Focus::Participants.next().prev(), Focus::Participants);
+    }
+
+    #[test]
+    fn clicks_land_in_the_pane_under_them() {
+        let areas = PaneAreas {
+            video_grid: Rect::new(0, 0, 80, 20),
+            chat_history: Rect::new(0, 20, 60, 7),
+            chat_input: Rect::new(0, 27, 60, 3),
+            participants: Rect::new(60, 20, 20, 10),
+        };
+        assert_eq!(areas.pane_at(0, 0), Some(Focus::VideoGrid));
+        assert_eq!(areas.pane_at(59, 26), Some(Focus::ChatHistory));
+        assert_eq!(areas.pane_at(59, 27), Some(Focus::ChatInput));
+        assert_eq!(areas.pane_at(60, 27), Some(Focus::Participants));
+        assert_eq!(areas.pane_at(80, 0), None);
+    }
+}
@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+/// Holds only the newest unread frame from each source, so a consumer that can't keep up skips
+/// stale frames rather than working through a backlog of them.
+pub struct LatestFrames<K, T> {
+    frames: Arc<Mutex<HashMap<K, T>>>,
+    notify: Arc<Notify>,
+}
+
+impl<K, T> Clone for LatestFrames<K, T> {
+    fn clone(&self) -> Self {
+        LatestFrames {
+            frames: self.frames.clone(),
+            notify: self.notify.clone(),
+        }
+    }
+}
+
+impl<K: Eq + Hash, T> Default for LatestFrames<K, T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Eq + Hash, T> LatestFrames<K, T> {
+    pub fn new() -> Self {
+        LatestFrames {
+            frames: Arc::new(Mutex::new(HashMap::new())),
+            notify: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Store `frame` for `source`, replacing any unread one.
+    ///
+    /// Returns true if nothing was waiting before, i.e. the consumer needs telling there's
+    /// something to read. Async consumers using `wait` are woken either way.
+    pub fn put(&self, source: K, frame: T) -> bool {
+        let mut frames = self.frames.lock().unwrap();
+        let was_empty = frames.is_empty();
+        frames.insert(source, frame);
+        self.notify.notify_one();
+        was_empty
+    }
+
+    /// Everything waiting to be read, in no particular order.
+    pub fn take(&self) -> Vec<(K, T)> {
+        self.frames.lock().unwrap().drain().collect()
+    }
+
+    /// Wait until there's something to read and take it. Safe to use in `select!`, nothing is lost
+    /// if cancelled.
+    pub async fn wait(&self) -> Vec<(K, T)> {
+        loop {
+            let frames = self.take();
+            if !frames.is_empty() {
+                return frames;
+            }
+            self.notify.notified().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn newer_frames_replace_unread_ones() {
+        let frames = LatestFrames::new();
+        assert!(frames.put(1, "old"));
+        assert!(!frames.put(1, "new"));
+        assert!(!frames.put(2, "other"));
+
+        let mut read = frames.wait().await;
+        read.sort();
+        assert_eq!(read, vec![(1, "new"), (2, "other")]);
+        assert!(frames.take().is_empty());
+        assert!(frames.put(1, "next"));
+    }
+}
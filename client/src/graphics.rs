@@ -0,0 +1,568 @@
+use image::{codecs::png::PngEncoder, imageops::FilterType, ColorType, ImageEncoder, RgbaImage};
+use std::io::{self, Write};
+use tui::layout::Rect;
+
+use tui_image::Palette;
+
+use crate::config::{Colors, VideoGraphics};
+
+/// Pixels in a character cell when the terminal won't say, about what most fonts come out at.
+pub const DEFAULT_CELL_SIZE: (u16, u16) = (8, 16);
+
+/// Base64 characters sent in each Kitty graphics escape, the most the protocol allows.
+const KITTY_CHUNK_LEN: usize = 4096;
+
+/// A way of drawing pictures into a terminal at full resolution.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    /// Kitty's graphics protocol, also spoken by Ghostty and Konsole.
+    Kitty,
+    /// iTerm2's inline images, also shown by WezTerm.
+    Iterm2,
+    Sixel,
+}
+
+/// The protocol to use for `setting`, looking at the terminal we're running in for `auto`.
+/// `None` means video is drawn with half-block characters instead.
+pub fn choose(setting: VideoGraphics) -> Option<Protocol> {
+    match setting {
+        VideoGraphics::Auto => detect(|name| std::env::var(name).ok()),
+        VideoGraphics::Kitty => Some(Protocol::Kitty),
+        VideoGraphics::Iterm2 => Some(Protocol::Iterm2),
+        VideoGraphics::Sixel => Some(Protocol::Sixel),
+        VideoGraphics::Blocks => None,
+    }
+}
+
+/// Guess which protocol the terminal understands from the environment variables `var` looks up.
+///
+/// Terminals can only be asked by reading their reply from stdin, which the input thread owns,
+/// so this goes by what they're known to set instead. Inside tmux or screen escapes would have
+/// to be wrapped to get through, so nothing is used there.
+pub fn detect(var: impl Fn(&str) -> Option<String>) -> Option<Protocol> {
+    if var("TMUX").is_some() || var("STY").is_some() {
+        return None;
+    }
+    let term = var("TERM").unwrap_or_default();
+    let term_program = var("TERM_PROGRAM").unwrap_or_default();
+    if term == "xterm-kitty" || term == "xterm-ghostty" || var("KITTY_WINDOW_ID").is_some() {
+        Some(Protocol::Kitty)
+    } else if term_program == "iTerm.app" || term_program == "WezTerm" {
+        Some(Protocol::Iterm2)
+    } else if term.starts_with("foot") || term.starts_with("mlterm") || term.contains("sixel") {
+        Some(Protocol::Sixel)
+    } else {
+        None
+    }
+}
+
+/// The palette to draw video with text in for `setting`, looking at the terminal we're running in
+/// for `auto`.
+pub fn choose_palette(setting: Colors) -> Palette {
+    match setting {
+        Colors::Auto => detect_palette(|name| std::env::var(name).ok()),
+        Colors::Truecolor => Palette::TrueColor,
+        Colors::Xterm256 => Palette::Xterm256,
+        Colors::Ansi16 => Palette::Ansi16,
+    }
+}
+
+/// Guess how many colours the terminal has from the environment variables `var` looks up,
+/// assuming the fewest if it doesn't say.
+pub fn detect_palette(var: impl Fn(&str) -> Option<String>) -> Palette {
+    let colorterm = var("COLORTERM").unwrap_or_default();
+    let term = var("TERM").unwrap_or_default();
+    if colorterm == "truecolor"
+        || colorterm == "24bit"
+        || term.ends_with("-direct")
+        || ["xterm-kitty", "xterm-ghostty", "alacritty", "foot"].contains(&term.as_str())
+    {
+        Palette::TrueColor
+    } else if term.contains("256color") {
+        Palette::Xterm256
+    } else {
+        Palette::Ansi16
+    }
+}
+
+/// Pixels in each character cell of the terminal on stdout, or `DEFAULT_CELL_SIZE` if it
+/// doesn't report its size in pixels.
+pub fn cell_size() -> (u16, u16) {
+    // SAFETY: TIOCGWINSZ only writes a `winsize`, which is plain integers so all zeroes is valid
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    let ok = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } == 0;
+    if !ok || size.ws_col == 0 || size.ws_row == 0 || size.ws_xpixel == 0 || size.ws_ypixel == 0 {
+        return DEFAULT_CELL_SIZE;
+    }
+    (size.ws_xpixel / size.ws_col, size.ws_ypixel / size.ws_row)
+}
+
+/// The part of `area` a `width` by `height` picture covers when scaled to fit without being
+/// stretched, centred in it.
+pub fn fit(area: Rect, (width, height): (u32, u32), (cell_width, cell_height): (u16, u16)) -> Rect {
+    if width == 0 || height == 0 || area.area() == 0 {
+        return Rect::new(area.x, area.y, 0, 0);
+    }
+    let (width, height) = (width as u64, height as u64);
+    let (cell_width, cell_height) = (
+        std::cmp::max(1, cell_width) as u64,
+        std::cmp::max(1, cell_height) as u64,
+    );
+    let area_width = area.width as u64 * cell_width;
+    let area_height = area.height as u64 * cell_height;
+    let (pixel_width, pixel_height) = if width * area_height > height * area_width {
+        (area_width, height * area_width / width)
+    } else {
+        (width * area_height / height, area_height)
+    };
+    let columns = ((pixel_width + cell_width / 2) / cell_width).clamp(1, area.width as u64) as u16;
+    let rows = ((pixel_height + cell_height / 2) / cell_height).clamp(1, area.height as u64) as u16;
+    Rect::new(
+        area.x + (area.width - columns) / 2,
+        area.y + (area.height - rows) / 2,
+        columns,
+        rows,
+    )
+}
+
+/// A picture that's been drawn, to tell whether it has to be drawn again.
+struct Placed {
+    area: Rect,
+    frame: RgbaImage,
+}
+
+/// Draws video frames over the panes tui has left empty for them, after each frame is flushed.
+pub struct Graphics {
+    protocol: Protocol,
+    cell_size: (u16, u16),
+    /// What's on screen now, bottom first.
+    placed: Vec<Placed>,
+}
+
+impl Graphics {
+    pub fn new(protocol: Protocol, cell_size: (u16, u16)) -> Self {
+        Graphics {
+            protocol,
+            cell_size,
+            placed: Vec::new(),
+        }
+    }
+
+    /// Pick up a new cell size after the terminal has been resized, which could have been the
+    /// font changing.
+    pub fn set_cell_size(&mut self, cell_size: (u16, u16)) {
+        self.cell_size = cell_size;
+    }
+
+    /// Draw each of `pictures` scaled to fit in its area, bottom first, skipping those that
+    /// haven't changed since the last time.
+    ///
+    /// Sixel and iTerm2 pictures replace the text under them, so once one is gone the screen has
+    /// to be drawn again from scratch. In that case nothing is drawn and `true` is returned, the
+    /// caller should clear the terminal and call this again after the next frame.
+    pub fn draw<W: Write>(
+        &mut self,
+        out: &mut W,
+        pictures: &[(Rect, &RgbaImage)],
+    ) -> io::Result<bool> {
+        let fitted: Vec<(Rect, &RgbaImage)> = pictures
+            .iter()
+            .map(|(area, frame)| (fit(*area, frame.dimensions(), self.cell_size), *frame))
+            .filter(|(area, _)| area.area() > 0)
+            .collect();
+
+        if self.protocol != Protocol::Kitty
+            && self
+                .placed
+                .iter()
+                .any(|placed| !fitted.iter().any(|(area, _)| *area == placed.area))
+        {
+            self.placed.clear();
+            return Ok(true);
+        }
+
+        let mut written = Vec::new();
+        for id in fitted.len()..self.placed.len() {
+            kitty_delete(&mut written, Some(id as u32 + 1))?;
+        }
+        self.placed.truncate(fitted.len());
+
+        // without layers, drawing a picture covers up the ones on top of it too
+        let mut redrawn: Vec<Rect> = Vec::new();
+        for (index, (area, frame)) in fitted.into_iter().enumerate() {
+            let unchanged = self
+                .placed
+                .get(index)
+                .is_some_and(|placed| placed.area == area && placed.frame == *frame);
+            let covered = self.protocol != Protocol::Kitty
+                && redrawn.iter().any(|below| below.intersects(area));
+            if unchanged && !covered {
+                continue;
+            }
+
+            write!(written, "\x1b[{};{}H", area.y + 1, area.x + 1)?;
+            match self.protocol {
+                Protocol::Kitty => kitty(&mut written, index as u32 + 1, area, frame)?,
+                Protocol::Iterm2 => iterm2(&mut written, area, frame)?,
+                Protocol::Sixel => {
+                    let (width, height) = (
+                        area.width as u32 * self.cell_size.0 as u32,
+                        area.height as u32 * self.cell_size.1 as u32,
+                    );
+                    sixel(
+                        &mut written,
+                        &image::imageops::resize(frame, width, height, FilterType::Triangle),
+                    )?
+                }
+            }
+            redrawn.push(area);
+            let placed = Placed {
+                area,
+                frame: frame.clone(),
+            };
+            match self.placed.get_mut(index) {
+                Some(old) => *old = placed,
+                None => self.placed.push(placed),
+            }
+        }
+
+        if !written.is_empty() {
+            // tui puts the cursor back where it wants it itself, but only when that moves
+            out.write_all(b"\x1b7")?;
+            out.write_all(&written)?;
+            out.write_all(b"\x1b8")?;
+            out.flush()?;
+        }
+        Ok(false)
+    }
+
+    /// Take every picture off the screen, after a resize or before quitting. The text under
+    /// Sixel and iTerm2 pictures is only put back by clearing the terminal.
+    pub fn clear<W: Write>(&mut self, out: &mut W) -> io::Result<()> {
+        if self.protocol == Protocol::Kitty && !self.placed.is_empty() {
+            kitty_delete(out, None)?;
+            out.flush()?;
+        }
+        self.placed.clear();
+        Ok(())
+    }
+}
+
+/// Send `frame` to Kitty as image `id` and show it at the cursor, stretched over `area`.
+fn kitty<W: Write>(out: &mut W, id: u32, area: Rect, frame: &RgbaImage) -> io::Result<()> {
+    let rgb: Vec<u8> = frame
+        .pixels()
+        .flat_map(|pixel| [pixel[0], pixel[1], pixel[2]])
+        .collect();
+    let data = base64::encode(rgb);
+    let mut chunks = data.as_bytes().chunks(KITTY_CHUNK_LEN).peekable();
+    let mut first = true;
+    while let Some(chunk) = chunks.next() {
+        let more = chunks.peek().is_some() as u8;
+        if first {
+            // the same placement id replaces the last frame rather than stacking on top of it,
+            // and C=1 keeps the cursor from moving, which could scroll the screen
+            write!(
+                out,
+                "\x1b_Ga=T,f=24,s={},v={},i={},p=1,c={},r={},z={},C=1,q=2,m={};",
+                frame.width(),
+                frame.height(),
+                id,
+                area.width,
+                area.height,
+                id,
+                more
+            )?;
+            first = false;
+        } else {
+            write!(out, "\x1b_Gm={};", more)?;
+        }
+        out.write_all(chunk)?;
+        out.write_all(b"\x1b\\")?;
+    }
+    Ok(())
+}
+
+/// Have Kitty forget image `id`, or every image if there's no id.
+fn kitty_delete<W: Write>(out: &mut W, id: Option<u32>) -> io::Result<()> {
+    match id {
+        Some(id) => write!(out, "\x1b_Ga=d,d=I,i={},q=2\x1b\\", id),
+        None => write!(out, "\x1b_Ga=d,d=A,q=2\x1b\\"),
+    }
+}
+
+/// Show `frame` as a PNG at the cursor, stretched over `area`.
+fn iterm2<W: Write>(out: &mut W, area: Rect, frame: &RgbaImage) -> io::Result<()> {
+    let mut png = Vec::new();
+    PngEncoder::new(&mut png)
+        .write_image(frame, frame.width(), frame.height(), ColorType::Rgba8)
+        .map_err(io::Error::other)?;
+    write!(
+        out,
+        "\x1b]1337;File=inline=1;size={};width={};height={};preserveAspectRatio=0;doNotMoveCursor=1:{}\x07",
+        png.len(),
+        area.width,
+        area.height,
+        base64::encode(png)
+    )
+}
+
+/// Colour `channel` as one of the six levels of each channel in the palette.
+fn sixel_level(channel: u8) -> usize {
+    (channel as usize * 5 + 127) / 255
+}
+
+/// Draw `frame` at the cursor pixel for pixel, each pixel rounded to the nearest colour in a
+/// 6x6x6 cube, which every Sixel terminal has room for.
+fn sixel<W: Write>(out: &mut W, frame: &RgbaImage) -> io::Result<()> {
+    let (width, height) = frame.dimensions();
+    let colours: Vec<usize> = frame
+        .pixels()
+        .map(|pixel| sixel_level(pixel[0]) * 36 + sixel_level(pixel[1]) * 6 + sixel_level(pixel[2]))
+        .collect();
+
+    write!(out, "\x1bPq\"1;1;{};{}", width, height)?;
+    let mut used = [false; 216];
+    for &colour in &colours {
+        used[colour] = true;
+    }
+    for colour in (0..216).filter(|&colour| used[colour]) {
+        let (r, g, b) = (colour / 36, colour / 6 % 6, colour % 6);
+        write!(out, "#{};2;{};{};{}", colour, r * 20, g * 20, b * 20)?;
+    }
+
+    // six rows at a time, each colour in them drawn in a pass of its own
+    for top in (0..height).step_by(6) {
+        if top > 0 {
+            out.write_all(b"-")?;
+        }
+        let rows = top..std::cmp::min(top + 6, height);
+        let mut in_band = [false; 216];
+        for y in rows.clone() {
+            for x in 0..width {
+                in_band[colours[(y * width + x) as usize]] = true;
+            }
+        }
+        let mut first = true;
+        for colour in (0..216).filter(|&colour| in_band[colour]) {
+            if !first {
+                out.write_all(b"$")?;
+            }
+            first = false;
+            write!(out, "#{}", colour)?;
+            let column = |x: u32| {
+                rows.clone().fold(0u8, |bits, y| {
+                    bits | ((colours[(y * width + x) as usize] == colour) as u8) << (y - top)
+                })
+            };
+            let mut x = 0;
+            while x < width {
+                let bits = column(x);
+                let mut run = 1;
+                while x + run < width && column(x + run) == bits {
+                    run += 1;
+                }
+                let symbol = (b'?' + bits) as char;
+                if run > 3 {
+                    write!(out, "!{}{}", run, symbol)?;
+                } else {
+                    for _ in 0..run {
+                        write!(out, "{}", symbol)?;
+                    }
+                }
+                x += run;
+            }
+        }
+    }
+    out.write_all(b"\x1b\\")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    fn solid(width: u32, height: u32, colour: [u8; 4]) -> RgbaImage {
+        RgbaImage::from_pixel(width, height, Rgba(colour))
+    }
+
+    fn env<'a>(vars: &'a [(&'a str, &'a str)]) -> impl Fn(&str) -> Option<String> + 'a {
+        move |name| {
+            vars.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| value.to_string())
+        }
+    }
+
+    #[test]
+    fn terminals_are_recognised_from_the_environment() {
+        assert_eq!(
+            detect(env(&[("TERM", "xterm-kitty")])),
+            Some(Protocol::Kitty)
+        );
+        assert_eq!(
+            detect(env(&[
+                ("TERM", "xterm-256color"),
+                ("TERM_PROGRAM", "iTerm.app")
+            ])),
+            Some(Protocol::Iterm2)
+        );
+        assert_eq!(detect(env(&[("TERM", "foot")])), Some(Protocol::Sixel));
+        assert_eq!(detect(env(&[("TERM", "xterm-256color")])), None);
+        assert_eq!(
+            detect(env(&[
+                ("TERM", "xterm-kitty"),
+                ("TMUX", "/tmp/tmux-1000/default")
+            ])),
+            None
+        );
+    }
+
+    #[test]
+    fn colours_are_recognised_from_the_environment() {
+        assert_eq!(
+            detect_palette(env(&[
+                ("COLORTERM", "truecolor"),
+                ("TERM", "xterm-256color")
+            ])),
+            Palette::TrueColor
+        );
+        assert_eq!(
+            detect_palette(env(&[("TERM", "xterm-kitty")])),
+            Palette::TrueColor
+        );
+        assert_eq!(
+            detect_palette(env(&[("TERM", "screen-256color")])),
+            Palette::Xterm256
+        );
+        assert_eq!(detect_palette(env(&[("TERM", "linux")])), Palette::Ansi16);
+        assert_eq!(detect_palette(env(&[])), Palette::Ansi16);
+    }
+
+    #[test]
+    fn pictures_fit_without_stretching() {
+        // 2:1 pixels in an area 40 by 5 cells of 8 by 16, which is 4:1
+        assert_eq!(
+            fit(Rect::new(5, 5, 40, 5), (200, 100), (8, 16)),
+            Rect::new(15, 5, 20, 5)
+        );
+        assert_eq!(
+            fit(Rect::new(0, 0, 10, 10), (100, 50), (10, 10)),
+            Rect::new(0, 2, 10, 5)
+        );
+        assert_eq!(fit(Rect::new(0, 0, 0, 10), (100, 50), (10, 10)).area(), 0);
+    }
+
+    #[test]
+    fn kitty_replaces_pictures_in_place_and_deletes_ones_gone() {
+        let mut graphics = Graphics::new(Protocol::Kitty, (1, 2));
+        let frame = solid(2, 4, [255, 0, 0, 255]);
+        let mut out = Vec::new();
+        let redraw = graphics
+            .draw(
+                &mut out,
+                &[
+                    (Rect::new(0, 0, 2, 2), &frame),
+                    (Rect::new(4, 1, 2, 2), &frame),
+                ],
+            )
+            .unwrap();
+        assert!(!redraw);
+        let data = base64::encode([255, 0, 0].repeat(8));
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!(
+                "\x1b7\x1b[1;1H\x1b_Ga=T,f=24,s=2,v=4,i=1,p=1,c=2,r=2,z=1,C=1,q=2,m=0;{}\x1b\\\
+                 \x1b[2;5H\x1b_Ga=T,f=24,s=2,v=4,i=2,p=1,c=2,r=2,z=2,C=1,q=2,m=0;{}\x1b\\\x1b8",
+                data, data
+            )
+        );
+
+        // nothing has changed so nothing is sent
+        let mut out = Vec::new();
+        graphics
+            .draw(
+                &mut out,
+                &[
+                    (Rect::new(0, 0, 2, 2), &frame),
+                    (Rect::new(4, 1, 2, 2), &frame),
+                ],
+            )
+            .unwrap();
+        assert!(out.is_empty());
+
+        let mut out = Vec::new();
+        graphics
+            .draw(&mut out, &[(Rect::new(0, 0, 2, 2), &frame)])
+            .unwrap();
+        assert_eq!(out, b"\x1b7\x1b_Ga=d,d=I,i=2,q=2\x1b\\\x1b8");
+
+        let mut out = Vec::new();
+        graphics.clear(&mut out).unwrap();
+        assert_eq!(out, b"\x1b_Ga=d,d=A,q=2\x1b\\");
+    }
+
+    #[test]
+    fn kitty_data_is_sent_in_chunks() {
+        let mut graphics = Graphics::new(Protocol::Kitty, (1, 2));
+        let frame = solid(64, 64, [0, 0, 255, 255]);
+        let mut out = Vec::new();
+        graphics
+            .draw(&mut out, &[(Rect::new(0, 0, 64, 32), &frame)])
+            .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        // 64 * 64 * 3 bytes is 16384 characters of base64
+        assert_eq!(out.matches("\x1b_G").count(), 4);
+        assert_eq!(out.matches(",m=1;").count(), 1);
+        assert_eq!(out.matches("\x1b_Gm=1;").count(), 2);
+        assert_eq!(out.matches("\x1b_Gm=0;").count(), 1);
+    }
+
+    #[test]
+    fn sixel_draws_pixels_and_asks_for_a_redraw_when_one_goes() {
+        let mut graphics = Graphics::new(Protocol::Sixel, (2, 6));
+        let frame = solid(4, 6, [255, 0, 0, 255]);
+        let mut out = Vec::new();
+        graphics
+            .draw(&mut out, &[(Rect::new(3, 2, 2, 1), &frame)])
+            .unwrap();
+        assert_eq!(
+            out,
+            b"\x1b7\x1b[3;4H\x1bPq\"1;1;4;6#180;2;100;0;0#180!4~\x1b\\\x1b8"
+        );
+
+        let mut out = Vec::new();
+        assert!(graphics.draw(&mut out, &[]).unwrap());
+        assert!(out.is_empty());
+        // after the caller has cleared the screen
+        assert!(!graphics.draw(&mut out, &[]).unwrap());
+    }
+
+    #[test]
+    fn sixel_bands_split_colours() {
+        // a white top row over black, seven rows so there's a second band
+        let mut frame = solid(2, 7, [0, 0, 0, 255]);
+        frame.put_pixel(0, 0, Rgba([255, 255, 255, 255]));
+        frame.put_pixel(1, 0, Rgba([255, 255, 255, 255]));
+        let mut out = Vec::new();
+        sixel(&mut out, &frame).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "\x1bPq\"1;1;2;7#0;2;0;0;0#215;2;100;100;100#0}}$#215@@-#0@@\x1b\\"
+        );
+    }
+
+    #[test]
+    fn iterm2_sends_a_png_sized_in_cells() {
+        let mut graphics = Graphics::new(Protocol::Iterm2, (1, 2));
+        let frame = solid(4, 4, [0, 255, 0, 255]);
+        let mut out = Vec::new();
+        graphics
+            .draw(&mut out, &[(Rect::new(0, 0, 8, 2), &frame)])
+            .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("\x1b7\x1b[1;3H\x1b]1337;File=inline=1;size="));
+        assert!(out.contains(";width=4;height=2;preserveAspectRatio=0;doNotMoveCursor=1:"));
+        assert!(out.ends_with("\x07\x1b8"));
+    }
+}
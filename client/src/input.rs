@@ -0,0 +1,404 @@
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+/// Most sent messages kept around to be recalled with Up.
+const HISTORY_LEN: usize = 100;
+/// Tallest the input grows to before it starts scrolling instead.
+pub const MAX_ROWS: usize = 4;
+
+/// The message being written, edited like a readline prompt.
+///
+/// The cursor is a byte offset that only ever lands between graphemes, so accented letters and
+/// emoji are moved over and deleted whole, and scrolling goes by how wide things are on screen.
+#[derive(Default)]
+pub struct ChatInput {
+    text: String,
+    cursor: usize,
+    /// Messages sent so far, oldest first.
+    history: Vec<String>,
+    /// The history entry being shown, along with what was being written before Up was pressed.
+    recalled: Option<(usize, String)>,
+    /// The first column and line in view.
+    scroll: (usize, usize),
+}
+
+impl ChatInput {
+    /// Lines needed to show the whole message, up to `MAX_ROWS`.
+    pub fn rows(&self) -> usize {
+        std::cmp::min(self.text.split('\n').count(), MAX_ROWS)
+    }
+
+    /// Apply an editing key, handing back the message if it was sent.
+    pub fn handle_key(&mut self, key: KeyEvent) -> Option<String> {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        let alt = key.modifiers.contains(KeyModifiers::ALT);
+        let shift = key.modifiers.contains(KeyModifiers::SHIFT);
+        match key.code {
+            // few terminals tell Shift-Enter apart from Enter, so Alt-Enter and Ctrl-J do it too
+            KeyCode::Enter if shift || alt => self.insert("\n"),
+            KeyCode::Char('j') if ctrl => self.insert("\n"),
+            KeyCode::Enter => return self.submit(),
+            KeyCode::Char('a') if ctrl => self.cursor = self.line_start(),
+            KeyCode::Char('e') if ctrl => self.cursor = self.line_end(),
+            KeyCode::Char('u') if ctrl => self.delete_to(self.line_start()),
+            KeyCode::Char('k') if ctrl => self.delete_to(self.line_end()),
+            KeyCode::Char('w') if ctrl => self.delete_to(self.word_start()),
+            KeyCode::Backspace if ctrl || alt => self.delete_to(self.word_start()),
+            KeyCode::Char('b') if alt => self.cursor = self.word_start(),
+            KeyCode::Char('f') if alt => self.cursor = self.word_end(),
+            KeyCode::Left if ctrl => self.cursor = self.word_start(),
+            KeyCode::Right if ctrl => self.cursor = self.word_end(),
+            KeyCode::Char(c) if !ctrl && !alt => self.insert(c.encode_utf8(&mut [0; 4])),
+            KeyCode::Backspace => self.delete_to(self.prev_boundary()),
+            KeyCode::Delete => self.delete_to(self.next_boundary()),
+            KeyCode::Left => self.cursor = self.prev_boundary(),
+            KeyCode::Right => self.cursor = self.next_boundary(),
+            KeyCode::Home => self.cursor = self.line_start(),
+            KeyCode::End => self.cursor = self.line_end(),
+            // Up and Down move between lines, and through the history from the first or last
+            KeyCode::Up if self.cursor_position().0 > 0 => self.move_lines(-1),
+            KeyCode::Up => self.recall_older(),
+            KeyCode::Down if self.line_end() < self.text.len() => self.move_lines(1),
+            KeyCode::Down => self.recall_newer(),
+            _ => {}
+        }
+        None
+    }
+
+    /// The visible part of the message for an area `width` by `height`, scrolled so the cursor
+    /// is in it, and where the cursor is within that area.
+    pub fn view(&mut self, width: usize, height: usize) -> (Vec<String>, (usize, usize)) {
+        if width == 0 || height == 0 {
+            return (Vec::new(), (0, 0));
+        }
+        let (line, column) = self.cursor_position();
+        let (mut left, mut top) = self.scroll;
+        if column < left {
+            left = column;
+        } else if column >= left + width {
+            left = column + 1 - width;
+        }
+        if line < top {
+            top = line;
+        } else if line >= top + height {
+            top = line + 1 - height;
+        }
+        self.scroll = (left, top);
+
+        let lines = self
+            .text
+            .split('\n')
+            .skip(top)
+            .take(height)
+            .map(|line| columns(line, left, width))
+            .collect();
+        (lines, (column - left, line - top))
+    }
+
+    /// Put the cursor at `column`, `row` of the area last passed to `view`.
+    pub fn click(&mut self, column: usize, row: usize) {
+        let line = std::cmp::min(self.scroll.1 + row, self.text.matches('\n').count());
+        self.cursor = self.offset_at(line, self.scroll.0 + column);
+    }
+
+    fn insert(&mut self, text: &str) {
+        self.text.insert_str(self.cursor, text);
+        self.cursor += text.len();
+    }
+
+    /// Remove everything between the cursor and `offset`, leaving the cursor at the start of it.
+    fn delete_to(&mut self, offset: usize) {
+        let range = std::cmp::min(self.cursor, offset)..std::cmp::max(self.cursor, offset);
+        self.cursor = range.start;
+        self.text.replace_range(range, "");
+    }
+
+    fn submit(&mut self) -> Option<String> {
+        if self.text.trim().is_empty() {
+            return None;
+        }
+        let message = std::mem::take(&mut self.text);
+        if self.history.last() != Some(&message) {
+            self.history.push(message.clone());
+            if self.history.len() > HISTORY_LEN {
+                self.history.remove(0);
+            }
+        }
+        self.cursor = 0;
+        self.recalled = None;
+        self.scroll = (0, 0);
+        Some(message)
+    }
+
+    fn recall_older(&mut self) {
+        let index = match &self.recalled {
+            None if !self.history.is_empty() => self.history.len() - 1,
+            Some((index, _)) if *index > 0 => index - 1,
+            _ => return,
+        };
+        let draft = match self.recalled.take() {
+            Some((_, draft)) => draft,
+            None => self.text.clone(),
+        };
+        self.recalled = Some((index, draft));
+        self.set_text(self.history[index].clone());
+    }
+
+    fn recall_newer(&mut self) {
+        match self.recalled.take() {
+            Some((index, draft)) if index + 1 < self.history.len() => {
+                self.set_text(self.history[index + 1].clone());
+                self.recalled = Some((index + 1, draft));
+            }
+            // back to what we were writing
+            Some((_, draft)) => self.set_text(draft),
+            None => {}
+        }
+    }
+
+    fn set_text(&mut self, text: String) {
+        self.text = text;
+        self.cursor = self.text.len();
+    }
+
+    fn prev_boundary(&self) -> usize {
+        self.text[..self.cursor]
+            .grapheme_indices(true)
+            .next_back()
+            .map_or(0, |(offset, _)| offset)
+    }
+
+    fn next_boundary(&self) -> usize {
+        self.text[self.cursor..]
+            .graphemes(true)
+            .next()
+            .map_or(self.cursor, |grapheme| self.cursor + grapheme.len())
+    }
+
+    /// The start of the word before the cursor, skipping any spaces and punctuation first.
+    fn word_start(&self) -> usize {
+        let mut graphemes = self.text[..self.cursor]
+            .grapheme_indices(true)
+            .rev()
+            .peekable();
+        while graphemes
+            .next_if(|(_, grapheme)| !is_word(grapheme))
+            .is_some()
+        {}
+        let mut start = graphemes.peek().map_or(0, |(offset, _)| *offset);
+        for (offset, grapheme) in graphemes {
+            if !is_word(grapheme) {
+                break;
+            }
+            start = offset;
+        }
+        start
+    }
+
+    /// The end of the word after the cursor, skipping any spaces and punctuation first.
+    fn word_end(&self) -> usize {
+        let mut graphemes = self.text[self.cursor..].grapheme_indices(true).peekable();
+        while graphemes
+            .next_if(|(_, grapheme)| !is_word(grapheme))
+            .is_some()
+        {}
+        let mut end = self.text.len() - self.cursor;
+        for (offset, grapheme) in graphemes {
+            if !is_word(grapheme) {
+                end = offset;
+                break;
+            }
+        }
+        self.cursor + end
+    }
+
+    fn line_start(&self) -> usize {
+        self.text[..self.cursor].rfind('\n').map_or(0, |i| i + 1)
+    }
+
+    fn line_end(&self) -> usize {
+        self.text[self.cursor..]
+            .find('\n')
+            .map_or(self.text.len(), |i| self.cursor + i)
+    }
+
+    /// The line the cursor is on and how many columns along it is.
+    fn cursor_position(&self) -> (usize, usize) {
+        let line = self.text[..self.cursor].matches('\n').count();
+        (line, self.text[self.line_start()..self.cursor].width())
+    }
+
+    /// Move the cursor up or down `lines`, staying as near its column as the line allows.
+    fn move_lines(&mut self, lines: isize) {
+        let (line, column) = self.cursor_position();
+        self.cursor = self.offset_at(line.saturating_add_signed(lines), column);
+    }
+
+    /// The grapheme boundary nearest to `column` on `line`, or the end of the line if it's short.
+    fn offset_at(&self, line: usize, column: usize) -> usize {
+        let line_start = match line {
+            0 => 0,
+            line => match self.text.match_indices('\n').nth(line - 1) {
+                Some((i, _)) => i + 1,
+                None => return self.text.len(),
+            },
+        };
+        let mut width = 0;
+        for (offset, grapheme) in self.text[line_start..].grapheme_indices(true) {
+            if grapheme == "\n" || width + grapheme.width() > column {
+                return line_start + offset;
+            }
+            width += grapheme.width();
+        }
+        self.text.len()
+    }
+}
+
+fn is_word(grapheme: &str) -> bool {
+    grapheme.chars().next().is_some_and(char::is_alphanumeric)
+}
+
+/// The part of `line` between columns `left` and `left + width`, with a wide character cut off
+/// at the left edge shown as spaces so everything after it stays in the right place.
+fn columns(line: &str, left: usize, width: usize) -> String {
+    let mut visible = String::new();
+    let mut column = 0;
+    for grapheme in line.graphemes(true) {
+        let end = column + grapheme.width();
+        if end > left + width {
+            break;
+        }
+        if column >= left {
+            visible.push_str(grapheme);
+        } else if end > left {
+            visible.extend(std::iter::repeat_n(' ', end - left));
+        }
+        column = end;
+    }
+    visible
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(code: KeyCode) -> KeyEvent {
+        KeyEvent::new(code, KeyModifiers::NONE)
+    }
+
+    fn ctrl(c: char) -> KeyEvent {
+        KeyEvent::new(KeyCode::Char(c), KeyModifiers::CONTROL)
+    }
+
+    fn typed(text: &str) -> ChatInput {
+        let mut input = ChatInput::default();
+        for c in text.chars() {
+            input.handle_key(key(KeyCode::Char(c)));
+        }
+        input
+    }
+
+    #[test]
+    fn multibyte_characters_are_edited_whole() {
+        let mut input = typed("café 👍🏽");
+        input.handle_key(key(KeyCode::Backspace));
+        assert_eq!(input.text, "café ");
+        input.handle_key(key(KeyCode::Left));
+        input.handle_key(key(KeyCode::Left));
+        input.handle_key(key(KeyCode::Char('è')));
+        assert_eq!(input.text, "cafèé ");
+        input.handle_key(key(KeyCode::Delete));
+        assert_eq!(input.text, "cafè ");
+        input.handle_key(key(KeyCode::Home));
+        input.handle_key(key(KeyCode::Char('¡')));
+        assert_eq!(input.text, "¡cafè ");
+    }
+
+    #[test]
+    fn readline_shortcuts() {
+        let mut input = typed("hello there, world");
+        input.handle_key(ctrl('w'));
+        assert_eq!(input.text, "hello there, ");
+        input.handle_key(ctrl('a'));
+        input.handle_key(KeyEvent::new(KeyCode::Right, KeyModifiers::CONTROL));
+        assert_eq!(input.cursor, "hello".len());
+        input.handle_key(KeyEvent::new(KeyCode::Char('f'), KeyModifiers::ALT));
+        assert_eq!(input.cursor, "hello there".len());
+        input.handle_key(ctrl('k'));
+        assert_eq!(input.text, "hello there");
+        input.handle_key(KeyEvent::new(KeyCode::Char('b'), KeyModifiers::ALT));
+        input.handle_key(ctrl('u'));
+        assert_eq!(input.text, "there");
+        input.handle_key(ctrl('e'));
+        assert_eq!(input.cursor, input.text.len());
+    }
+
+    #[test]
+    fn history_is_recalled_and_the_draft_kept() {
+        let mut input = ChatInput::default();
+        for message in ["first", "second"] {
+            for c in message.chars() {
+                input.handle_key(key(KeyCode::Char(c)));
+            }
+            assert_eq!(
+                input.handle_key(key(KeyCode::Enter)).as_deref(),
+                Some(message)
+            );
+        }
+        input.handle_key(key(KeyCode::Char('x')));
+
+        input.handle_key(key(KeyCode::Up));
+        assert_eq!(input.text, "second");
+        input.handle_key(key(KeyCode::Up));
+        input.handle_key(key(KeyCode::Up));
+        assert_eq!(input.text, "first");
+        input.handle_key(key(KeyCode::Down));
+        assert_eq!(input.text, "second");
+        input.handle_key(key(KeyCode::Down));
+        assert_eq!(input.text, "x");
+
+        // nothing to send
+        let mut blank = typed("  ");
+        assert_eq!(blank.handle_key(key(KeyCode::Enter)), None);
+    }
+
+    #[test]
+    fn lines_are_added_and_moved_between() {
+        let mut input = typed("one");
+        input.handle_key(KeyEvent::new(KeyCode::Enter, KeyModifiers::SHIFT));
+        input.handle_key(key(KeyCode::Char('t')));
+        input.handle_key(KeyEvent::new(KeyCode::Enter, KeyModifiers::ALT));
+        input.handle_key(key(KeyCode::Char('3')));
+        input.handle_key(ctrl('j'));
+        assert_eq!(input.text, "one\nt\n3\n");
+        assert_eq!(input.rows(), 4);
+
+        input.handle_key(key(KeyCode::Up));
+        input.handle_key(key(KeyCode::Up));
+        input.handle_key(key(KeyCode::End));
+        input.handle_key(key(KeyCode::Up));
+        assert_eq!(input.cursor_position(), (0, 1));
+        input.handle_key(key(KeyCode::End));
+        input.handle_key(key(KeyCode::Down));
+        assert_eq!(input.cursor_position(), (1, 1));
+    }
+
+    #[test]
+    fn view_scrolls_by_display_width() {
+        let mut input = typed("ab日本語");
+        // "日本語" is six columns wide, so the cursor at the end is column eight
+        let (lines, cursor) = input.view(4, 1);
+        // half of "本" is scrolled off
+        assert_eq!(lines, vec![" 語"]);
+        assert_eq!(cursor, (3, 0));
+
+        input.handle_key(key(KeyCode::Home));
+        let (lines, cursor) = input.view(4, 1);
+        assert_eq!(lines, vec!["ab日"]);
+        assert_eq!(cursor, (0, 0));
+
+        input.click(3, 0);
+        assert_eq!(input.cursor, "ab".len());
+    }
+}
@@ -1,59 +1,68 @@
-use chrono::serde::ts_milliseconds;
-use tokio::{
-    io::AsyncBufReadExt,
-    io::AsyncWriteExt,
-    io::{AsyncReadExt, BufReader},
-    net::{tcp::WriteHalf, TcpListener, TcpStream},
-    sync::broadcast,
-};
-
-use chrono::prelude::*;
 use crossterm::{
-    event::{self, KeyCode},
-    execute,
-    terminal::{
-        disable_raw_mode, enable_raw_mode, Clear, ClearType, EnterAlternateScreen,
-        LeaveAlternateScreen,
+    event::{
+        self, DisableMouseCapture, EnableMouseCapture, KeyCode, KeyModifiers, MouseButton,
+        MouseEventKind,
     },
+    execute,
+    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
-use rand::{distributions::Alphanumeric, prelude::*};
-use serde::{Deserialize, Serialize};
-use std::fmt::{self, Display};
-use std::io::Write;
-use std::sync::mpsc;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
 use std::thread;
 use std::time::{Duration, Instant};
-use std::{borrow::Cow, fs};
-use std::{fmt::Debug, io};
-use thiserror::Error;
-use tui::widgets::{
-    canvas::{Canvas, Points},
-    Wrap,
-};
 use tui::{
     backend::CrosstermBackend,
     layout::{Alignment, Constraint, Direction, Layout, Rect},
-    style::{Color, Modifier, Style},
+    style::{Color, Style},
     text::{Span, Spans},
-    widgets::{
-        Block, BorderType, Borders, Cell, List, ListItem, ListState, Paragraph, Row, Table, Tabs,
-    },
+    widgets::{Block, List, ListItem, Paragraph},
     Terminal,
 };
-use tui::{text::Text, widgets::Widget};
-use viuer::{print_from_file, Config};
 
-use image::{codecs::jpeg::JpegDecoder, io::Reader, ImageBuffer, RgbImage, Rgba};
-use std::collections::HashMap;
+use image::{io::Reader, RgbImage};
 use std::io::Cursor;
 use std::path::Path;
 
-use tui_image::{ColorMode, Image};
-
-use rscam::{Camera, Config as RscamConfig, Frame};
+use protocol::{
+    ClientChatData, ClientNetworkData, MediaState, ServerChatData, ServerNetworkData, UserId,
+    UserInfo, DEFAULT_ROOM,
+};
+use tokio::sync::mpsc::UnboundedSender;
 // use nokhwa::{Camera, CameraFormat, FrameFormat};
 
+use audio::{Encoder, Mixer};
+use backlog::Backlog;
+use capture::{Capture, CaptureStatus};
+use chat::ChatMessageInfo;
+use chat_view::ChatView;
+use commands::{Command, COMMANDS};
+use config::Config;
+use connection::ConnectionStatus;
+use focus::{pane_block, Focus, PaneAreas};
+use frames::LatestFrames;
+use graphics::Graphics;
+use input::ChatInput;
+use participant_list::ParticipantList;
+use video_grid::VideoGrid;
+use video_source::EncodedFrame;
+
+pub mod audio;
+pub mod backlog;
+pub mod capture;
+pub mod chat;
+pub mod chat_view;
+pub mod commands;
+pub mod config;
+pub mod connection;
+pub mod focus;
+pub mod frames;
+pub mod graphics;
+pub mod input;
+pub mod participant_list;
 pub mod util;
+pub mod video_grid;
+pub mod video_source;
 
 // #[derive(Serialize, Deserialize, Clone)]
 // struct Pet {
@@ -66,154 +75,118 @@ pub mod util;
 
 enum Event {
     UserInputKey(crossterm::event::KeyEvent),
-    UserInputFrame(Frame),
+    UserInputMouse(crossterm::event::MouseEvent),
+    /// A new camera frame is waiting in the local video slot.
+    UserInputFrame,
     ServerInput(ServerNetworkData),
+    /// New video from other users is waiting in the remote video slots.
+    ServerInputFrame,
+    Connection(ConnectionStatus),
+    /// Our camera started, stopped or failed.
+    Capture(CaptureStatus),
+    /// The microphone or speakers stopped working, already worded for the chat.
+    Audio(String),
+    /// The terminal changed size.
+    Resize,
     Tick,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct ServerNetworkData {
-    #[serde(with = "ts_milliseconds")]
-    timestamp: DateTime<Utc>,
-    // user id/struct
-    chat_data: ServerChatData,
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub enum ServerChatData {
-    OtherClientChatMessage(String),           // message
-    ReturnToSenderChatMessage(String, usize), // message, id
-    VideoFrame(Vec<u8>, u32, u32),            // (stream_data, width, height)
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct ClientNetworkData {
-    chat_data: ClientChatData,
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub enum ClientChatData {
-    ChatMessage(String, usize),    // message, id
-    VideoFrame(Vec<u8>, u32, u32), // (stream_data, width, height)
-}
-
-// make a method of ChatData
-fn convert_to_stream_data(network_data: &ClientNetworkData) -> Vec<u8> {
-    let buf = bincode::serialize(network_data).expect("serialize failed");
-    let buf_with_header = [&(buf.len() as u32).to_be_bytes(), &buf[..]].concat();
-    return buf_with_header;
-}
-
-struct ChatMessageInfo {
-    message: String,
-    is_pending: bool,
-    uid: usize,
-    timestamp: DateTime<Utc>,
-    // author: num or UserStruct
-}
-
-impl fmt::Display for ChatMessageInfo {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let local_time = Local.from_utc_datetime(&self.timestamp.naive_local());
-        let (pm, hour) = local_time.hour12();
-        let minute = local_time.minute();
-        let seconds = local_time.second();
-        let time = format!(
-            "{:0>2}:{:0>2}:{:0>2}{}",
-            hour,
-            minute,
-            seconds,
-            if pm { "PM" } else { "AM" }
-        );
-        write!(
-            f,
-            "[{time}] User Temp: {}{}",
-            self.message,
-            if self.is_pending { " (pending...)" } else { "" }
-        )
-    }
-}
-
-impl ChatMessageInfo {
-    fn new(message: String, is_pending: bool) -> Self {
-        return ChatMessageInfo {
-            message,
-            is_pending,
-            uid: util::get_uid(),
-            timestamp: chrono::offset::Utc::now(),
-        };
-    }
-
-    fn new_with_all(
-        message: String,
-        is_pending: bool,
-        uid: usize,
-        timestamp: DateTime<Utc>,
-    ) -> Self {
-        return ChatMessageInfo {
-            message,
-            is_pending,
-            uid,
-            timestamp,
-        };
-    }
-
-    fn new_with_timestamp(message: String, is_pending: bool, timestamp: DateTime<Utc>) -> Self {
-        return ChatMessageInfo {
-            message,
-            is_pending,
-            uid: util::get_uid(),
-            timestamp,
-        };
-    }
-
-    fn to_line_spans(&self, line_width: usize) -> Vec<Span> {
-        return textwrap::wrap(&self.to_string(), line_width)
-            .into_iter()
-            .map(|message| {
-                Span::styled(
-                    message.into_owned(),
-                    Style::default().fg(if self.is_pending {
-                        Color::Gray
-                    } else {
-                        Color::White
-                    }),
-                )
-            })
-            .collect::<Vec<_>>();
-    }
-}
-
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let config = Config::load().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
     let (tx, rx) = mpsc::channel();
-    let tick_rate = Duration::from_millis(67);
-
-    // let mess = ChatData::ChatMessage(String::from("test message from client"));
-    let stream = TcpStream::connect("127.0.0.1:8080").await?;
-    let (reader, mut writer) = stream.into_split();
-    let mut buf_reader = BufReader::new(reader);
+    let tick_rate = config.tick_rate();
+
+    // the connection introduces us on every connect, the server replies with the id (and cleaned
+    // up name) we should use
+    // video gets its own newest-frame-only slots so it can never hold up chat
+    let local_video: LatestFrames<(), EncodedFrame> = LatestFrames::new();
+    let outgoing_video = LatestFrames::new();
+    let remote_video = LatestFrames::new();
+    let remote_audio = Mixer::default();
+    let outgoing = connection::spawn(
+        config.server_addr(),
+        config.nickname.clone(),
+        tx.clone(),
+        outgoing_video.clone(),
+        remote_video.clone(),
+        remote_audio.clone(),
+    );
+
+    // audio runs on its own, these only need to stay alive for as long as we want it to
+    let mut startup_errors = Vec::new();
+    let microphone_unmuted = Arc::new(AtomicBool::new(true));
+    // errors from the audio threads, which mustn't write over the UI
+    let audio_errors = |device: &'static str| -> audio::OnError {
+        let tx = tx.clone();
+        Box::new(move |e| {
+            let message = format!("{} stopped working: {}", device, e);
+            let _ = tx.send(Event::Audio(message));
+        })
+    };
+    let audio_input = audio::start_input(
+        &config.audio_input,
+        Encoder::new(outgoing.clone(), microphone_unmuted.clone()),
+        audio_errors("Microphone"),
+    )
+    .unwrap_or_else(|e| {
+        startup_errors.push(format!("Microphone unavailable: {}", e));
+        None
+    });
+    let _audio_output = audio::start_output(
+        &config.audio_output,
+        remote_audio.clone(),
+        audio_errors("Speakers"),
+    )
+    .map_err(|e| startup_errors.push(format!("Speakers unavailable: {}", e)))
+    .ok();
+    let mut connection_status = (ConnectionStatus::Connecting, Instant::now());
+    let mut me = UserInfo {
+        id: 0,
+        name: config.nickname.clone(),
+    };
+
+    // the server puts us in the default room on every connect, this is where we ask to move to instead
+    let mut room_to_rejoin = config
+        .room
+        .clone()
+        .map(|room| (room, config.passcode.clone()));
+    let mut current_room = String::from(DEFAULT_ROOM);
+    // a room asked for with /join or /leave, which becomes the one to rejoin once we're in it
+    let mut joining: Option<(String, Option<String>)> = None;
+    // the nickname picked with /nick, to ask for again after reconnecting
+    let mut renamed: Option<String> = None;
+    // everyone else in the current room and their video
+    let mut video_grid = VideoGrid::new(config.self_view);
+    // what we're sending, the camera turns itself on once it's started
+    let mut media = MediaState {
+        camera: false,
+        microphone: audio_input.is_some(),
+    };
+    video_grid.set_own_media(media);
+    // whose video the server is sending us, only those on the current page
+    let mut video_subscriptions: Vec<UserId> = Vec::new();
 
     // let mess_data = convert_to_stream_data(&mess);
     // writer.write_all(&mess_data).await?;
 
+    let (video_spec, camera_device) = (config.video_source.clone(), config.camera_device.clone());
+    let (resolution, fps) = (
+        (config.capture_width, config.capture_height),
+        config.capture_fps,
+    );
+    let capture = Capture::spawn(
+        Box::new(move || video_source::open(&video_spec, &camera_device, resolution, fps)),
+        local_video.clone(),
+        tx.clone(),
+    );
+    capture.start();
+
     let tx1 = tx.clone();
     let _user_input_handler = thread::spawn(move || {
-        let mut camera = Camera::new("/dev/video0");
-        let mut camera_available = camera.is_ok();
-        if camera_available {
-            camera_available = camera
-                .as_mut()
-                .unwrap()
-                .start(&RscamConfig {
-                    interval: (1, 15),
-                    resolution: (176, 144),
-                    format: b"MJPG",
-                    ..Default::default()
-                })
-                .is_ok();
-        }
-
         let mut last_tick = Instant::now();
         loop {
             let timeout = tick_rate
@@ -221,101 +194,63 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .unwrap_or_else(|| Duration::from_secs(0));
 
             if event::poll(timeout).expect("poll works") {
-                if let crossterm::event::Event::Key(key) = event::read().expect("can read events") {
-                    tx1.send(Event::UserInputKey(key)).expect("can send events");
-                }
-            }
-
-            if last_tick.elapsed() >= tick_rate {
-                if let Ok(_) = tx1.send(Event::Tick) {
-                    last_tick = Instant::now();
+                match event::read().expect("can read events") {
+                    crossterm::event::Event::Key(key) => {
+                        tx1.send(Event::UserInputKey(key)).expect("can send events")
+                    }
+                    crossterm::event::Event::Mouse(mouse) => tx1
+                        .send(Event::UserInputMouse(mouse))
+                        .expect("can send events"),
+                    crossterm::event::Event::Resize(..) => {
+                        tx1.send(Event::Resize).expect("can send events")
+                    }
                 }
             }
 
-            if camera_available {
-                let frame = camera.as_mut().unwrap().capture().unwrap();
-                tx1.send(Event::UserInputFrame(frame))
-                    .expect("can send events");
-            }
-        }
-    });
-    let tx2 = tx.clone();
-    let _server_input_handler = tokio::spawn(async move {
-        loop {
-            // get incoming message from server
-            let res = buf_reader.read_u32().await;
-            match res {
-                Ok(size) => {
-                    let mut buf = vec![0u8; size as usize];
-                    let res = buf_reader.read_exact(&mut buf).await;
-                    match res {
-                        Ok(bytes_read) if bytes_read == size as usize => {
-                            let chat_data =
-                                bincode::deserialize(&buf).expect("deserialize should work");
-                            tx2.send(Event::ServerInput(chat_data)).unwrap();
-                        }
-                        Ok(_) => {
-                            println!("didn't read right number of bytes");
-                        }
-                        Err(e) => match e.kind() {
-                            tokio::io::ErrorKind::UnexpectedEof => {
-                                println!("client disconnected");
-                                break;
-                            }
-                            _ => println!("read u64: {}", e),
-                        },
-                    }
-                }
-                Err(e) => match e.kind() {
-                    tokio::io::ErrorKind::UnexpectedEof => {
-                        println!("client disconnected");
-                        break;
-                    }
-                    _ => println!("read u64: {}", e),
-                },
+            if last_tick.elapsed() >= tick_rate && tx1.send(Event::Tick).is_ok() {
+                last_tick = Instant::now();
             }
         }
     });
-
     enable_raw_mode().expect("can run in raw mode");
     let mut stdout = io::stdout();
-    execute!(stdout, EnterAlternateScreen)?;
+    execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
     let backend = CrosstermBackend::new(stdout);
     let mut terminal = Terminal::new(backend)?;
     terminal.clear()?;
     terminal.hide_cursor()?;
-
-    let mut video_frames: Vec<ImageBuffer<Rgba<u8>, Vec<u8>>> = Vec::new();
+    // full resolution video drawn over the panes, if the terminal can do it
+    let mut graphics = graphics::choose(config.graphics)
+        .map(|protocol| Graphics::new(protocol, graphics::cell_size()));
+    if graphics.is_some() {
+        video_grid.use_graphics();
+    }
+    video_grid.set_text_mode(config.text_mode);
+    video_grid.set_palette(graphics::choose_palette(config.colors), config.dither);
 
     let mut chat_history: Vec<ChatMessageInfo> = Vec::with_capacity(8);
-    let mut current_input = String::with_capacity(16);
+    chat_history.extend(startup_errors.into_iter().map(ChatMessageInfo::new_system));
+    let mut chat_input = ChatInput::default();
 
-    // let mut chat_history_prev_width = 0;
-    let mut chat_history_stick_to_bottom = true;
+    let mut chat_view = ChatView::default();
+    let mut backlog = Backlog::default();
 
-    // let mut chat_history_line_start_index: usize = 0;
-    // let mut chat_history_message_index: usize = 0;
-    let mut chat_history_message_line_index: usize = 0;
-    // let mut chat_history_line_offset: i64 = 0;
-
-    // let mut chat_history_selected_ind: Option<usize> = None;
-    // let mut chat_history_list_state = ListState::default();
-    // chat_history_list_state.select(None);
-
-    let mut current_chat_input_index: usize = 0;
-    let mut current_chat_input_scroll_index: u16 = 0;
+    // which pane keys go to, starting with the input so typing just works
+    let mut focus = Focus::default();
+    let mut participant_list = ParticipantList::default();
+    // where everything was last drawn, so mouse clicks can be matched up with it
+    let mut pane_areas = PaneAreas::default();
+    let mut video_pane_areas: Vec<(Option<UserId>, Rect)> = Vec::new();
+    let mut chat_history_text_area = Rect::default();
+    let mut chat_input_text_area = Rect::default();
 
     // println!("start get colors");
-    let img_path = "test_image.jpg";
     // let img = open(img_path);
     // let width = img.width();
     // let height = img.height();
     // let img_data = group_by_color(img);
     // println!("finished getting colors");
 
-    let img = image::open(img_path)?.to_rgba8();
-    video_frames.push(img);
-
     // loop {}
     // return Ok(());
     // let mut camera = Camera::new(
@@ -360,308 +295,529 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     loop {
         terminal.draw(|screen_area| {
             let screen_size = screen_area.size();
+            let banner = match &connection_status {
+                (ConnectionStatus::Connected, _) => None,
+                (ConnectionStatus::Connecting, _) => Some(String::from("Connecting to server...")),
+                (ConnectionStatus::Reconnecting { attempt, retry_in }, since) => Some(format!(
+                    "Disconnected from server, retrying in {}s (attempt {})",
+                    retry_in.saturating_sub(since.elapsed()).as_secs() + 1,
+                    attempt
+                )),
+            };
             let hoz_areas = Layout::default()
                 .direction(Direction::Vertical)
                 .margin(1)
-                .constraints([Constraint::Min(20), Constraint::Percentage(20)].as_ref())
-                .split(screen_size);
-            let video_area = hoz_areas[0];
-            let chat_area = hoz_areas[1];
-
-            let video_frame = Block::default()
-                .borders(Borders::ALL)
-                .border_style(Style::default().fg(Color::White))
-                .border_type(BorderType::Double)
-                .style(Style::default().bg(Color::Black));
-            screen_area.render_widget(video_frame.clone(), video_area);
-
-            let num_video_panes: usize = 2;
-            let video_panes = Layout::default()
-                .direction(Direction::Horizontal)
                 .constraints(
-                    [Constraint::Percentage((100 / num_video_panes) as u16)]
-                        .repeat(num_video_panes.into())
-                        .as_ref(),
+                    [
+                        Constraint::Length(if banner.is_some() { 1 } else { 0 }),
+                        Constraint::Min(20),
+                        Constraint::Percentage(30),
+                    ]
+                    .as_ref(),
                 )
-                .split(video_frame.inner(video_area));
-            for (ind, video_pane) in video_panes.iter().enumerate() {
-                if ind < video_frames.len() {
-                    screen_area.render_widget(
-                        Image::with_img(video_frames.get(ind).unwrap().to_owned())
-                            .color_mode(ColorMode::Rgb),
-                        *video_pane,
-                    );
-                }
+                .split(screen_size);
+            let banner_area = hoz_areas[0];
+            let video_area = hoz_areas[1];
+            let chat_area = hoz_areas[2];
+
+            if let Some(banner) = banner {
+                let banner_widget = Paragraph::new(banner)
+                    .style(Style::default().fg(Color::White).bg(Color::Red))
+                    .alignment(Alignment::Center);
+                screen_area.render_widget(banner_widget, banner_area);
             }
 
-            let chat_frame = Block::default()
-                .title("Chat")
-                .borders(Borders::ALL)
-                .border_style(Style::default().fg(Color::White))
-                .border_type(BorderType::Double)
-                .style(Style::default().bg(Color::Black));
-            screen_area.render_widget(chat_frame.clone(), chat_area);
+            let video_title = match (video_grid.spotlight(), video_grid.page_count()) {
+                (Some(user), _) => format!("{} (click again to go back)", user.name),
+                (None, 1) => String::new(),
+                (None, page_count) => format!(
+                    "Page {}/{} (Alt-Left/Alt-Right)",
+                    video_grid.page() + 1,
+                    page_count
+                ),
+            };
+            let video_frame = pane_block(video_title, focus == Focus::VideoGrid);
+            screen_area.render_widget(video_frame.clone(), video_area);
+
+            video_pane_areas = video_grid.render(screen_area, video_frame.inner(video_area), &me);
 
+            let chat_columns = Layout::default()
+                .direction(Direction::Horizontal)
+                .constraints([Constraint::Min(20), Constraint::Length(28)].as_ref())
+                .split(chat_area);
             let chat_sections = Layout::default()
                 .direction(Direction::Vertical)
-                // .margin(1)
-                .constraints([Constraint::Min(1), Constraint::Length(1)].as_ref())
-                .split(chat_frame.inner(chat_area));
-            let chat_history_area = chat_sections[0];
-            let chat_input_area = chat_sections[1];
+                .constraints(
+                    [
+                        Constraint::Min(3),
+                        // grows with the message being written, up to a few lines
+                        Constraint::Length(chat_input.rows() as u16 + 2),
+                    ]
+                    .as_ref(),
+                )
+                .split(chat_columns[0]);
+
+            let participants_frame = pane_block(
+                format!("People ({})", video_grid.participants().count() + 1),
+                focus == Focus::Participants,
+            );
+            participant_list.render(
+                screen_area,
+                chat_columns[1],
+                participants_frame,
+                focus == Focus::Participants,
+                &me,
+                &video_grid,
+            );
+
+            let chat_history_frame = pane_block(
+                format!("Chat - {}", current_room),
+                focus == Focus::ChatHistory,
+            );
+            let chat_history_area = chat_history_frame.inner(chat_sections[0]);
+            screen_area.render_widget(chat_history_frame, chat_sections[0]);
+
+            let chat_input_frame = pane_block(String::from("Message"), focus == Focus::ChatInput);
+            let chat_input_area = chat_input_frame.inner(chat_sections[1]);
+            screen_area.render_widget(chat_input_frame, chat_sections[1]);
+
+            pane_areas = PaneAreas {
+                video_grid: video_area,
+                chat_history: chat_sections[0],
+                chat_input: chat_sections[1],
+                participants: chat_columns[1],
+            };
+            chat_history_text_area = chat_history_area;
+            chat_input_text_area = chat_input_area;
 
             let chat_history_area_height = chat_history_area.height as usize;
             let chat_history_area_width = chat_history_area.width as usize;
             if chat_history_area_height > 0 && chat_history_area_width > 0 {
                 chat_history.sort_by_key(|chat_msg| chat_msg.timestamp);
-                let chat_history_lines: Vec<_> = chat_history
+                // other rooms' messages are kept for if we go back to them
+                let shown_history: Vec<&ChatMessageInfo> = chat_history
+                    .iter()
+                    .filter(|chat_msg| chat_msg.shown_in(&current_room))
+                    .collect();
+                let chat_history_lines: Vec<Vec<Span>> = shown_history
                     .iter()
                     .enumerate()
-                    .flat_map(|(ind, chat_msg)| {
+                    .map(|(ind, chat_msg)| {
                         let mut line_spans = chat_msg.to_line_spans(chat_history_area_width);
                         for span in &mut line_spans {
-                            span.style = span.style.bg(if ind & 1 == 0 {
-                                Color::LightMagenta
+                            span.style = if Some(chat_msg.uid) == chat_view.selected() {
+                                span.style.bg(Color::Yellow).fg(Color::Black)
                             } else {
-                                Color::LightBlue
-                            });
+                                span.style.bg(if ind & 1 == 0 {
+                                    Color::LightMagenta
+                                } else {
+                                    Color::LightBlue
+                                })
+                            };
                         }
-                        return line_spans;
+                        line_spans
                     })
                     .collect(); // only map as needed in future
-
-                // force chat_history_message_line_index within bounds
-                if chat_history_stick_to_bottom
-                    || chat_history_message_line_index + chat_history_area_height
-                        > chat_history_lines.len()
-                {
-                    chat_history_message_line_index = chat_history_lines
-                        .len()
-                        .saturating_sub(chat_history_area_height);
-                    chat_history_stick_to_bottom = true;
-                }
+                let visible_lines = chat_view.visible(
+                    shown_history
+                        .iter()
+                        .zip(&chat_history_lines)
+                        .map(|(chat_msg, lines)| (chat_msg.uid, lines.len()))
+                        .collect(),
+                    chat_history_area_height,
+                );
 
                 let chat_history_items: Vec<ListItem> = chat_history_lines
-                    .get(
-                        chat_history_message_line_index
-                            ..std::cmp::min(
-                                chat_history_message_line_index + chat_history_area_height,
-                                chat_history_lines.len(),
-                            ),
-                    )
-                    .unwrap()
-                    .iter()
-                    .map(|chat_msg| ListItem::new(chat_msg.clone()))
+                    .into_iter()
+                    .flatten()
+                    .skip(visible_lines.start)
+                    .take(visible_lines.len())
+                    .map(ListItem::new)
                     .collect();
                 let chat_history_widget = List::new(chat_history_items)
                     .style(Style::default().fg(Color::Black))
                     .block(Block::default().style(Style::default().fg(Color::White)));
                 screen_area.render_widget(chat_history_widget, chat_history_area);
-            }
 
-            // chat_history_line_offset = 0;
-            // chat_history_prev_width = chat_history_area_width;
-
-            // if message_ind+line_ind is as far down as possible, set chat_history_stick_to_bottom = true
-
-            // use calculated chat_history_line_start_index to create list of up to chat_history_area_height items from chat_history_lines
-
-            // // update visible window only if chat history is non-empty
-            // if let Some(selected_ind) = chat_history_selected_ind {
-            //     if chat_history_area_len > 0 {
-            //         // if selected_ind is out of bounds of visible window (it will be within bounds of chat_history), move visible window to be within bounds
-            //         if selected_ind >=  {
-
-            //         } else if selected_ind < chat_history_start_index {
-
-            //         }
-
-            //         // update list state index to match selected_ind
-            //         // chat_history_list_state.select(Some(selected_ind - chat_history_start_index));
-            //     }
-            // } else {
-            //     // update list state index to match selected_ind
-            //     chat_history_list_state.select(None);
-            // }
-
-            // // let chat_history = textwrap::wrap(
-            // //     "textwrap: an efficient and powerful library for wrapping text.",
-            // //     chat_history_area.width as usize,
-            // // );
-            // let chat_history_items: Vec<ListItem> = chat_history
-            //     .iter()
-            //     .map(|chat_msg| textwrap::wrap(chat_msg, chat_history_area.width as usize))
-            //     .reduce(f)
-            //     // .get(
-            //     //     chat_history_start_index
-            //     //         ..std::cmp::min(
-            //     //             chat_history_start_index + chat_history_area_len,
-            //     //             chat_history.len(),
-            //     //         ),
-            //     // )
-            //     // .unwrap()
-            //     // .iter()
-            //     // .map(|chat_msg| ListItem::new(Text::from(vec![Spans::from(chat_msg.clone())])))
-            //     // .collect();
-            // let chat_history_widget = List::new(chat_history_items)
-            //     .style(Style::default().fg(Color::LightCyan))
-            //     .block(Block::default().style(Style::default().fg(Color::White)))
-            //     .highlight_style(
-            //         Style::default()
-            //             .bg(Color::Yellow)
-            //             .fg(Color::Black)
-            //             .add_modifier(Modifier::BOLD),
-            //     );
-            // screen_area.render_stateful_widget(
-            //     chat_history_widget,
-            //     chat_history_area,
-            //     &mut chat_history_list_state,
-            // );
-
-            if current_chat_input_index < current_chat_input_scroll_index as usize {
-                // we need to scroll to the left
-                current_chat_input_scroll_index = current_chat_input_index as u16;
-            } else if current_chat_input_index
-                >= (current_chat_input_scroll_index + chat_input_area.width) as usize
-            {
-                // we need to scroll to the right
-                current_chat_input_scroll_index =
-                    current_chat_input_index as u16 - chat_input_area.width + 1;
+                if chat_view.new_below() > 0 {
+                    let notice = match chat_view.new_below() {
+                        1 => String::from("1 new message below (End)"),
+                        count => format!("{} new messages below (End)", count),
+                    };
+                    let notice_area = Rect {
+                        y: chat_history_area.bottom() - 1,
+                        height: 1,
+                        ..chat_history_area
+                    };
+                    screen_area.render_widget(
+                        Paragraph::new(notice)
+                            .style(Style::default().fg(Color::Black).bg(Color::Yellow))
+                            .alignment(Alignment::Center),
+                        notice_area,
+                    );
+                }
             }
 
-            let chat_input_widget = Paragraph::new(current_input.clone())
-                .block(Block::default().style(Style::default().fg(Color::Rgb(255, 150, 150))))
-                .scroll((0, current_chat_input_scroll_index));
+            let (input_lines, (cursor_x, cursor_y)) = chat_input.view(
+                chat_input_area.width as usize,
+                chat_input_area.height as usize,
+            );
+            let chat_input_widget =
+                Paragraph::new(input_lines.into_iter().map(Spans::from).collect::<Vec<_>>())
+                    .block(Block::default().style(Style::default().fg(Color::Rgb(255, 150, 150))));
 
-            if chat_input_area.height > 0 && chat_input_area.width > 0 {
+            // the cursor stays hidden unless typing would go somewhere
+            if focus == Focus::ChatInput && chat_input_area.height > 0 && chat_input_area.width > 0
+            {
                 screen_area.set_cursor(
-                    chat_input_area.x + current_chat_input_index as u16
-                        - current_chat_input_scroll_index,
-                    chat_input_area.y,
+                    chat_input_area.x + cursor_x as u16,
+                    chat_input_area.y + cursor_y as u16,
                 );
             }
 
             screen_area.render_widget(chat_input_widget, chat_input_area);
         })?;
 
+        if let Some(graphics) = &mut graphics {
+            let pictures = video_grid.pictures(&video_pane_areas);
+            if graphics.draw(terminal.backend_mut(), &pictures)? {
+                // a picture has gone from where tui thinks there's nothing to draw
+                terminal.clear()?;
+                continue;
+            }
+        }
+
+        // scrolled back as far as we've got, so fetch the page before it
+        if chat_view.at_top() {
+            if let Some(id) = backlog.next_request() {
+                let _ = outgoing.send(ClientNetworkData {
+                    chat_data: ClientChatData::RequestHistory(id),
+                });
+            }
+        }
+
         match rx.recv()? {
-            Event::UserInputKey(event) => match event.code {
-                // check for ctrl vs no ctrl modifier, ctrl-C/D should quit also
-                // ctrl-Q/ESC should quit to main menu once we have rooms setup
-                KeyCode::Char('q') => {
-                    let mut stdout = io::stdout();
-                    execute!(stdout, LeaveAlternateScreen)?;
-                    disable_raw_mode()?;
-                    terminal.show_cursor()?;
-                    // once meeting rooms are setup, figure out how we want to quit threads etc.
-                    break;
-                }
-                KeyCode::Char(c) => {
-                    // create helper function to, when pushing a char (unless in insert mode), always move cursor one right
-                    current_input.insert(current_chat_input_index, c);
-                    current_chat_input_index += 1;
-                }
-                KeyCode::Backspace => {
-                    if current_chat_input_index > 0 {
-                        current_input.remove(current_chat_input_index - 1);
-                        current_chat_input_index -= 1;
+            Event::UserInputKey(event) => {
+                let ctrl = event.modifiers.contains(KeyModifiers::CONTROL);
+                let alt = event.modifiers.contains(KeyModifiers::ALT);
+                // keys that do the same thing whatever has focus
+                match event.code {
+                    KeyCode::Char('c') | KeyCode::Char('q') if ctrl => break,
+                    KeyCode::Tab => focus = focus.next(),
+                    KeyCode::BackTab => focus = focus.prev(),
+                    KeyCode::Char('v') if alt => {
+                        if media.camera {
+                            capture.stop();
+                        } else {
+                            capture.start();
+                        }
                     }
-                }
-                KeyCode::Delete => {
-                    if current_chat_input_index < current_input.len() {
-                        current_input.remove(current_chat_input_index);
+                    KeyCode::Char('m') if alt => {
+                        let message = toggle_microphone(
+                            &mut media,
+                            audio_input.is_some(),
+                            &microphone_unmuted,
+                            &mut video_grid,
+                            &outgoing,
+                        );
+                        chat_history.push(ChatMessageInfo::new_system(String::from(message)));
                     }
-                }
-                KeyCode::Enter => {
-                    let user_message = current_input.clone();
-                    // initial add to chat history (will update after server response)
-                    let chat_msg_info = ChatMessageInfo::new(user_message.clone(), true);
-                    let msg_uid = chat_msg_info.uid;
-                    chat_history.push(chat_msg_info);
-                    // send to server
-                    let mess_data = convert_to_stream_data(&ClientNetworkData {
-                        chat_data: ClientChatData::ChatMessage(user_message, msg_uid),
-                    });
-                    writer.write_all(&mess_data).await?;
-                    // reset input field
-                    current_input.clear();
-                    current_chat_input_index = 0;
-                }
-                KeyCode::Up => {
-                    // chat_history_message_line_index -= 1;
-                    if chat_history_message_line_index > 0 {
-                        chat_history_message_line_index -= 1;
+                    KeyCode::Char('r') if alt => {
+                        let message = if graphics.is_some() {
+                            String::from(
+                                "Video is drawn with graphics, start with --graphics blocks to draw it with text",
+                            )
+                        } else {
+                            let text_mode = video_grid.text_mode().next();
+                            video_grid.set_text_mode(text_mode);
+                            format!(
+                                "Drawing video with {}, Alt-R for the next way",
+                                text_mode.name()
+                            )
+                        };
+                        chat_history.push(ChatMessageInfo::new_system(message));
+                    }
+                    KeyCode::Left if alt => {
+                        video_grid.prev_page();
+                        update_video_subscriptions(
+                            &video_grid,
+                            &mut video_subscriptions,
+                            &outgoing,
+                        );
+                    }
+                    KeyCode::Right if alt => {
+                        video_grid.next_page();
+                        update_video_subscriptions(
+                            &video_grid,
+                            &mut video_subscriptions,
+                            &outgoing,
+                        );
+                    }
+                    code => {
+                        // typing from anywhere else goes straight into the input
+                        if matches!(code, KeyCode::Char(_)) && !ctrl && !alt {
+                            focus = Focus::ChatInput;
+                        }
+                        match (focus, code) {
+                            (Focus::ChatHistory, KeyCode::Up) => chat_view.scroll_by(-1),
+                            (Focus::ChatHistory, KeyCode::Down) => chat_view.scroll_by(1),
+                            (Focus::ChatHistory | Focus::ChatInput, KeyCode::PageUp) => {
+                                chat_view.page_up()
+                            }
+                            (Focus::ChatHistory | Focus::ChatInput, KeyCode::PageDown) => {
+                                chat_view.page_down()
+                            }
+                            (Focus::ChatHistory, KeyCode::Home) => chat_view.home(),
+                            (Focus::ChatHistory, KeyCode::End) => chat_view.end(),
+                            (Focus::ChatHistory, KeyCode::Esc) => chat_view.clear_selection(),
+                            (Focus::VideoGrid, KeyCode::Left) => {
+                                video_grid.prev_page();
+                                update_video_subscriptions(
+                                    &video_grid,
+                                    &mut video_subscriptions,
+                                    &outgoing,
+                                );
+                            }
+                            (Focus::VideoGrid, KeyCode::Right) => {
+                                video_grid.next_page();
+                                update_video_subscriptions(
+                                    &video_grid,
+                                    &mut video_subscriptions,
+                                    &outgoing,
+                                );
+                            }
+                            (Focus::Participants, KeyCode::Up) => participant_list.select_prev(),
+                            (Focus::Participants, KeyCode::Down) => participant_list.select_next(),
+                            (Focus::Participants, KeyCode::Enter) => {
+                                // bring their video into view
+                                if let Some(user_id) = participant_list.selected(&video_grid) {
+                                    video_grid.show(user_id);
+                                    update_video_subscriptions(
+                                        &video_grid,
+                                        &mut video_subscriptions,
+                                        &outgoing,
+                                    );
+                                    focus = Focus::VideoGrid;
+                                }
+                            }
+                            (Focus::ChatInput, _) => {
+                                let line = match chat_input.handle_key(event) {
+                                    Some(line) => line,
+                                    None => continue,
+                                };
+                                let chat_data = match commands::parse(&line) {
+                                    Ok(Command::Say(user_message)) => {
+                                        // initial add to chat history (will update after server response)
+                                        let chat_msg_info = ChatMessageInfo::new(
+                                            me.clone(),
+                                            user_message.clone(),
+                                            true,
+                                        )
+                                        .in_room(&current_room);
+                                        let msg_uid = chat_msg_info.uid;
+                                        chat_history.push(chat_msg_info);
+                                        Some(ClientChatData::ChatMessage(user_message, msg_uid))
+                                    }
+                                    Ok(Command::Nick(name)) => {
+                                        Some(ClientChatData::SetNickname(name))
+                                    }
+                                    Ok(Command::Join(room, passcode)) => {
+                                        joining = Some((room.clone(), passcode.clone()));
+                                        Some(ClientChatData::JoinRoom(room, passcode))
+                                    }
+                                    Ok(Command::Leave) if current_room == DEFAULT_ROOM => {
+                                        chat_history.push(ChatMessageInfo::new_system(format!(
+                                            "Already in {}, the room everyone starts in",
+                                            DEFAULT_ROOM
+                                        )));
+                                        None
+                                    }
+                                    Ok(Command::Leave) => {
+                                        joining = Some((String::from(DEFAULT_ROOM), None));
+                                        Some(ClientChatData::JoinRoom(
+                                            String::from(DEFAULT_ROOM),
+                                            None,
+                                        ))
+                                    }
+                                    Ok(Command::Me(action)) => Some(ClientChatData::Emote(action)),
+                                    Ok(Command::Msg(to, message)) => {
+                                        Some(ClientChatData::DirectMessage(to, message))
+                                    }
+                                    Ok(Command::Who) => {
+                                        let names: Vec<String> =
+                                            std::iter::once(format!("{} (you)", me.name))
+                                                .chain(video_grid.participants().map(
+                                                    |(participant, _)| participant.name.clone(),
+                                                ))
+                                                .collect();
+                                        chat_history.push(ChatMessageInfo::new_system(format!(
+                                            "In {}: {}",
+                                            current_room,
+                                            names.join(", ")
+                                        )));
+                                        None
+                                    }
+                                    Ok(Command::Mute) => {
+                                        let message = toggle_microphone(
+                                            &mut media,
+                                            audio_input.is_some(),
+                                            &microphone_unmuted,
+                                            &mut video_grid,
+                                            &outgoing,
+                                        );
+                                        chat_history.push(ChatMessageInfo::new_system(
+                                            String::from(message),
+                                        ));
+                                        None
+                                    }
+                                    Ok(Command::Help) => {
+                                        for (usage, description) in COMMANDS {
+                                            chat_history.push(ChatMessageInfo::new_system(
+                                                format!("{} - {}", usage, description),
+                                            ));
+                                        }
+                                        None
+                                    }
+                                    Ok(Command::Quit) => break,
+                                    Err(e) => {
+                                        chat_history
+                                            .push(ChatMessageInfo::new_system(e.to_string()));
+                                        None
+                                    }
+                                };
+                                if let Some(chat_data) = chat_data {
+                                    let _ = outgoing.send(ClientNetworkData { chat_data });
+                                }
+                                // you'll want to see what came of it
+                                chat_view.end();
+                            }
+                            _ => {}
+                        }
                     }
-                    chat_history_stick_to_bottom = false;
-                    // if let Some(selected_ind) = chat_history_selected_ind {
-                    //     if selected_ind > 0 {
-                    //         chat_history_selected_ind = Some(selected_ind - 1);
-                    //         // since terminal.draw has access to space available for chat history, we do the logic for changing chat_history_start_index there
-                    //     }
-                    // } else if chat_history.len() > 0 {
-                    //     // should never be needed since when a new chat message is added we update selected_ind
-                    //     chat_history_selected_ind = Some(0);
-                    // }
                 }
-                KeyCode::Down => {
-                    chat_history_message_line_index += 1;
-                    // let UI keep within bounds
-                    // chat_history_message_line_index += 1;
-                    // if let Some(selected_ind) = chat_history_selected_ind {
-                    //     if selected_ind + 1 < chat_history.len() {
-                    //         chat_history_selected_ind = Some(selected_ind + 1);
-                    //         // since terminal.draw has access to space available for chat history, we do the logic for changing chat_history_start_index there
-                    //     }
-                    // } else if chat_history.len() > 0 {
-                    //     // should never be needed since when a new chat message is added we update selected_ind
-                    //     chat_history_selected_ind = Some(0);
-                    // }
+            }
+            Event::UserInputMouse(mouse) => {
+                let (column, row) = (mouse.column, mouse.row);
+                match mouse.kind {
+                    MouseEventKind::ScrollUp
+                        if focus::contains(pane_areas.chat_history, column, row) =>
+                    {
+                        chat_view.scroll_by(-3);
+                    }
+                    MouseEventKind::ScrollDown
+                        if focus::contains(pane_areas.chat_history, column, row) =>
+                    {
+                        chat_view.scroll_by(3);
+                    }
+                    MouseEventKind::Down(MouseButton::Left) => {
+                        let pane = match pane_areas.pane_at(column, row) {
+                            Some(pane) => pane,
+                            None => continue,
+                        };
+                        focus = pane;
+                        match pane {
+                            Focus::VideoGrid => {
+                                // the first pane that matches is the one on top
+                                let clicked = video_pane_areas
+                                    .iter()
+                                    .find(|(_, area)| focus::contains(*area, column, row));
+                                if let Some((Some(user_id), _)) = clicked {
+                                    video_grid.toggle_spotlight(*user_id);
+                                    update_video_subscriptions(
+                                        &video_grid,
+                                        &mut video_subscriptions,
+                                        &outgoing,
+                                    );
+                                }
+                            }
+                            Focus::ChatHistory
+                                if focus::contains(chat_history_text_area, column, row) =>
+                            {
+                                // clicking the selected message again lets go of it
+                                if let Some(uid) =
+                                    chat_view.uid_at((row - chat_history_text_area.y) as usize)
+                                {
+                                    chat_view.toggle_selected(uid);
+                                }
+                            }
+                            Focus::ChatInput
+                                if focus::contains(chat_input_text_area, column, row) =>
+                            {
+                                chat_input.click(
+                                    (column - chat_input_text_area.x) as usize,
+                                    (row - chat_input_text_area.y) as usize,
+                                );
+                            }
+                            _ => {}
+                        }
+                    }
+                    _ => {}
                 }
-                KeyCode::Right => {
-                    // move input cursor right (if chat input is focoused)
-                    if current_chat_input_index < current_input.len() {
-                        current_chat_input_index += 1;
+            }
+            Event::UserInputFrame => {
+                for ((), frame) in local_video.take() {
+                    if let Ok(img) = image::load_from_memory(&frame.data) {
+                        video_grid.set_own_frame(&img.to_rgba8());
                     }
+                    outgoing_video.put(
+                        (),
+                        ClientNetworkData {
+                            chat_data: ClientChatData::VideoFrame(
+                                frame.data,
+                                frame.width,
+                                frame.height,
+                            ),
+                        },
+                    );
+                }
+            }
+            Event::Capture(status) => {
+                media.camera = matches!(status, CaptureStatus::Started);
+                if !media.camera {
+                    // anything still waiting would bring back the frame we're about to clear
+                    local_video.take();
                 }
-                KeyCode::Left => {
-                    // move input cursor left (if chat input is focused)
-                    if current_chat_input_index > 0 {
-                        current_chat_input_index -= 1;
+                video_grid.set_own_media(media);
+                send_media_state(media, &outgoing);
+                let message = match status {
+                    CaptureStatus::Started => String::from("Camera on, Alt-V turns it off"),
+                    CaptureStatus::Stopped => String::from("Camera off, Alt-V turns it back on"),
+                    CaptureStatus::Failed(e) => format!("Camera stopped: {}, Alt-V to retry", e),
+                };
+                chat_history.push(ChatMessageInfo::new_system(message));
+            }
+            Event::Audio(message) => {
+                chat_history.push(ChatMessageInfo::new_system(message));
+            }
+            Event::ServerInputFrame => {
+                for (_, data) in remote_video.take() {
+                    if let ServerChatData::VideoFrame(sender, data, _width, _height) =
+                        data.chat_data
+                    {
+                        // println!("got frame with {} {}", width, height);
+                        // RgbImage::from
+                        // println!("{} {}", width * height, data.len());
+                        let c = Cursor::new(data);
+                        let r = Reader::new(c);
+                        let img = match r.with_guessed_format().map(|img| img.decode()) {
+                            Ok(Ok(img)) => img,
+                            // not worth tearing down the UI over one bad frame
+                            _ => continue,
+                        };
+                        // let temp = JpegDecoder::new(r);
+                        video_grid.set_frame(sender.id, img.to_rgba8());
                     }
                 }
-                _ => {}
-            },
-            Event::UserInputFrame(frame) => {
-                let frame_data = convert_to_stream_data(&ClientNetworkData {
-                    chat_data: ClientChatData::VideoFrame(
-                        frame[..].to_vec(),
-                        frame.resolution.0,
-                        frame.resolution.1,
-                    ),
-                });
-                writer.write_all(&frame_data).await?;
             }
             Event::ServerInput(chat_data) => match chat_data {
                 ServerNetworkData {
                     timestamp,
-                    chat_data: ServerChatData::OtherClientChatMessage(chat_message),
+                    chat_data: ServerChatData::OtherClientChatMessage(sender, chat_message),
                 } => {
-                    // make this a helper function so we don't forget to update selected_ind
-                    chat_history.push(ChatMessageInfo::new_with_timestamp(
-                        chat_message,
-                        false,
-                        timestamp,
-                    ));
-                    // // update selected_ind
-                    // if let Some(selected_ind) = chat_history_selected_ind {
-                    //     // keep selected_ind at bottom if user was already at bottom of chat history
-                    //     if selected_ind == chat_history.len() - 2 {
-                    //         chat_history_selected_ind = Some(chat_history.len() - 1);
-                    //     }
-                    // } else {
-                    //     // can put in helper function to share with failsafe, init_selected_ind
-                    //     chat_history_selected_ind = Some(0);
-                    // }
+                    chat_history.push(
+                        ChatMessageInfo::new_with_timestamp(sender, chat_message, false, timestamp)
+                            .in_room(&current_room),
+                    );
                 }
                 ServerNetworkData {
                     timestamp,
@@ -671,42 +827,264 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     // remove the placeholder/pending chat message
                     chat_history.retain(|chat_msg_info| chat_msg_info.uid != uid);
                     // add up-to-update chat message to chat_history
-                    chat_history.push(ChatMessageInfo::new_with_all(
-                        chat_message,
-                        false,
-                        uid,
+                    chat_history.push(
+                        ChatMessageInfo::new_with_all(
+                            me.clone(),
+                            chat_message,
+                            false,
+                            uid,
+                            timestamp,
+                        )
+                        .in_room(&current_room),
+                    );
+                }
+                ServerNetworkData {
+                    timestamp: _,
+                    // the connection hands these over through remote_video and remote_audio instead
+                    chat_data: ServerChatData::VideoFrame(..) | ServerChatData::AudioFrame(..),
+                } => {}
+                ServerNetworkData {
+                    timestamp: _,
+                    chat_data: ServerChatData::Welcome(user),
+                } => {
+                    chat_history.push(ChatMessageInfo::new_system(format!(
+                        "Connected as {}",
+                        user.name
+                    )));
+                    me = user;
+                }
+                ServerNetworkData {
+                    timestamp: _,
+                    chat_data: ServerChatData::JoinedRoom(room, members),
+                } => {
+                    chat_history.push(ChatMessageInfo::new_system(format!("Joined room {}", room)));
+                    if joining.as_ref().is_some_and(|(joined, _)| *joined == room) {
+                        room_to_rejoin = joining.take().filter(|(room, _)| room != DEFAULT_ROOM);
+                    }
+                    current_room = room;
+                    // its latest messages follow straight after
+                    backlog.reset();
+                    video_grid.reset(members);
+                    remote_audio.clear();
+                    // the server forgets our subscriptions whenever we change rooms
+                    video_subscriptions.clear();
+                    update_video_subscriptions(&video_grid, &mut video_subscriptions, &outgoing);
+                }
+                ServerNetworkData {
+                    timestamp: _,
+                    chat_data: ServerChatData::History(room, messages, more),
+                } => {
+                    if room == current_room {
+                        backlog.received(messages.first().map(|stored| stored.id), more);
+                    }
+                    for stored in messages {
+                        // anything said while we were here already arrived at the time
+                        let seen = chat_history.iter().any(|chat_msg| {
+                            chat_msg.room.as_deref() == Some(room.as_str())
+                                && chat_msg.timestamp == stored.timestamp
+                                && chat_msg.message == stored.message
+                        });
+                        if !seen {
+                            let chat_msg_info = if stored.action {
+                                ChatMessageInfo::new_action(
+                                    stored.sender,
+                                    stored.message,
+                                    stored.timestamp,
+                                )
+                            } else {
+                                ChatMessageInfo::new_with_timestamp(
+                                    stored.sender,
+                                    stored.message,
+                                    false,
+                                    stored.timestamp,
+                                )
+                            };
+                            chat_history.push(chat_msg_info.in_room(&room));
+                        }
+                    }
+                }
+                ServerNetworkData {
+                    timestamp,
+                    chat_data: ServerChatData::Emote(sender, action),
+                } => {
+                    chat_history.push(
+                        ChatMessageInfo::new_action(sender, action, timestamp)
+                            .in_room(&current_room),
+                    );
+                }
+                ServerNetworkData {
+                    timestamp,
+                    chat_data: ServerChatData::DirectMessage(sender, message),
+                } => {
+                    chat_history.push(ChatMessageInfo::new_private(
+                        sender,
+                        me.clone(),
+                        message,
+                        timestamp,
+                    ));
+                }
+                ServerNetworkData {
+                    timestamp,
+                    chat_data: ServerChatData::DirectMessageSent(recipient, message),
+                } => {
+                    chat_history.push(ChatMessageInfo::new_private(
+                        me.clone(),
+                        recipient,
+                        message,
                         timestamp,
                     ));
                 }
                 ServerNetworkData {
                     timestamp: _,
-                    chat_data: ServerChatData::VideoFrame(data, width, height),
+                    chat_data: ServerChatData::UserRenamed(user, old_name),
                 } => {
-                    // println!("got frame with {} {}", width, height);
-                    // RgbImage::from
-                    // println!("{} {}", width * height, data.len());
-                    let c = Cursor::new(data.clone());
-                    let r = Reader::new(c);
-                    let img = r.with_guessed_format().unwrap();
-                    // eprintln!("{:?}", img.format().unwrap());
-                    let img = img.decode().unwrap();
-                    // let temp = JpegDecoder::new(r);
-                    video_frames.insert(
-                        1,
-                        img.to_rgba8(), // ImageBuffer::from_raw(width, height, data)
-                                        //     .expect("given frame buffer is not big enough"),
-                    );
-                    if video_frames.len() > 2 {
-                        video_frames.remove(2);
+                    let message = if user.id == me.id {
+                        renamed = Some(user.name.clone());
+                        me = user.clone();
+                        format!("You're now called {}", user.name)
+                    } else {
+                        video_grid.rename(&user);
+                        format!("{} is now called {}", old_name, user.name)
+                    };
+                    // asking for the same name again after reconnecting changes nothing
+                    if old_name != user.name {
+                        chat_history.push(ChatMessageInfo::new_system(message));
+                    }
+                }
+                ServerNetworkData {
+                    timestamp: _,
+                    chat_data: ServerChatData::CommandRejected(reason),
+                } => {
+                    chat_history.push(ChatMessageInfo::new_system(reason.to_string()));
+                }
+                ServerNetworkData {
+                    timestamp: _,
+                    chat_data: ServerChatData::UserJoined(user, state),
+                } => {
+                    chat_history.push(ChatMessageInfo::new_system(format!("{} joined", user.name)));
+                    video_grid.add(user, state);
+                    update_video_subscriptions(&video_grid, &mut video_subscriptions, &outgoing);
+                }
+                ServerNetworkData {
+                    timestamp: _,
+                    chat_data: ServerChatData::MediaState(user, state),
+                } => {
+                    video_grid.set_media(user.id, state);
+                }
+                ServerNetworkData {
+                    timestamp: _,
+                    chat_data: ServerChatData::UserLeft(user),
+                } => {
+                    chat_history.push(ChatMessageInfo::new_system(format!("{} left", user.name)));
+                    video_grid.remove(user.id);
+                    remote_audio.remove(user.id);
+                    update_video_subscriptions(&video_grid, &mut video_subscriptions, &outgoing);
+                }
+                ServerNetworkData {
+                    timestamp: _,
+                    chat_data: ServerChatData::JoinRoomRejected(room, reason),
+                } => {
+                    // don't keep asking for a room we can't get into
+                    if room_to_rejoin
+                        .as_ref()
+                        .is_some_and(|(rejoin, _)| *rejoin == room)
+                    {
+                        room_to_rejoin = None;
                     }
+                    if joining.as_ref().is_some_and(|(joined, _)| *joined == room) {
+                        joining = None;
+                    }
+                    chat_history.push(ChatMessageInfo::new_system(format!(
+                        "Couldn't join room {}: {}",
+                        room, reason
+                    )));
                 }
             },
+            Event::Connection(status) => {
+                match status {
+                    ConnectionStatus::Connected => {
+                        current_room = String::from(DEFAULT_ROOM);
+                        // a new connection starts out sending nothing as far as the server knows
+                        send_media_state(media, &outgoing);
+                        // and introduces us by the name we started with
+                        if let Some(name) = &renamed {
+                            let _ = outgoing.send(ClientNetworkData {
+                                chat_data: ClientChatData::SetNickname(name.clone()),
+                            });
+                        }
+                        if let Some((room, passcode)) = &room_to_rejoin {
+                            let _ = outgoing.send(ClientNetworkData {
+                                chat_data: ClientChatData::JoinRoom(room.clone(), passcode.clone()),
+                            });
+                        }
+                        // anything still pending may never have reached the server, but only
+                        // what was typed in the room we're going back to belongs there
+                        let rejoining = room_to_rejoin
+                            .as_ref()
+                            .map_or(DEFAULT_ROOM, |(room, _)| room.as_str());
+                        let mut unsent_rooms: Vec<String> = Vec::new();
+                        chat_history.retain(|chat_msg| {
+                            let elsewhere = chat_msg.is_pending
+                                && chat_msg
+                                    .room
+                                    .as_deref()
+                                    .is_some_and(|room| room != rejoining);
+                            if let Some(room) = chat_msg.room.as_ref().filter(|_| elsewhere) {
+                                if !unsent_rooms.contains(room) {
+                                    unsent_rooms.push(room.clone());
+                                }
+                            }
+                            !elsewhere
+                        });
+                        if !unsent_rooms.is_empty() {
+                            chat_history.push(ChatMessageInfo::new_system(format!(
+                                "Dropped messages still pending in {}, we aren't going back there",
+                                unsent_rooms.join(", ")
+                            )));
+                        }
+                        // the server echoes these back with the same uid so it replaces the
+                        // pending copy
+                        for chat_msg in chat_history.iter().filter(|chat_msg| chat_msg.is_pending) {
+                            let _ = outgoing.send(ClientNetworkData {
+                                chat_data: ClientChatData::ChatMessage(
+                                    chat_msg.message.clone(),
+                                    chat_msg.uid,
+                                ),
+                            });
+                        }
+                    }
+                    ConnectionStatus::Connecting
+                        if connection_status.0 == ConnectionStatus::Connected =>
+                    {
+                        chat_history.push(ChatMessageInfo::new_system(String::from(
+                            "Lost connection to server",
+                        )));
+                    }
+                    _ => {}
+                }
+                connection_status = (status, Instant::now());
+            }
+            Event::Resize => {
+                if let Some(graphics) = &mut graphics {
+                    graphics.set_cell_size(graphics::cell_size());
+                    graphics.clear(terminal.backend_mut())?;
+                    terminal.clear()?;
+                }
+            }
             Event::Tick => {}
         }
 
         // break;
         // thread::sleep(Duration::from_micros(15000));
     }
+    if let Some(graphics) = &mut graphics {
+        graphics.clear(terminal.backend_mut())?;
+    }
+    let mut stdout = io::stdout();
+    execute!(stdout, LeaveAlternateScreen, DisableMouseCapture)?;
+    disable_raw_mode()?;
+    terminal.show_cursor()?;
+    // once meeting rooms are setup, figure out how we want to quit threads etc.
     // loop{}
     // println!("byeeeeeeeeeeeee");
 
@@ -724,7 +1102,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     // user_input_handler.join().expect("The user input thread has panicked");
     // server_input_handler.await.expect("The server input thread has panicked");
-    return Ok(());
+    Ok(())
 }
 
 pub fn open<P>(path: P) -> RgbImage
@@ -735,30 +1113,51 @@ where
     img.to_rgb8()
 }
 
-// fn chat_history_move_by(
-//     line_offset: i64,
-//     lines: &Vec<Vec<Cow<str>>>,
-//     message_ind: usize,
-//     line_ind: usize,
-//     area_height: usize,
-// ) -> (usize, usize) {
-//     let mut lines_passed = 0;
-//     if line_offset > 0 {
-//         let lines_after = lines
-//             .get(message_ind..)
-//             .unwrap()
-//             .iter()
-//             .flatten()
-//             .collect::<Vec<_>>()
-//             .len();
-//         for (ind, lines) in lines.get(message_ind..).unwrap().iter().enumerate() {
-//             lines_passed += lines.len();
-//             if lines_passed >= line_offset {
-//                 return (ind, (lines.len() - 1) - (lines_passed - line_offset));
-//             } else if lines_after - lines_passed < area_height {
-//                 return (ind, (lines_after - lines_passed));
-//             }
-//         }
-//     } else if line_offset < 0 {
-//     }
-// }
+/// Ask the server for video from everyone on the grid's current page, if that isn't what we're
+/// already getting.
+fn update_video_subscriptions(
+    video_grid: &VideoGrid,
+    video_subscriptions: &mut Vec<UserId>,
+    outgoing: &UnboundedSender<ClientNetworkData>,
+) {
+    let wanted: Vec<UserId> = video_grid
+        .visible()
+        .iter()
+        .map(|participant| participant.id)
+        .collect();
+    if wanted != *video_subscriptions {
+        let _ = outgoing.send(ClientNetworkData {
+            chat_data: ClientChatData::SubscribeVideo(wanted.clone()),
+        });
+        *video_subscriptions = wanted;
+    }
+}
+
+/// Let the server, and through it our room, know what we're sending now.
+fn send_media_state(media: MediaState, outgoing: &UnboundedSender<ClientNetworkData>) {
+    let _ = outgoing.send(ClientNetworkData {
+        chat_data: ClientChatData::MediaState(media),
+    });
+}
+
+/// Mute the microphone or unmute it, returning what to tell the user.
+fn toggle_microphone(
+    media: &mut MediaState,
+    has_microphone: bool,
+    unmuted: &AtomicBool,
+    video_grid: &mut VideoGrid,
+    outgoing: &UnboundedSender<ClientNetworkData>,
+) -> &'static str {
+    if !has_microphone {
+        return "No microphone to unmute";
+    }
+    media.microphone = !media.microphone;
+    unmuted.store(media.microphone, Ordering::Relaxed);
+    video_grid.set_own_media(*media);
+    send_media_state(*media, outgoing);
+    if media.microphone {
+        "Microphone on, Alt-M mutes it"
+    } else {
+        "Microphone muted, Alt-M unmutes it"
+    }
+}
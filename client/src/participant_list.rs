@@ -0,0 +1,87 @@
+use protocol::{MediaState, UserId, UserInfo};
+use tui::{
+    backend::Backend,
+    layout::Rect,
+    style::{Color, Modifier, Style},
+    widgets::{Block, List, ListItem, ListState},
+    Frame,
+};
+
+use crate::video_grid::VideoGrid;
+
+/// Everyone in the room, us first, with a selection that can be moved to pick someone out.
+#[derive(Default)]
+pub struct ParticipantList {
+    selected: usize,
+}
+
+impl ParticipantList {
+    pub fn select_prev(&mut self) {
+        self.selected = self.selected.saturating_sub(1);
+    }
+
+    /// Move down one, `render` stops it running off the end of the list.
+    pub fn select_next(&mut self) {
+        self.selected += 1;
+    }
+
+    /// Whoever is selected, `None` if that's us.
+    pub fn selected(&self, video_grid: &VideoGrid) -> Option<UserId> {
+        let index = self.selected.checked_sub(1)?;
+        video_grid
+            .participants()
+            .nth(index)
+            .map(|(participant, _)| participant.id)
+    }
+
+    /// Draw the list inside `block`, only highlighting the selection while we have focus.
+    pub fn render<B: Backend>(
+        &mut self,
+        f: &mut Frame<B>,
+        area: Rect,
+        block: Block,
+        focused: bool,
+        me: &UserInfo,
+        video_grid: &VideoGrid,
+    ) {
+        let items: Vec<ListItem> = std::iter::once(ListItem::new(describe(
+            &format!("{} (you)", me.name),
+            video_grid.own_media(),
+        )))
+        .chain(
+            video_grid
+                .participants()
+                .map(|(participant, media)| ListItem::new(describe(&participant.name, media))),
+        )
+        .collect();
+        // someone below the selection may have left
+        self.selected = std::cmp::min(self.selected, items.len() - 1);
+
+        let mut state = ListState::default();
+        if focused {
+            state.select(Some(self.selected));
+        }
+        let list = List::new(items)
+            .block(block)
+            .style(Style::default().fg(Color::White))
+            .highlight_style(
+                Style::default()
+                    .bg(Color::Yellow)
+                    .fg(Color::Black)
+                    .add_modifier(Modifier::BOLD),
+            );
+        f.render_stateful_widget(list, area, &mut state);
+    }
+}
+
+/// A name followed by whatever they've turned off.
+fn describe(name: &str, media: MediaState) -> String {
+    let mut line = String::from(name);
+    if !media.camera {
+        line.push_str(" [cam off]");
+    }
+    if !media.microphone {
+        line.push_str(" [muted]");
+    }
+    line
+}
@@ -0,0 +1,590 @@
+use image::RgbaImage;
+use protocol::{MediaState, UserId, UserInfo};
+use std::collections::HashMap;
+use tui::{
+    backend::Backend,
+    layout::{Alignment, Constraint, Direction, Layout, Rect},
+    style::{Modifier, Style},
+    text::{Span, Spans},
+    widgets::{Block, Borders, Clear, Paragraph},
+    Frame,
+};
+use tui_image::{ColorMode, Dither, Image, Palette};
+
+use crate::config::{Dithering, SelfView, TextMode};
+
+/// Most panes shown at once, anyone past this is on a later page.
+pub const PANES_PER_PAGE: usize = 9;
+
+/// Columns and rows needed for `panes` panes, as square as possible but never taller than wide.
+pub fn grid_size(panes: usize) -> (usize, usize) {
+    let mut cols = 1;
+    while cols * cols < panes {
+        cols += 1;
+    }
+    let rows = std::cmp::max(1, panes.div_ceil(cols));
+    (cols, rows)
+}
+
+/// Split `area` into `panes` equally sized cells, filled a row at a time from the top left.
+pub fn grid_rects(area: Rect, panes: usize) -> Vec<Rect> {
+    let (cols, rows) = grid_size(panes);
+    Layout::default()
+        .direction(Direction::Vertical)
+        .constraints(vec![Constraint::Ratio(1, rows as u32); rows])
+        .split(area)
+        .into_iter()
+        .flat_map(|row| {
+            Layout::default()
+                .direction(Direction::Horizontal)
+                .constraints(vec![Constraint::Ratio(1, cols as u32); cols])
+                .split(row)
+        })
+        .take(panes)
+        .collect()
+}
+
+/// Everyone in the room and their latest video, laid out a page at a time.
+pub struct VideoGrid {
+    /// Everyone else, in the order they arrived, which is also the order they're shown in.
+    participants: Vec<UserInfo>,
+    frames: HashMap<UserId, RgbaImage>,
+    media: HashMap<UserId, MediaState>,
+    page: usize,
+    /// Someone shown on their own in place of the page.
+    spotlight: Option<UserId>,
+    self_view: SelfView,
+    /// Our own camera, already mirrored.
+    own_frame: Option<RgbaImage>,
+    own_media: MediaState,
+    /// Frames are drawn over the panes with a graphics protocol, so the panes are left empty.
+    graphics: bool,
+    text_mode: TextMode,
+    palette: Palette,
+    dither: Dither,
+}
+
+impl VideoGrid {
+    pub fn new(self_view: SelfView) -> Self {
+        VideoGrid {
+            participants: Vec::new(),
+            frames: HashMap::new(),
+            media: HashMap::new(),
+            page: 0,
+            spotlight: None,
+            self_view,
+            own_frame: None,
+            own_media: MediaState::default(),
+            graphics: false,
+            text_mode: TextMode::HalfBlocks,
+            palette: Palette::TrueColor,
+            dither: Dither::None,
+        }
+    }
+
+    /// Leave the video panes empty for `pictures` to be drawn over them once the frame is out.
+    pub fn use_graphics(&mut self) {
+        self.graphics = true;
+    }
+
+    pub fn text_mode(&self) -> TextMode {
+        self.text_mode
+    }
+
+    pub fn set_text_mode(&mut self, text_mode: TextMode) {
+        self.text_mode = text_mode;
+    }
+
+    /// Draw video with text in only the colours in `palette`, dithered as `dithering` says.
+    pub fn set_palette(&mut self, palette: Palette, dithering: Dithering) {
+        self.palette = palette;
+        self.dither = match dithering {
+            Dithering::None => Dither::None,
+            Dithering::Ordered => Dither::Ordered,
+            Dithering::FloydSteinberg => Dither::FloydSteinberg,
+        };
+    }
+
+    /// Start over with the people already in a room we've just joined.
+    pub fn reset(&mut self, participants: Vec<(UserInfo, MediaState)>) {
+        self.media = participants
+            .iter()
+            .map(|(participant, media)| (participant.id, *media))
+            .collect();
+        self.participants = participants
+            .into_iter()
+            .map(|(participant, _)| participant)
+            .collect();
+        self.frames.clear();
+        self.page = 0;
+        self.spotlight = None;
+    }
+
+    pub fn add(&mut self, user: UserInfo, media: MediaState) {
+        self.media.insert(user.id, media);
+        self.participants.push(user);
+    }
+
+    pub fn remove(&mut self, user_id: UserId) {
+        self.participants
+            .retain(|participant| participant.id != user_id);
+        self.frames.remove(&user_id);
+        self.media.remove(&user_id);
+        if self.spotlight == Some(user_id) {
+            self.spotlight = None;
+        }
+        self.page = std::cmp::min(self.page, self.page_count() - 1);
+    }
+
+    /// Show `frame` as the latest from `user_id`, ignored if they're not in the room or their
+    /// camera is off, since frames queued before it went off can still turn up after.
+    pub fn set_frame(&mut self, user_id: UserId, frame: RgbaImage) {
+        if self.media.get(&user_id).is_some_and(|media| media.camera) {
+            self.frames.insert(user_id, frame);
+        }
+    }
+
+    /// Note what `user_id` is sending now, their last frame goes once their camera is off.
+    pub fn set_media(&mut self, user_id: UserId, media: MediaState) {
+        if let Some(state) = self.media.get_mut(&user_id) {
+            *state = media;
+            if !media.camera {
+                self.frames.remove(&user_id);
+            }
+        }
+    }
+
+    /// Pick up someone's new nickname.
+    pub fn rename(&mut self, user: &UserInfo) {
+        if let Some(participant) = self
+            .participants
+            .iter_mut()
+            .find(|participant| participant.id == user.id)
+        {
+            participant.name = user.name.clone();
+        }
+    }
+
+    /// Note what we're sending now, going back to our initials once the camera is off.
+    pub fn set_own_media(&mut self, media: MediaState) {
+        self.own_media = media;
+        if !media.camera {
+            self.own_frame = None;
+        }
+    }
+
+    /// Show `frame` from our own camera, flipped so it moves the way a mirror would.
+    pub fn set_own_frame(&mut self, frame: &RgbaImage) {
+        self.own_frame = Some(image::imageops::flip_horizontal(frame));
+    }
+
+    /// How many other participants fit on a page, the self-view pane takes up one spot.
+    fn per_page(&self) -> usize {
+        match self.self_view {
+            SelfView::Pane => PANES_PER_PAGE - 1,
+            SelfView::Pip => PANES_PER_PAGE,
+        }
+    }
+
+    pub fn page(&self) -> usize {
+        self.page
+    }
+
+    pub fn page_count(&self) -> usize {
+        std::cmp::max(1, self.participants.len().div_ceil(self.per_page()))
+    }
+
+    /// Turning the page also leaves the spotlight, back to the page it was on.
+    pub fn next_page(&mut self) {
+        if self.spotlight.take().is_none() {
+            self.page = std::cmp::min(self.page + 1, self.page_count() - 1);
+        }
+    }
+
+    pub fn prev_page(&mut self) {
+        if self.spotlight.take().is_none() {
+            self.page = self.page.saturating_sub(1);
+        }
+    }
+
+    pub fn spotlight(&self) -> Option<&UserInfo> {
+        let user_id = self.spotlight?;
+        self.participants
+            .iter()
+            .find(|participant| participant.id == user_id)
+    }
+
+    /// Show just `user_id`, filling the whole grid, or go back to the page if they already are.
+    pub fn toggle_spotlight(&mut self, user_id: UserId) {
+        if self.spotlight == Some(user_id) {
+            self.spotlight = None;
+        } else if self
+            .participants
+            .iter()
+            .any(|participant| participant.id == user_id)
+        {
+            self.spotlight = Some(user_id);
+            self.show(user_id);
+        }
+    }
+
+    /// Everyone else in the room and what they're sending, in the order they're shown.
+    pub fn participants(&self) -> impl Iterator<Item = (&UserInfo, MediaState)> {
+        self.participants.iter().map(|participant| {
+            let media = self.media.get(&participant.id).copied();
+            (participant, media.unwrap_or_default())
+        })
+    }
+
+    pub fn own_media(&self) -> MediaState {
+        self.own_media
+    }
+
+    /// Turn to the page `user_id` is on, if they're still here.
+    pub fn show(&mut self, user_id: UserId) {
+        if let Some(index) = self
+            .participants
+            .iter()
+            .position(|participant| participant.id == user_id)
+        {
+            self.page = index / self.per_page();
+        }
+    }
+
+    /// The participants on the current page, or just the one in the spotlight.
+    pub fn visible(&self) -> &[UserInfo] {
+        if let Some(user) = self.spotlight() {
+            return std::slice::from_ref(user);
+        }
+        let start = std::cmp::min(self.page * self.per_page(), self.participants.len());
+        let end = std::cmp::min(start + self.per_page(), self.participants.len());
+        &self.participants[start..end]
+    }
+
+    /// Draw the current page into `area` along with our own camera, returning where each pane
+    /// went so clicks can be matched up with them. Our own pane is the one without an id.
+    pub fn render<B: Backend>(
+        &self,
+        f: &mut Frame<B>,
+        area: Rect,
+        me: &UserInfo,
+    ) -> Vec<(Option<UserId>, Rect)> {
+        let own_pane = Pane {
+            name: &me.name,
+            you: true,
+            media: self.own_media,
+            // the camera can be on without having sent anything yet
+            frame: self.own_frame.as_ref().filter(|_| !self.graphics),
+            text_mode: self.text_mode,
+            palette: self.palette,
+            dither: self.dither,
+        };
+        let mut panes: Vec<(Option<UserId>, Pane)> = self
+            .visible()
+            .iter()
+            .map(|participant| {
+                let pane = Pane {
+                    name: &participant.name,
+                    you: false,
+                    media: self.media.get(&participant.id).copied().unwrap_or_default(),
+                    frame: self.frames.get(&participant.id).filter(|_| !self.graphics),
+                    text_mode: self.text_mode,
+                    palette: self.palette,
+                    dither: self.dither,
+                };
+                (Some(participant.id), pane)
+            })
+            .collect();
+        // picture-in-picture would just cover the corner of an empty grid, and a spotlight is
+        // meant to fill the whole thing
+        let pip =
+            (self.self_view == SelfView::Pip || self.spotlight.is_some()) && !panes.is_empty();
+        if !pip {
+            panes.insert(0, (None, own_pane));
+        }
+
+        let mut areas = Vec::with_capacity(panes.len() + 1);
+        for ((user_id, pane), area) in panes.iter().zip(grid_rects(area, panes.len())) {
+            pane.render(f, area);
+            areas.push((*user_id, area));
+        }
+
+        if pip {
+            let width = std::cmp::min(area.width, std::cmp::max(area.width / 4, 16));
+            let height = std::cmp::min(area.height, std::cmp::max(area.height / 4, 6));
+            let corner = Rect::new(area.right() - width, area.bottom() - height, width, height);
+            f.render_widget(Clear, corner);
+            own_pane.render(f, corner);
+            // on top, so it has to come first
+            areas.insert(0, (None, corner));
+        }
+        areas
+    }
+
+    /// Where inside each of the panes `render` returned to draw its latest frame, for those
+    /// with one, bottom first so the picture-in-picture goes on top.
+    pub fn pictures(&self, areas: &[(Option<UserId>, Rect)]) -> Vec<(Rect, &RgbaImage)> {
+        areas
+            .iter()
+            .rev()
+            .filter_map(|(user_id, area)| {
+                let frame = match user_id {
+                    Some(user_id) if self.media.get(user_id).is_some_and(|media| media.camera) => {
+                        self.frames.get(user_id)
+                    }
+                    Some(_) => None,
+                    None if self.own_media.camera => self.own_frame.as_ref(),
+                    None => None,
+                }?;
+                Some((Block::default().borders(Borders::ALL).inner(*area), frame))
+            })
+            .collect()
+    }
+}
+
+/// One participant's spot in the grid.
+#[derive(Clone, Copy)]
+struct Pane<'a> {
+    name: &'a str,
+    you: bool,
+    media: MediaState,
+    frame: Option<&'a RgbaImage>,
+    text_mode: TextMode,
+    palette: Palette,
+    dither: Dither,
+}
+
+impl Pane<'_> {
+    fn label(&self) -> String {
+        let mut label = String::from(self.name);
+        if self.you {
+            label.push_str(" (you)");
+        }
+        if !self.media.microphone {
+            label.push_str(" (muted)");
+        }
+        label
+    }
+
+    /// A bordered pane showing the latest frame, their initials if their camera is off, or just
+    /// the border if the camera is on but nothing has arrived yet.
+    fn render<B: Backend>(&self, f: &mut Frame<B>, area: Rect) {
+        let block = Block::default().title(self.label()).borders(Borders::ALL);
+        if !self.media.camera {
+            let inner = block.inner(area);
+            let text = vec![
+                Spans::from(Span::styled(
+                    initials(self.name),
+                    Style::default().add_modifier(Modifier::BOLD),
+                )),
+                Spans::from(Span::styled(
+                    "camera off",
+                    Style::default().add_modifier(Modifier::DIM),
+                )),
+            ];
+            let top = inner.height.saturating_sub(text.len() as u16) / 2;
+            let centred = Rect::new(inner.x, inner.y + top, inner.width, inner.height - top);
+            f.render_widget(block, area);
+            f.render_widget(Paragraph::new(text).alignment(Alignment::Center), centred);
+        } else if let Some(frame) = self.frame {
+            f.render_widget(
+                Image::with_img(frame.to_owned())
+                    .block(block)
+                    .color_mode(color_mode(self.text_mode))
+                    .palette(self.palette)
+                    .dither(self.dither),
+                area,
+            );
+        } else {
+            f.render_widget(block, area);
+        }
+    }
+}
+
+fn color_mode(text_mode: TextMode) -> ColorMode {
+    match text_mode {
+        TextMode::HalfBlocks => ColorMode::Rgb,
+        TextMode::Quadrants => ColorMode::Quadrant,
+        TextMode::Sextants => ColorMode::Sextant,
+        TextMode::Braille => ColorMode::Braille,
+        TextMode::Shades => ColorMode::Luma,
+        TextMode::Ascii => ColorMode::Ascii,
+    }
+}
+
+/// Up to two capital letters standing in for someone's face, from the first two words of `name`.
+pub fn initials(name: &str) -> String {
+    let initials: String = name
+        .split(|c: char| !c.is_alphanumeric())
+        .filter_map(|word| word.chars().next())
+        .take(2)
+        .flat_map(char::to_uppercase)
+        .collect();
+    if initials.is_empty() {
+        String::from("?")
+    } else {
+        initials
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(id: u64) -> UserInfo {
+        UserInfo {
+            id,
+            name: format!("user{}", id),
+        }
+    }
+
+    fn member(id: u64) -> (UserInfo, MediaState) {
+        (user(id), MediaState::default())
+    }
+
+    #[test]
+    fn grid_grows_with_participants() {
+        assert_eq!(grid_size(0), (1, 1));
+        assert_eq!(grid_size(1), (1, 1));
+        assert_eq!(grid_size(2), (2, 1));
+        assert_eq!(grid_size(3), (2, 2));
+        assert_eq!(grid_size(4), (2, 2));
+        assert_eq!(grid_size(5), (3, 2));
+        assert_eq!(grid_size(9), (3, 3));
+
+        let rects = grid_rects(Rect::new(0, 0, 90, 30), 5);
+        assert_eq!(rects.len(), 5);
+        assert_eq!(rects[0], Rect::new(0, 0, 30, 15));
+        assert_eq!(rects[3], Rect::new(0, 15, 30, 15));
+    }
+
+    #[test]
+    fn paging_follows_participants() {
+        let mut grid = VideoGrid::new(SelfView::Pip);
+        grid.reset((1..=10).map(member).collect());
+        assert_eq!(grid.page_count(), 2);
+        assert_eq!(grid.visible().len(), PANES_PER_PAGE);
+
+        grid.next_page();
+        grid.next_page();
+        assert_eq!(grid.page(), 1);
+        assert_eq!(grid.visible(), &[user(10)]);
+        grid.show(1);
+        assert_eq!(grid.page(), 0);
+        grid.show(10);
+        assert_eq!(grid.page(), 1);
+
+        // the last page empties out so we go back to the one before
+        grid.remove(10);
+        assert_eq!(grid.page(), 0);
+        assert_eq!(grid.page_count(), 1);
+    }
+
+    #[test]
+    fn self_view_pane_takes_a_spot_on_every_page() {
+        let mut grid = VideoGrid::new(SelfView::Pane);
+        grid.reset((1..=9).map(member).collect());
+        assert_eq!(grid.page_count(), 2);
+        assert_eq!(grid.visible().len(), PANES_PER_PAGE - 1);
+    }
+
+    #[test]
+    fn initials_come_from_the_first_two_words() {
+        assert_eq!(initials("alice"), "A");
+        assert_eq!(initials("bob smith-jones"), "BS");
+        assert_eq!(initials("  émile_zola "), "ÉZ");
+        assert_eq!(initials("!!"), "?");
+    }
+
+    #[test]
+    fn turning_a_camera_off_drops_its_last_frame() {
+        let mut grid = VideoGrid::new(SelfView::Pane);
+        let camera_on = MediaState {
+            camera: true,
+            microphone: true,
+        };
+        grid.reset(vec![(user(1), camera_on)]);
+        grid.set_frame(1, RgbaImage::new(2, 2));
+        grid.set_media(1, camera_on);
+        assert!(grid.frames.contains_key(&1));
+
+        grid.set_media(1, MediaState::default());
+        assert!(!grid.frames.contains_key(&1));
+    }
+
+    #[test]
+    fn frames_arriving_after_the_camera_goes_off_are_ignored() {
+        let mut grid = VideoGrid::new(SelfView::Pane);
+        grid.reset(vec![member(1)]);
+        grid.set_media(1, MediaState::default());
+        grid.set_frame(1, RgbaImage::new(2, 2));
+        assert!(!grid.frames.contains_key(&1));
+        // nor from anyone who isn't here
+        grid.set_frame(2, RgbaImage::new(2, 2));
+        assert!(grid.frames.is_empty());
+    }
+
+    #[test]
+    fn text_modes_cycle_through_every_color_mode() {
+        let mut text_mode = TextMode::HalfBlocks;
+        let mut color_modes = Vec::new();
+        for _ in 0..6 {
+            color_modes.push(color_mode(text_mode));
+            text_mode = text_mode.next();
+        }
+        assert_eq!(text_mode, TextMode::HalfBlocks);
+        color_modes.dedup();
+        assert_eq!(color_modes.len(), 6);
+    }
+
+    #[test]
+    fn pictures_go_inside_panes_with_the_picture_in_picture_last() {
+        let mut grid = VideoGrid::new(SelfView::Pip);
+        let camera_on = MediaState {
+            camera: true,
+            microphone: true,
+        };
+        grid.reset(vec![(user(1), camera_on), (user(2), camera_on)]);
+        grid.set_frame(1, RgbaImage::new(2, 2));
+        grid.set_own_media(camera_on);
+        grid.set_own_frame(&RgbaImage::new(2, 2));
+        let areas = [
+            (None, Rect::new(30, 20, 10, 5)),
+            (Some(1), Rect::new(0, 0, 20, 25)),
+            (Some(2), Rect::new(20, 0, 20, 25)),
+        ];
+        let pictures: Vec<Rect> = grid
+            .pictures(&areas)
+            .into_iter()
+            .map(|(area, _)| area)
+            .collect();
+        // user 2 hasn't sent anything yet
+        assert_eq!(pictures, [Rect::new(1, 1, 18, 23), Rect::new(31, 21, 8, 3)]);
+
+        // a frame left over from before the camera went off isn't drawn over the label
+        grid.media.get_mut(&1).unwrap().camera = false;
+        assert_eq!(grid.pictures(&areas).len(), 1);
+    }
+
+    #[test]
+    fn spotlight_shows_one_participant_until_toggled_off() {
+        let mut grid = VideoGrid::new(SelfView::Pane);
+        grid.reset((1..=10).map(member).collect());
+        grid.toggle_spotlight(9);
+        assert_eq!(grid.visible(), &[user(9)]);
+        assert_eq!(grid.page(), 1);
+
+        // leaving the spotlight goes back to the page they're on
+        grid.next_page();
+        assert_eq!(grid.page(), 1);
+        assert_eq!(grid.visible().len(), 2);
+
+        grid.toggle_spotlight(3);
+        grid.toggle_spotlight(3);
+        assert_eq!(grid.visible().len(), PANES_PER_PAGE - 1);
+
+        grid.toggle_spotlight(4);
+        grid.remove(4);
+        assert!(grid.spotlight().is_none());
+    }
+}
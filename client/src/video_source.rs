@@ -0,0 +1,422 @@
+use image::codecs::gif::GifDecoder;
+use image::codecs::jpeg::JpegEncoder;
+use image::{AnimationDecoder, ColorType, DynamicImage, ImageError, RgbImage};
+use rscam::{Camera, Config as RscamConfig};
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// A JPEG ready to go out as a `VideoFrame`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EncodedFrame {
+    pub data: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+}
+
+#[derive(Debug, Error)]
+pub enum VideoSourceError {
+    #[error("could not open camera {0}: {1}")]
+    Camera(String, rscam::Error),
+    #[error("could not capture from camera: {0}")]
+    Capture(io::Error),
+    #[error("could not read {0}: {1}")]
+    File(PathBuf, ImageError),
+    #[error("could not read {0}: {1}")]
+    FileIo(PathBuf, io::Error),
+    #[error("{0} has no frames in it")]
+    Empty(PathBuf),
+    #[error("could not encode frame: {0}")]
+    Encode(ImageError),
+}
+
+/// Somewhere to get our own video from.
+pub trait VideoSource: Send {
+    /// Wait for the next frame, sources pace themselves to their own frame rate.
+    fn next_frame(&mut self) -> Result<EncodedFrame, VideoSourceError>;
+}
+
+/// Open the source described by `spec`: `camera` for the V4L2 `camera_device`, `test-pattern`,
+/// `none`, or the path of an image, GIF or `.mjpeg` file to loop.
+///
+/// Generated and decoded frames are fitted within `resolution`, MJPEG frames are sent as they are.
+/// `resolution` and `fps` can't be zero, `Config` sees to that.
+pub fn open(
+    spec: &str,
+    camera_device: &str,
+    resolution: (u32, u32),
+    fps: u32,
+) -> Result<Option<Box<dyn VideoSource>>, VideoSourceError> {
+    let frame_interval = Duration::from_secs(1) / fps;
+    let source: Box<dyn VideoSource> = match spec {
+        "none" => return Ok(None),
+        "camera" => Box::new(V4l2Source::open(camera_device, resolution, fps)?),
+        "test-pattern" => Box::new(TestPatternSource::new(resolution, frame_interval)),
+        path => Box::new(FileSource::open(
+            Path::new(path),
+            resolution,
+            frame_interval,
+        )?),
+    };
+    Ok(Some(source))
+}
+
+/// A webcam, which hands us MJPEG frames at whatever rate it was configured for.
+pub struct V4l2Source {
+    camera: Camera,
+}
+
+impl V4l2Source {
+    pub fn open(device: &str, resolution: (u32, u32), fps: u32) -> Result<Self, VideoSourceError> {
+        let camera_error = |e: rscam::Error| VideoSourceError::Camera(String::from(device), e);
+        let mut camera = Camera::new(device).map_err(|e| camera_error(e.into()))?;
+        camera
+            .start(&RscamConfig {
+                interval: (1, fps),
+                resolution,
+                format: b"MJPG",
+                ..Default::default()
+            })
+            .map_err(camera_error)?;
+        Ok(V4l2Source { camera })
+    }
+}
+
+impl VideoSource for V4l2Source {
+    fn next_frame(&mut self) -> Result<EncodedFrame, VideoSourceError> {
+        let frame = self.camera.capture().map_err(VideoSourceError::Capture)?;
+        Ok(EncodedFrame {
+            data: frame[..].to_vec(),
+            width: frame.resolution.0,
+            height: frame.resolution.1,
+        })
+    }
+}
+
+/// Frames from a file, played over and over.
+pub struct FileSource {
+    /// Each frame and how long it stays up.
+    frames: Vec<(EncodedFrame, Duration)>,
+    index: usize,
+    pacer: Pacer,
+}
+
+impl FileSource {
+    /// Load every frame up front so playing them back costs nothing. Still images become a single
+    /// frame repeated every `frame_interval`, as do MJPEG frames since the format has no timing.
+    pub fn open(
+        path: &Path,
+        resolution: (u32, u32),
+        frame_interval: Duration,
+    ) -> Result<Self, VideoSourceError> {
+        let file_error = |e| VideoSourceError::File(path.to_path_buf(), e);
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_ascii_lowercase());
+        let frames = match extension.as_deref() {
+            Some("mjpeg") | Some("mjpg") => {
+                let data = std::fs::read(path)
+                    .map_err(|e| VideoSourceError::FileIo(path.to_path_buf(), e))?;
+                split_mjpeg(&data)
+                    .map(|jpeg| {
+                        let img = image::load_from_memory(jpeg).map_err(file_error)?;
+                        let frame = EncodedFrame {
+                            data: jpeg.to_vec(),
+                            width: img.width(),
+                            height: img.height(),
+                        };
+                        Ok((frame, frame_interval))
+                    })
+                    .collect::<Result<_, _>>()?
+            }
+            Some("gif") => {
+                let file = File::open(path)
+                    .map_err(|e| VideoSourceError::FileIo(path.to_path_buf(), e))?;
+                let decoder = GifDecoder::new(BufReader::new(file)).map_err(file_error)?;
+                decoder
+                    .into_frames()
+                    .map(|frame| {
+                        let frame = frame.map_err(file_error)?;
+                        // plenty of GIFs leave the delay out and expect a sensible default
+                        let delay = match Duration::from(frame.delay()) {
+                            delay if delay.is_zero() => frame_interval,
+                            delay => delay,
+                        };
+                        let img = DynamicImage::ImageRgba8(frame.into_buffer());
+                        Ok((encode_jpeg(&img, resolution)?, delay))
+                    })
+                    .collect::<Result<_, _>>()?
+            }
+            _ => {
+                let img = image::open(path).map_err(file_error)?;
+                vec![(encode_jpeg(&img, resolution)?, frame_interval)]
+            }
+        };
+        if frames.is_empty() {
+            return Err(VideoSourceError::Empty(path.to_path_buf()));
+        }
+        Ok(FileSource {
+            frames,
+            index: 0,
+            pacer: Pacer::new(),
+        })
+    }
+}
+
+impl VideoSource for FileSource {
+    fn next_frame(&mut self) -> Result<EncodedFrame, VideoSourceError> {
+        let (frame, delay) = &self.frames[self.index];
+        self.index = (self.index + 1) % self.frames.len();
+        self.pacer.wait(*delay);
+        Ok(frame.clone())
+    }
+}
+
+/// Colour bars with a band sweeping down them, so it's obvious when video is moving.
+pub struct TestPatternSource {
+    resolution: (u32, u32),
+    frame_interval: Duration,
+    frame_count: u32,
+    pacer: Pacer,
+}
+
+const TEST_PATTERN_BARS: [[u8; 3]; 7] = [
+    [192, 192, 192],
+    [192, 192, 0],
+    [0, 192, 192],
+    [0, 192, 0],
+    [192, 0, 192],
+    [192, 0, 0],
+    [0, 0, 192],
+];
+
+impl TestPatternSource {
+    pub fn new(resolution: (u32, u32), frame_interval: Duration) -> Self {
+        TestPatternSource {
+            resolution,
+            frame_interval,
+            frame_count: 0,
+            pacer: Pacer::new(),
+        }
+    }
+
+    fn render(&self) -> RgbImage {
+        let (width, height) = self.resolution;
+        let band_height = std::cmp::max(1, height / 8);
+        let band_top = self.frame_count * 2 % height;
+        RgbImage::from_fn(width, height, |x, y| {
+            if (band_top..band_top + band_height).contains(&y) {
+                image::Rgb([255, 255, 255])
+            } else {
+                let bar = (x * TEST_PATTERN_BARS.len() as u32 / width) as usize;
+                image::Rgb(TEST_PATTERN_BARS[bar])
+            }
+        })
+    }
+}
+
+impl VideoSource for TestPatternSource {
+    fn next_frame(&mut self) -> Result<EncodedFrame, VideoSourceError> {
+        let img = DynamicImage::ImageRgb8(self.render());
+        self.frame_count = self.frame_count.wrapping_add(1);
+        self.pacer.wait(self.frame_interval);
+        encode_jpeg(&img, self.resolution)
+    }
+}
+
+/// Sleeps just long enough to keep frames evenly spaced, however long making them took.
+struct Pacer {
+    next: Option<Instant>,
+}
+
+impl Pacer {
+    fn new() -> Self {
+        Pacer { next: None }
+    }
+
+    /// Wait until `interval` after the last frame, the first frame goes out straight away.
+    fn wait(&mut self, interval: Duration) {
+        let now = Instant::now();
+        let next = match self.next {
+            // we fell behind, don't try to catch up with a burst of frames
+            Some(next) if next > now => {
+                thread::sleep(next - now);
+                next
+            }
+            _ => now,
+        };
+        self.next = Some(next + interval);
+    }
+}
+
+/// Shrink `img` to fit within `resolution` if needed and encode it as a JPEG.
+fn encode_jpeg(
+    img: &DynamicImage,
+    resolution: (u32, u32),
+) -> Result<EncodedFrame, VideoSourceError> {
+    let (width, height) = resolution;
+    let img = if img.width() > width || img.height() > height {
+        img.resize(width, height, image::imageops::FilterType::Triangle)
+    } else {
+        img.clone()
+    };
+    let rgb = img.to_rgb8();
+    let mut data = Vec::new();
+    JpegEncoder::new_with_quality(&mut data, 80)
+        .encode(rgb.as_raw(), rgb.width(), rgb.height(), ColorType::Rgb8)
+        .map_err(VideoSourceError::Encode)?;
+    Ok(EncodedFrame {
+        data,
+        width: rgb.width(),
+        height: rgb.height(),
+    })
+}
+
+/// The JPEGs in a Motion JPEG stream, which is just one JPEG after another. Anything between
+/// them, or a JPEG that's cut short or garbled, is skipped.
+fn split_mjpeg(data: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut rest = data;
+    std::iter::from_fn(move || loop {
+        let start = rest.windows(2).position(|marker| marker == [0xff, 0xd8])?;
+        match jpeg_len(&rest[start..]) {
+            Some(len) => {
+                let jpeg = &rest[start..start + len];
+                rest = &rest[start + len..];
+                return Some(jpeg);
+            }
+            None => rest = &rest[start + 2..],
+        }
+    })
+}
+
+/// How long the JPEG at the start of `data` is, found by walking its segments rather than
+/// looking for the first end marker, which could belong to a thumbnail in its EXIF data.
+fn jpeg_len(data: &[u8]) -> Option<usize> {
+    if !data.starts_with(&[0xff, 0xd8]) {
+        return None;
+    }
+    let mut i = 2;
+    loop {
+        if *data.get(i)? != 0xff {
+            return None;
+        }
+        // markers can be padded with any number of extra 0xff
+        while data.get(i + 1) == Some(&0xff) {
+            i += 1;
+        }
+        let marker = *data.get(i + 1)?;
+        i += 2;
+        match marker {
+            // end of image
+            0xd9 => return Some(i),
+            // restarts and TEM stand alone
+            0xd0..=0xd7 | 0x01 => {}
+            _ => {
+                let len = u16::from_be_bytes([*data.get(i)?, *data.get(i + 1)?]) as usize;
+                if len < 2 {
+                    return None;
+                }
+                i += len;
+                if marker == 0xda {
+                    // the compressed scan runs up to the next marker, 0xff in it is followed by
+                    // a 0 and restarts can be mixed in
+                    loop {
+                        match (*data.get(i)?, *data.get(i + 1)?) {
+                            (0xff, 0x00) | (0xff, 0xd0..=0xd7) => i += 2,
+                            (0xff, _) => break,
+                            _ => i += 1,
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pattern_fits_the_resolution() {
+        let mut source = TestPatternSource::new((64, 48), Duration::ZERO);
+        let frame = source.next_frame().unwrap();
+        assert_eq!((frame.width, frame.height), (64, 48));
+        let img = image::load_from_memory(&frame.data).unwrap();
+        assert_eq!((img.width(), img.height()), (64, 48));
+        assert_ne!(source.next_frame().unwrap(), frame);
+    }
+
+    #[test]
+    fn mjpeg_files_loop_frame_by_frame() {
+        let colours = [[255, 0, 0], [0, 0, 255]];
+        let mut mjpeg = Vec::new();
+        for colour in colours {
+            let img = DynamicImage::ImageRgb8(RgbImage::from_pixel(16, 8, image::Rgb(colour)));
+            mjpeg.extend(encode_jpeg(&img, (16, 8)).unwrap().data);
+        }
+        let path =
+            std::env::temp_dir().join(format!("tvc-video-test-{}.mjpeg", std::process::id()));
+        std::fs::write(&path, &mjpeg).unwrap();
+        let mut source = FileSource::open(&path, (16, 8), Duration::ZERO).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let red = |frame: EncodedFrame| {
+            image::load_from_memory(&frame.data)
+                .unwrap()
+                .to_rgb8()
+                .get_pixel(8, 4)[0]
+        };
+        assert!(red(source.next_frame().unwrap()) > 200);
+        assert!(red(source.next_frame().unwrap()) < 50);
+        assert!(red(source.next_frame().unwrap()) > 200);
+    }
+
+    #[test]
+    fn mjpeg_frames_with_exif_thumbnails_stay_whole() {
+        let jpeg = |colour, size: (u32, u32)| {
+            let img =
+                DynamicImage::ImageRgb8(RgbImage::from_pixel(size.0, size.1, image::Rgb(colour)));
+            encode_jpeg(&img, size).unwrap().data
+        };
+        // a camera style frame, with a thumbnail of itself in an APP1 segment straight after
+        // the start marker
+        let with_thumbnail = |colour| {
+            let thumbnail = jpeg(colour, (4, 4));
+            let mut app1 = b"Exif\0\0".to_vec();
+            app1.extend(&thumbnail);
+            let mut frame = vec![0xff, 0xd8, 0xff, 0xe1];
+            frame.extend(((app1.len() + 2) as u16).to_be_bytes());
+            frame.extend(app1);
+            frame.extend(&jpeg(colour, (16, 8))[2..]);
+            frame
+        };
+        let frames = [with_thumbnail([255, 0, 0]), with_thumbnail([0, 0, 255])];
+        let mjpeg = frames.concat();
+
+        let split: Vec<&[u8]> = split_mjpeg(&mjpeg).collect();
+        assert_eq!(split, [&frames[0][..], &frames[1][..]]);
+        for jpeg in split {
+            let img = image::load_from_memory(jpeg).unwrap();
+            assert_eq!((img.width(), img.height()), (16, 8));
+        }
+
+        // whatever isn't a whole JPEG is passed over
+        let mut damaged = frames[0][..40].to_vec();
+        damaged.extend(b"junk");
+        damaged.extend(&frames[1]);
+        assert_eq!(split_mjpeg(&damaged).collect::<Vec<_>>(), [&frames[1][..]]);
+    }
+
+    #[test]
+    fn missing_files_are_reported() {
+        assert!(matches!(
+            open("/nonexistent/video.gif", "/dev/video0", (16, 8), 15),
+            Err(VideoSourceError::FileIo(..))
+        ));
+    }
+}
@@ -1,21 +1,91 @@
 use failure::Error;
-use image::{imageops::resize, imageops::FilterType, RgbaImage};
-use std::cmp::{max, min};
+use image::{imageops::crop_imm, imageops::resize, Rgba, RgbaImage};
+use std::borrow::Cow;
+use std::cmp::min;
 use tui::buffer::Buffer;
 use tui::layout::{Alignment, Rect};
 use tui::style::{Color, Style};
 use tui::widgets::{Block, Widget};
 
+mod palette;
+
+pub use image::imageops::FilterType;
+pub use palette::{Dither, Palette};
+
+/// How an image is scaled to the widget's area.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scale {
+	/// As large as fits without stretching, leaving a border along two sides if the aspect ratios
+	/// differ.
+	Fit,
+	/// Covers the whole area without stretching, cropping the sides that don't fit.
+	Fill,
+	/// Covers the whole area exactly, stretching the image if the aspect ratios differ.
+	Stretch,
+	/// Pixel for pixel, cropping whatever doesn't fit.
+	None,
+}
+
+/// Terminal cells are about twice as high as they are wide.
+const CELL_ASPECT: f64 = 2.0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ColorMode {
+	/// Shade blocks by brightness, two pixels to a cell.
 	Luma,
+	/// Upper-half blocks in full color, two pixels to a cell.
 	Rgb,
+	/// Braille dots in the main color of each cell, 2x4 pixels to a cell.
+	Braille,
+	/// Quadrant blocks in two colors, 2x2 pixels to a cell.
+	Quadrant,
+	/// Sextant blocks in two colors, 2x3 pixels to a cell. They need a font with the Symbols for
+	/// Legacy Computing block.
+	Sextant,
+	/// ASCII characters by brightness, two pixels to a cell.
+	Ascii,
 }
 
+impl ColorMode {
+	/// The pixels each terminal cell shows in this mode, across then down.
+	pub fn cell_pixels(self) -> (u32, u32) {
+		match self {
+			ColorMode::Luma | ColorMode::Rgb | ColorMode::Ascii => (1, 2),
+			ColorMode::Braille => (2, 4),
+			ColorMode::Quadrant => (2, 2),
+			ColorMode::Sextant => (2, 3),
+		}
+	}
+}
+
+type ImgFn = Box<dyn Fn(usize, usize) -> Result<RgbaImage, Error>>;
+
 const BLOCK_LIGHT: char = '\u{2591}';
 const BLOCK_MEDIUM: char = '\u{2592}';
 const BLOCK_DARK: char = '\u{2593}';
 const BLOCK_FULL: char = '\u{2588}';
 const BLOCK_UPPER_HALF: char = '\u{2580}';
+const BLOCK_LEFT_HALF: char = '\u{258c}';
+const BLOCK_RIGHT_HALF: char = '\u{2590}';
+
+/// Braille with no dots raised, the rest follow on from it one bit per dot.
+const BRAILLE_BLANK: u32 = 0x2800;
+/// Braille dot bits, indexed by row then column.
+const BRAILLE_DOTS: [[u32; 2]; 4] = [[0x01, 0x08], [0x02, 0x10], [0x04, 0x20], [0x40, 0x80]];
+
+/// Quadrant blocks indexed by which quarters are filled, top left, top right, bottom left then
+/// bottom right from the lowest bit.
+const QUADRANTS: [char; 16] = [
+	' ', '\u{2598}', '\u{259d}', '\u{2580}', '\u{2596}', '\u{258c}', '\u{259e}', '\u{259b}',
+	'\u{2597}', '\u{259a}', '\u{2590}', '\u{259c}', '\u{2584}', '\u{2599}', '\u{259f}', '\u{2588}',
+];
+
+/// The first sextant block, the rest follow in order of which sixths are filled, skipping those
+/// that already had a block character.
+const SEXTANT_FIRST: u32 = 0x1fb00;
+
+/// Darkest to lightest.
+const ASCII_RAMP: &[u8] = b" .:-=+*#%@";
 
 /// A tui-rs Widget which displays an image.
 pub struct Image<'a> {
@@ -26,11 +96,19 @@ pub struct Image<'a> {
 	/// Image to display
 	img: Option<RgbaImage>,
 	/// Function returning image to display
-	img_fn: Option<Box<dyn Fn(usize, usize) -> Result<RgbaImage, Error>>>,
+	img_fn: Option<ImgFn>,
 	/// Color mode
 	color_mode: ColorMode,
 	/// Alignment of the image
 	alignment: Alignment,
+	/// Colors the terminal can show
+	palette: Palette,
+	/// How to make up for colors the palette doesn't have
+	dither: Dither,
+	/// How the image is scaled to the area
+	scale: Scale,
+	/// Filter used when scaling
+	filter: FilterType,
 }
 
 impl<'a> Image<'a> {
@@ -43,6 +121,10 @@ impl<'a> Image<'a> {
 			img_fn: None,
 			color_mode: ColorMode::Luma,
 			alignment: Alignment::Center,
+			palette: Palette::TrueColor,
+			dither: Dither::None,
+			scale: Scale::Fit,
+			filter: FilterType::Triangle,
 		}
 	}
 
@@ -57,6 +139,10 @@ impl<'a> Image<'a> {
 			img_fn: Some(Box::new(img_fn)),
 			color_mode: ColorMode::Luma,
 			alignment: Alignment::Center,
+			palette: Palette::TrueColor,
+			dither: Dither::None,
+			scale: Scale::Fit,
+			filter: FilterType::Triangle,
 		}
 	}
 
@@ -84,43 +170,141 @@ impl<'a> Image<'a> {
 		self
 	}
 
-	fn draw_img(&self, area: Rect, buf: &mut Buffer, img: &RgbaImage) {
-		// TODO: add other fixed colours
-		let bg_rgb = match self.style.bg {
-			Some(Color::Black) => vec![0f32, 0f32, 0f32],
-			Some(Color::White) => vec![1f32, 1f32, 1f32],
-			Some(Color::Rgb(r, g, b)) => {
-				vec![r as f32 / 255f32, g as f32 / 255f32, b as f32 / 255f32]
+	/// Set the colors the terminal can show, every color drawn is the nearest one from it.
+	pub fn palette(mut self, palette: Palette) -> Image<'a> {
+		self.palette = palette;
+		self
+	}
+
+	/// Set how pixels are dithered when the palette isn't true color.
+	pub fn dither(mut self, dither: Dither) -> Image<'a> {
+		self.dither = dither;
+		self
+	}
+
+	/// Set how the image is scaled to the area, `Scale::Fit` by default.
+	pub fn scale(mut self, scale: Scale) -> Image<'a> {
+		self.scale = scale;
+		self
+	}
+
+	/// Set the filter used to scale the image, `FilterType::Triangle` by default.
+	pub fn filter(mut self, filter: FilterType) -> Image<'a> {
+		self.filter = filter;
+		self
+	}
+
+	/// Scale `img` for `area` as `self.scale` says, cropping it to the pixels the area's cells
+	/// can show.
+	fn scale_img<'i>(&self, img: &'i RgbaImage, area: Rect) -> Cow<'i, RgbaImage> {
+		let (cell_width, cell_height) = self.color_mode.cell_pixels();
+		let width = cell_width * area.width as u32;
+		let height = cell_height * area.height as u32;
+		if img.width() == 0 || img.height() == 0 {
+			return Cow::Borrowed(img);
+		}
+
+		let (scaled_width, scaled_height) = match self.scale {
+			Scale::None => img.dimensions(),
+			Scale::Stretch => (width, height),
+			Scale::Fit | Scale::Fill => {
+				// compare sizes in cell widths, so pixels that aren't square in some modes come
+				// out the right shape
+				let x_scale = area.width as f64 / img.width() as f64;
+				let y_scale = area.height as f64 * CELL_ASPECT / img.height() as f64;
+				let scale = if self.scale == Scale::Fit {
+					x_scale.min(y_scale)
+				} else {
+					x_scale.max(y_scale)
+				};
+				let scaled = |pixels: u32, per_cell: f64| {
+					(pixels as f64 * scale * per_cell).round().max(1.0) as u32
+				};
+				(
+					scaled(img.width(), cell_width as f64),
+					scaled(img.height(), cell_height as f64 / CELL_ASPECT),
+				)
 			}
-			_ => vec![0f32, 0f32, 0f32],
+		};
+		let img = if img.dimensions() == (scaled_width, scaled_height) {
+			Cow::Borrowed(img)
+		} else {
+			Cow::Owned(resize(img, scaled_width, scaled_height, self.filter))
 		};
 
-		// calc offset
-
-		let ox = max(
-			0,
-			min(
-				area.width as i32 - 1,
-				match self.alignment {
-					Alignment::Center => (area.width as i32 - img.width() as i32) / 2i32,
-					Alignment::Left => 0i32,
-					Alignment::Right => area.width as i32 - img.width() as i32,
-				},
-			),
-		) as u16;
-		let oy = max(
-			0,
-			min(
-				(2 * area.height) - 1,
-				((2 * area.height) - img.height() as u16) / 2,
-			),
-		) as u16;
+		if img.width() <= width && img.height() <= height {
+			return img;
+		}
+		let crop_width = min(width, img.width());
+		let crop_height = min(height, img.height());
+		let x = match self.alignment {
+			Alignment::Center => (img.width() - crop_width) / 2,
+			Alignment::Left => 0,
+			Alignment::Right => img.width() - crop_width,
+		};
+		let y = (img.height() - crop_height) / 2;
+		Cow::Owned(crop_imm(img.as_ref(), x, y, crop_width, crop_height).to_image())
+	}
+
+	/// The background color pixels are composited against, each channel from 0 to 1.
+	fn background(&self) -> [f32; 3] {
+		// TODO: add other fixed colours
+		match self.style.bg {
+			Some(Color::Black) => [0f32, 0f32, 0f32],
+			Some(Color::White) => [1f32, 1f32, 1f32],
+			Some(Color::Rgb(r, g, b)) => [r as f32 / 255f32, g as f32 / 255f32, b as f32 / 255f32],
+			_ => [0f32, 0f32, 0f32],
+		}
+	}
+
+	fn draw_img(&self, area: Rect, buf: &mut Buffer, img: &RgbaImage) {
+		let dithered;
+		let colored = !matches!(self.color_mode, ColorMode::Luma | ColorMode::Ascii);
+		let img = if colored && self.palette != Palette::TrueColor && self.dither != Dither::None {
+			let bg_rgb = self.background();
+			let composited = RgbaImage::from_fn(img.width(), img.height(), |x, y| {
+				let rgb = composite(img.get_pixel(x, y).0, bg_rgb);
+				let channel = |c: f32| (255.0 * c).round() as u8;
+				Rgba([channel(rgb[0]), channel(rgb[1]), channel(rgb[2]), 255])
+			});
+			dithered = palette::dither(&composited, self.palette, self.dither);
+			&dithered
+		} else {
+			img
+		};
+
+		match self.color_mode {
+			ColorMode::Luma | ColorMode::Rgb => self.draw_halves(area, buf, img),
+			_ => self.draw_cells(area, buf, img),
+		}
+	}
+
+	/// The cells `img`, already scaled for `area`, is drawn in. It's aligned across as
+	/// `self.alignment` says and centred down by whole cells, so each cell's pixels all come
+	/// from one block of the image.
+	fn placement(&self, area: Rect, img: &RgbaImage) -> Rect {
+		let (cell_width, cell_height) = self.color_mode.cell_pixels();
+		let cols = min(area.width as u32, img.width().div_ceil(cell_width)) as u16;
+		let rows = min(area.height as u32, img.height().div_ceil(cell_height)) as u16;
+		let x = match self.alignment {
+			Alignment::Center => (area.width - cols) / 2,
+			Alignment::Left => 0,
+			Alignment::Right => area.width - cols,
+		};
+		Rect::new(area.x + x, area.y + (area.height - rows) / 2, cols, rows)
+	}
+
+	/// Draw the modes that show two pixels in each cell, a pixel at a time.
+	fn draw_halves(&self, area: Rect, buf: &mut Buffer, img: &RgbaImage) {
+		let bg_rgb = self.background();
+
+		let placed = self.placement(area, img);
 
 		// draw
 
-		for y in oy..min(oy + img.height() as u16, (2 * area.height) - 1) {
-			for x in ox..min(ox + img.width() as u16, area.width - 1) {
-				let p = img.get_pixel((x - ox) as u32, (y - oy) as u32);
+		for y in 0..min(img.height(), 2 * placed.height as u32) {
+			for x in 0..min(img.width(), placed.width as u32) {
+				let p = img.get_pixel(x, y);
 
 				// composite onto background
 				let a = p[3] as f32 / 255.0;
@@ -128,13 +312,17 @@ impl<'a> Image<'a> {
 				let g = p[1] as f32 * a / 255.0 + bg_rgb[1] * (1f32 - a);
 				let b = p[2] as f32 * a / 255.0 + bg_rgb[2] * (1f32 - a);
 
-				let cell = buf.get_mut(area.left() + x, area.top() + (y / 2));
+				let cell = buf.get_mut(placed.x + x as u16, placed.y + (y / 2) as u16);
 
 				match self.color_mode {
 					ColorMode::Luma => {
 						let luma = r * 0.3 + g * 0.59 + b * 0.11;
 						let luma_u8 = (5.0 * luma) as u8;
 						if luma_u8 == 0 {
+							// the lower pixel only shows if it's brighter
+							if y & 1 == 0 {
+								cell.set_char(' ');
+							}
 							continue;
 						}
 
@@ -147,23 +335,150 @@ impl<'a> Image<'a> {
 					}
 					ColorMode::Rgb => {
 						if y & 1 == 0 {
-							cell.set_char(BLOCK_UPPER_HALF).set_fg(Color::Rgb(
-								(255.0 * r) as u8,
-								(255.0 * g) as u8,
-								(255.0 * b) as u8,
-							));
+							cell.set_char(BLOCK_UPPER_HALF)
+								.set_fg(self.palette.color([r, g, b]));
+							if y + 1 == img.height() {
+								// an odd height leaves the lower half showing the background
+								cell.set_bg(self.palette.color(bg_rgb));
+							}
 						} else {
-							cell.set_bg(Color::Rgb(
-								(255.0 * r) as u8,
-								(255.0 * g) as u8,
-								(255.0 * b) as u8,
-							));
+							cell.set_bg(self.palette.color([r, g, b]));
 						}
 					}
+					_ => unreachable!("only drawn a cell at a time"),
 				}
 			}
 		}
 	}
+
+	/// Draw the modes that show several pixels in each cell, a cell at a time.
+	fn draw_cells(&self, area: Rect, buf: &mut Buffer, img: &RgbaImage) {
+		let bg_rgb = self.background();
+		let (cell_width, cell_height) = self.color_mode.cell_pixels();
+		let placed = self.placement(area, img);
+
+		for cy in 0..placed.height {
+			for cx in 0..placed.width {
+				// the cell's pixels a row at a time, `None` past the edge of the image
+				let pixels: Vec<Option<[f32; 3]>> = (0..cell_height)
+					.flat_map(|dy| (0..cell_width).map(move |dx| (dx, dy)))
+					.map(|(dx, dy)| {
+						let x = cx as u32 * cell_width + dx;
+						let y = cy as u32 * cell_height + dy;
+						if x < img.width() && y < img.height() {
+							Some(composite(img.get_pixel(x, y).0, bg_rgb))
+						} else {
+							None
+						}
+					})
+					.collect();
+				let cell = buf.get_mut(placed.x + cx, placed.y + cy);
+
+				if self.color_mode == ColorMode::Ascii {
+					let luma = mean(pixels.iter().flatten().copied()).map_or(0.0, luma);
+					let index = min(
+						ASCII_RAMP.len() - 1,
+						(luma * ASCII_RAMP.len() as f32) as usize,
+					);
+					cell.set_char(ASCII_RAMP[index] as char);
+					continue;
+				}
+
+				// split the pixels into the brighter ones drawn by the character and the rest
+				let threshold = mean(pixels.iter().flatten().copied()).map_or(0.0, luma);
+				let lit: Vec<bool> = pixels
+					.iter()
+					.map(|pixel| pixel.is_some_and(|pixel| luma(pixel) >= threshold))
+					.collect();
+				let mask = lit
+					.iter()
+					.enumerate()
+					.filter(|(_, lit)| **lit)
+					.fold(0u32, |mask, (i, _)| mask | 1 << i);
+				let fg = mean(
+					pixels
+						.iter()
+						.zip(&lit)
+						.filter(|(_, lit)| **lit)
+						.filter_map(|(pixel, _)| *pixel),
+				);
+				let bg = mean(
+					pixels
+						.iter()
+						.zip(&lit)
+						.filter(|(_, lit)| !**lit)
+						.filter_map(|(pixel, _)| *pixel),
+				);
+
+				match self.color_mode {
+					ColorMode::Braille => {
+						let dots = lit
+							.iter()
+							.enumerate()
+							.filter(|(_, lit)| **lit)
+							.fold(0, |dots, (i, _)| dots | BRAILLE_DOTS[i / 2][i % 2]);
+						cell.set_char(std::char::from_u32(BRAILLE_BLANK + dots).unwrap());
+					}
+					ColorMode::Quadrant => {
+						cell.set_char(QUADRANTS[mask as usize]);
+					}
+					_ => {
+						cell.set_char(sextant(mask));
+					}
+				}
+				if let Some(fg) = fg {
+					cell.set_fg(self.palette.color(fg));
+				}
+				if self.color_mode != ColorMode::Braille {
+					if let Some(bg) = bg.or(fg) {
+						cell.set_bg(self.palette.color(bg));
+					}
+				}
+			}
+		}
+	}
+}
+
+/// Composite an RGBA pixel onto `bg`, each channel of the result from 0 to 1.
+fn composite(p: [u8; 4], bg: [f32; 3]) -> [f32; 3] {
+	let a = p[3] as f32 / 255.0;
+	[
+		p[0] as f32 * a / 255.0 + bg[0] * (1f32 - a),
+		p[1] as f32 * a / 255.0 + bg[1] * (1f32 - a),
+		p[2] as f32 * a / 255.0 + bg[2] * (1f32 - a),
+	]
+}
+
+fn luma(rgb: [f32; 3]) -> f32 {
+	rgb[0] * 0.3 + rgb[1] * 0.59 + rgb[2] * 0.11
+}
+
+/// The average of `pixels`, if there are any.
+fn mean(pixels: impl Iterator<Item = [f32; 3]>) -> Option<[f32; 3]> {
+	let (count, sum) = pixels.fold((0, [0f32; 3]), |(count, sum), p| {
+		(count + 1, [sum[0] + p[0], sum[1] + p[1], sum[2] + p[2]])
+	});
+	if count == 0 {
+		None
+	} else {
+		let count = count as f32;
+		Some([sum[0] / count, sum[1] / count, sum[2] / count])
+	}
+}
+
+/// The sextant block with the sixths in `mask` filled, top left, top right, middle left and so on
+/// from the lowest bit.
+fn sextant(mask: u32) -> char {
+	match mask {
+		0 => ' ',
+		0b010101 => BLOCK_LEFT_HALF,
+		0b101010 => BLOCK_RIGHT_HALF,
+		0b111111 => BLOCK_FULL,
+		_ => {
+			let skipped = (mask > 0b010101) as u32 + (mask > 0b101010) as u32;
+			std::char::from_u32(SEXTANT_FIRST + mask - 1 - skipped).unwrap()
+		}
+	}
 }
 
 impl<'a> Widget for Image<'a> {
@@ -184,20 +499,397 @@ impl<'a> Widget for Image<'a> {
 		buf.set_style(area, self.style);
 
 		if let Some(ref img) = self.img {
-			if img.width() > area.width as u32 || img.height() > 2 * area.height as u32 {
-				let scaled = resize(
-					img,
-					area.width as u32,
-					2 * area.height as u32,
-					FilterType::Nearest,
-				);
-				self.draw_img(area, buf, &scaled)
+			self.draw_img(area, buf, &self.scale_img(img, area));
+		} else if let Some(ref img_fn) = self.img_fn {
+			let (cell_width, cell_height) = self.color_mode.cell_pixels();
+			let width = cell_width * area.width as u32;
+			let height = cell_height * area.height as u32;
+			if let Ok(img) = img_fn(width as usize, height as usize) {
+				self.draw_img(area, buf, &self.scale_img(&img, area));
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use image::Rgba;
+	use proptest::prelude::*;
+	use tui::buffer::Cell;
+
+	const BLACK: Rgba<u8> = Rgba([0, 0, 0, 255]);
+	const WHITE: Rgba<u8> = Rgba([255, 255, 255, 255]);
+
+	/// An image `columns` wide and `columns[0]` high, given a column at a time.
+	fn image(columns: &[&[Rgba<u8>]]) -> RgbaImage {
+		RgbaImage::from_fn(columns.len() as u32, columns[0].len() as u32, |x, y| {
+			columns[x as usize][y as usize]
+		})
+	}
+
+	fn draw(img: RgbaImage, color_mode: ColorMode, width: u16, height: u16) -> Buffer {
+		let area = Rect::new(0, 0, width, height);
+		let mut buf = Buffer::empty(area);
+		Image::with_img(img)
+			.color_mode(color_mode)
+			.scale(Scale::None)
+			.render(area, &mut buf);
+		buf
+	}
+
+	fn rgb(rgba: Rgba<u8>) -> Color {
+		Color::Rgb(rgba[0], rgba[1], rgba[2])
+	}
+
+	#[test]
+	fn braille_raises_the_bright_dots_in_their_color() {
+		let red = Rgba([255, 0, 0, 255]);
+		let img = image(&[
+			&[WHITE; 4],
+			&[BLACK; 4],
+			&[red, red, BLACK, BLACK],
+			&[BLACK; 4],
+		]);
+		let mut expected = Buffer::with_lines(vec!["\u{2847}\u{2803}"]);
+		expected.get_mut(0, 0).set_fg(rgb(WHITE));
+		expected.get_mut(1, 0).set_fg(rgb(red));
+		assert_eq!(draw(img, ColorMode::Braille, 2, 1), expected);
+	}
+
+	#[test]
+	fn quadrants_split_each_cell_into_two_colors() {
+		let red = Rgba([255, 0, 0, 255]);
+		let img = image(&[&[WHITE, BLACK], &[BLACK, BLACK], &[red, red], &[red, red]]);
+		let mut expected = Buffer::with_lines(vec![" \u{2598}\u{2588} "]);
+		expected.get_mut(1, 0).set_fg(rgb(WHITE)).set_bg(rgb(BLACK));
+		expected.get_mut(2, 0).set_fg(rgb(red)).set_bg(rgb(red));
+		assert_eq!(draw(img, ColorMode::Quadrant, 4, 1), expected);
+	}
+
+	#[test]
+	fn sextants_use_half_blocks_where_they_exist() {
+		let img = image(&[
+			&[WHITE, BLACK, BLACK],
+			&[WHITE, BLACK, BLACK],
+			&[WHITE; 3],
+			&[BLACK; 3],
+		]);
+		let mut expected = Buffer::with_lines(vec!["\u{1fb02}\u{258c}"]);
+		expected.get_mut(0, 0).set_fg(rgb(WHITE)).set_bg(rgb(BLACK));
+		expected.get_mut(1, 0).set_fg(rgb(WHITE)).set_bg(rgb(BLACK));
+		assert_eq!(draw(img, ColorMode::Sextant, 2, 1), expected);
+		assert_eq!(sextant(0b000001), '\u{1fb00}');
+		assert_eq!(sextant(0b010110), '\u{1fb14}');
+		assert_eq!(sextant(0b111110), '\u{1fb3b}');
+	}
+
+	#[test]
+	fn ascii_ramps_from_dark_to_light() {
+		let grey = Rgba([128, 128, 128, 255]);
+		let img = image(&[&[BLACK; 2], &[grey; 2], &[WHITE; 2], &[WHITE, BLACK]]);
+		assert_eq!(
+			draw(img, ColorMode::Ascii, 6, 2),
+			Buffer::with_lines(vec!["  +@+ ", "      "])
+		);
+	}
+
+	#[test]
+	fn colors_come_from_the_palette() {
+		// exactly one of the xterm colors, so there's no error to spread
+		let orange = Rgba([255, 135, 0, 255]);
+		let area = Rect::new(0, 0, 1, 1);
+		let mut buf = Buffer::empty(area);
+		Image::with_img(RgbaImage::from_pixel(2, 4, orange))
+			.color_mode(ColorMode::Braille)
+			.palette(Palette::Xterm256)
+			.dither(Dither::FloydSteinberg)
+			.render(area, &mut buf);
+		let mut expected = Buffer::with_lines(vec!["\u{28ff}"]);
+		expected.get_mut(0, 0).set_fg(Color::Indexed(208));
+		assert_eq!(buf, expected);
+
+		let mut buf = Buffer::empty(area);
+		Image::with_img(image(&[&[orange, WHITE], &[orange, WHITE]]))
+			.color_mode(ColorMode::Quadrant)
+			.scale(Scale::None)
+			.palette(Palette::Ansi16)
+			.render(area, &mut buf);
+		let mut expected = Buffer::with_lines(vec![QUADRANTS[0b1100].to_string()]);
+		expected
+			.get_mut(0, 0)
+			.set_fg(Color::White)
+			.set_bg(Color::Yellow);
+		assert_eq!(buf, expected);
+	}
+
+	fn draw_scaled(img: RgbaImage, scale: Scale, width: u16, height: u16) -> Buffer {
+		let area = Rect::new(0, 0, width, height);
+		let mut buf = Buffer::empty(area);
+		Image::with_img(img)
+			.color_mode(ColorMode::Braille)
+			.scale(scale)
+			.filter(FilterType::Nearest)
+			.render(area, &mut buf);
+		buf
+	}
+
+	/// `lines` of braille, each character in `fg`.
+	fn braille(lines: Vec<&str>, fg: Rgba<u8>) -> Buffer {
+		let mut buf = Buffer::with_lines(lines);
+		for cell in &mut buf.content {
+			if cell.symbol != " " {
+				cell.set_fg(rgb(fg));
+			}
+		}
+		buf
+	}
+
+	#[test]
+	fn fit_scales_small_images_up_without_stretching() {
+		// 6 cells across and 2 down is 6:4, so a square image is 4 cells across
+		let square = RgbaImage::from_pixel(2, 2, WHITE);
+		assert_eq!(
+			draw_scaled(square.clone(), Scale::Fit, 6, 2),
+			braille(vec![" \u{28ff}\u{28ff}\u{28ff}\u{28ff} "; 2], WHITE)
+		);
+		assert_eq!(
+			draw_scaled(square, Scale::Stretch, 6, 2),
+			braille(
+				vec!["\u{28ff}\u{28ff}\u{28ff}\u{28ff}\u{28ff}\u{28ff}"; 2],
+				WHITE
+			)
+		);
+
+		let big = RgbaImage::from_pixel(40, 40, WHITE);
+		assert_eq!(
+			draw_scaled(big, Scale::Fit, 3, 2),
+			braille(
+				vec!["\u{28ff}\u{28ff}\u{28ff}", "\u{281b}\u{281b}\u{281b}"],
+				WHITE
+			)
+		);
+	}
+
+	#[test]
+	fn fill_crops_what_fit_would_leave_a_border_around() {
+		let blue = Rgba([0, 0, 255, 255]);
+		let red = Rgba([255, 0, 0, 255]);
+		// 2:1, in an area that's square
+		let img = image(&[&[red; 2], &[red; 2], &[blue; 2], &[blue; 2]]);
+		let mut expected = braille(vec!["\u{28ff}\u{28ff}"], red);
+		expected.get_mut(1, 0).set_fg(rgb(blue));
+		assert_eq!(draw_scaled(img.clone(), Scale::Fill, 2, 1), expected);
+
+		let mut expected = braille(vec!["\u{281b}\u{281b}"], red);
+		expected.get_mut(1, 0).set_fg(rgb(blue));
+		assert_eq!(draw_scaled(img, Scale::Fit, 2, 1), expected);
+	}
+
+	#[test]
+	fn none_leaves_the_size_alone() {
+		assert_eq!(
+			draw_scaled(RgbaImage::from_pixel(1, 1, WHITE), Scale::None, 2, 1),
+			braille(vec!["\u{2801} "], WHITE)
+		);
+		// too big, so cropped around the middle
+		let img = RgbaImage::from_fn(8, 4, |x, _| if x == 3 { WHITE } else { BLACK });
+		let mut expected = braille(vec!["\u{28b8}\u{28ff}"], WHITE);
+		expected.get_mut(1, 0).set_fg(rgb(BLACK));
+		assert_eq!(draw_scaled(img, Scale::None, 2, 1), expected);
+	}
+
+	#[test]
+	fn half_blocks_are_centred_by_whole_cells() {
+		let red = Rgba([255, 0, 0, 255]);
+		let area = Rect::new(0, 0, 2, 2);
+		let mut buf = Buffer::empty(area);
+		Image::with_img(image(&[&[red, WHITE]]))
+			.color_mode(ColorMode::Rgb)
+			.scale(Scale::None)
+			.render(area, &mut buf);
+		let mut expected =
+			Buffer::with_lines(vec![format!("{} ", BLOCK_UPPER_HALF), String::from("  ")]);
+		expected.get_mut(0, 0).set_fg(rgb(red)).set_bg(rgb(WHITE));
+		assert_eq!(buf, expected);
+	}
+
+	#[test]
+	fn half_blocks_cover_the_last_column_and_row() {
+		let red = Rgba([255, 0, 0, 255]);
+		let area = Rect::new(0, 0, 2, 2);
+		let mut buf = Buffer::empty(area);
+		// three rows, so the bottom cell's lower half is the background
+		Image::with_img(RgbaImage::from_pixel(2, 3, red))
+			.color_mode(ColorMode::Rgb)
+			.scale(Scale::None)
+			.style(Style::default().bg(Color::White))
+			.render(area, &mut buf);
+		let mut expected = Buffer::with_lines(vec![BLOCK_UPPER_HALF.to_string().repeat(2); 2]);
+		expected.set_style(area, Style::default().fg(rgb(red)).bg(rgb(red)));
+		expected.set_style(Rect::new(0, 1, 2, 1), Style::default().bg(rgb(WHITE)));
+		assert_eq!(buf, expected);
+	}
+
+	fn any_color_mode() -> impl Strategy<Value = ColorMode> {
+		prop_oneof![
+			Just(ColorMode::Luma),
+			Just(ColorMode::Rgb),
+			Just(ColorMode::Braille),
+			Just(ColorMode::Quadrant),
+			Just(ColorMode::Sextant),
+			Just(ColorMode::Ascii),
+		]
+	}
+
+	fn any_scale() -> impl Strategy<Value = Scale> {
+		prop_oneof![
+			Just(Scale::Fit),
+			Just(Scale::Fill),
+			Just(Scale::Stretch),
+			Just(Scale::None),
+		]
+	}
+
+	fn any_alignment() -> impl Strategy<Value = Alignment> {
+		prop_oneof![
+			Just(Alignment::Left),
+			Just(Alignment::Center),
+			Just(Alignment::Right),
+		]
+	}
+
+	/// Whether `cell` has been drawn on by `color_mode`, starting from a `?` with no colors.
+	fn painted(cell: &Cell, color_mode: ColorMode) -> bool {
+		cell.symbol != "?"
+			&& match color_mode {
+				// a cell with no lit pixels has nothing to color in the foreground
+				ColorMode::Luma | ColorMode::Ascii | ColorMode::Braille => true,
+				ColorMode::Quadrant | ColorMode::Sextant => cell.bg != Color::Reset,
+				ColorMode::Rgb => cell.fg != Color::Reset && cell.bg != Color::Reset,
+			}
+	}
+
+	/// The cells an image of `size` pixels should cover in `area`, worked out in whole numbers
+	/// rather than the way the widget does it. `None` when the scaled size lands exactly half way
+	/// between two pixels, which floating point could round either way.
+	fn expected_footprint(
+		(img_width, img_height): (u32, u32),
+		area: Rect,
+		color_mode: ColorMode,
+		scale: Scale,
+		alignment: Alignment,
+	) -> Option<Rect> {
+		if img_width == 0 || img_height == 0 || area.area() == 0 {
+			return Some(Rect::new(area.x, area.y, 0, 0));
+		}
+		let (cell_width, cell_height) = color_mode.cell_pixels();
+		let (w, h) = (img_width as u64, img_height as u64);
+		let (cols, rows) = (area.width as u64, area.height as u64);
+		let (cw, ch) = (cell_width as u64, cell_height as u64);
+		// numerator over denominator, rounded to the nearest pixel but never to nothing
+		let rounded = |num: u64, den: u64| {
+			if (2 * num) % (2 * den) == den {
+				None
 			} else {
-				self.draw_img(area, buf, img)
+				Some(std::cmp::max(1, (2 * num + den) / (2 * den)))
 			}
-		} else if let Some(ref img_fn) = self.img_fn {
-			if let Ok(img) = img_fn(area.width as usize, 2 * area.height as usize) {
-				self.draw_img(area, buf, &img);
+		};
+		// a cell is twice as tall as it is wide
+		let width_limited = cols * h <= 2 * rows * w;
+		let (scaled_width, scaled_height) = match scale {
+			// whatever Fill has left over runs off the edge
+			Scale::Stretch | Scale::Fill => (cols * cw, rows * ch),
+			Scale::None => (w, h),
+			Scale::Fit if width_limited => (cols * cw, rounded(h * cols * ch, 2 * w)?),
+			Scale::Fit => (rounded(w * 2 * rows * cw, h)?, rows * ch),
+		};
+		let used_cols = std::cmp::min(cols, scaled_width.div_ceil(cw)) as u16;
+		let used_rows = std::cmp::min(rows, scaled_height.div_ceil(ch)) as u16;
+		let left = match alignment {
+			Alignment::Left => 0,
+			Alignment::Center => (area.width - used_cols) / 2,
+			Alignment::Right => area.width - used_cols,
+		};
+		let top = (area.height - used_rows) / 2;
+		Some(Rect::new(area.x + left, area.y + top, used_cols, used_rows))
+	}
+
+	#[test]
+	fn expected_footprints_by_hand() {
+		let area = Rect::new(1, 1, 20, 10);
+		let rgb = ColorMode::Rgb;
+		// 40x20 pixels is 40x10 cells wide, so it's narrowed to 20 by 5 cells
+		assert_eq!(
+			expected_footprint((40, 20), area, rgb, Scale::Fit, Alignment::Center),
+			Some(Rect::new(1, 3, 20, 5))
+		);
+		// and a tall one the other way, right aligned
+		assert_eq!(
+			expected_footprint((10, 40), area, rgb, Scale::Fit, Alignment::Right),
+			Some(Rect::new(16, 1, 5, 10))
+		);
+		assert_eq!(
+			expected_footprint(
+				(3, 5),
+				area,
+				ColorMode::Braille,
+				Scale::None,
+				Alignment::Left
+			),
+			Some(Rect::new(1, 5, 2, 2))
+		);
+		assert_eq!(
+			expected_footprint((3, 5), area, rgb, Scale::Fill, Alignment::Left),
+			Some(area)
+		);
+	}
+
+	proptest! {
+		#[test]
+		fn every_cell_the_image_covers_is_painted(
+			img_width in 0u32..48,
+			img_height in 0u32..48,
+			x in 0u16..3,
+			y in 0u16..3,
+			width in 0u16..24,
+			height in 0u16..16,
+			color_mode in any_color_mode(),
+			scale in any_scale(),
+			alignment in any_alignment(),
+			seed in any::<u32>(),
+		) {
+			let img = RgbaImage::from_fn(img_width, img_height, |px, py| {
+				let noise = (seed ^ (px * 7919 + py * 104_729)).wrapping_mul(2_654_435_761);
+				Rgba(noise.to_le_bytes())
+			});
+			let area = Rect::new(x, y, width, height);
+			let screen = Rect::new(0, 0, x + width + 2, y + height + 2);
+			let mut sentinel = Cell::default();
+			sentinel.set_symbol("?");
+			let mut buf = Buffer::filled(screen, &sentinel);
+			Image::with_img(img.clone())
+				.color_mode(color_mode)
+				.scale(scale)
+				.alignment(alignment)
+				.render(area, &mut buf);
+
+			let footprint = expected_footprint(img.dimensions(), area, color_mode, scale, alignment);
+			prop_assume!(footprint.is_some());
+			let footprint = footprint.unwrap();
+			for cy in 0..screen.height {
+				for cx in 0..screen.width {
+					let cell = buf.get(cx, cy);
+					let inside = cx >= footprint.left()
+						&& cx < footprint.right()
+						&& cy >= footprint.top()
+						&& cy < footprint.bottom();
+					if inside {
+						prop_assert!(painted(cell, color_mode), "{:?} at {}, {}", cell, cx, cy);
+					} else {
+						prop_assert_eq!(cell, &sentinel, "at {}, {}", cx, cy);
+					}
+				}
 			}
 		}
 	}
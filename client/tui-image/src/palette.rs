@@ -0,0 +1,233 @@
+use image::{Rgba, RgbaImage};
+use tui::style::Color;
+
+/// The colors a terminal can show.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Palette {
+	/// Any 24-bit color.
+	TrueColor,
+	/// The 6x6x6 color cube and grey ramp of xterm's 256 colors. The first 16 are left out
+	/// since every terminal theme changes them.
+	Xterm256,
+	/// The 16 standard ANSI colors, as xterm shows them by default.
+	Ansi16,
+}
+
+/// How pixels are spread over the palette's colors when it doesn't have the one they want.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dither {
+	/// Every pixel takes the nearest color.
+	None,
+	/// A fixed 4x4 Bayer pattern, which stays put from one video frame to the next.
+	Ordered,
+	/// Floyd–Steinberg error diffusion, smoother but shimmers when the image moves.
+	FloydSteinberg,
+}
+
+/// Levels of each channel in the xterm color cube.
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+const ANSI_16: [(Color, [u8; 3]); 16] = [
+	(Color::Black, [0, 0, 0]),
+	(Color::Red, [205, 0, 0]),
+	(Color::Green, [0, 205, 0]),
+	(Color::Yellow, [205, 205, 0]),
+	(Color::Blue, [0, 0, 238]),
+	(Color::Magenta, [205, 0, 205]),
+	(Color::Cyan, [0, 205, 205]),
+	(Color::Gray, [229, 229, 229]),
+	(Color::DarkGray, [127, 127, 127]),
+	(Color::LightRed, [255, 0, 0]),
+	(Color::LightGreen, [0, 255, 0]),
+	(Color::LightYellow, [255, 255, 0]),
+	(Color::LightBlue, [92, 92, 255]),
+	(Color::LightMagenta, [255, 0, 255]),
+	(Color::LightCyan, [0, 255, 255]),
+	(Color::White, [255, 255, 255]),
+];
+
+/// Thresholds for ordered dithering, from 0 to 15.
+const BAYER_4X4: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+impl Palette {
+	/// The nearest color to `rgb` (each channel from 0 to 1), and what it looks like.
+	pub fn nearest(self, rgb: [f32; 3]) -> (Color, [u8; 3]) {
+		let rgb = [to_u8(rgb[0]), to_u8(rgb[1]), to_u8(rgb[2])];
+		match self {
+			Palette::TrueColor => (Color::Rgb(rgb[0], rgb[1], rgb[2]), rgb),
+			Palette::Xterm256 => {
+				let levels = [cube_level(rgb[0]), cube_level(rgb[1]), cube_level(rgb[2])];
+				let cube = [
+					CUBE_LEVELS[levels[0]],
+					CUBE_LEVELS[levels[1]],
+					CUBE_LEVELS[levels[2]],
+				];
+				// greys run from 8 to 238 in steps of 10
+				let average = (rgb[0] as u32 + rgb[1] as u32 + rgb[2] as u32) / 3;
+				let grey_index = (average.saturating_sub(3) / 10).min(23) as u8;
+				let grey_level = 8 + 10 * grey_index;
+				let grey = [grey_level; 3];
+				if distance(rgb, grey) < distance(rgb, cube) {
+					(Color::Indexed(232 + grey_index), grey)
+				} else {
+					let index = 16 + 36 * levels[0] + 6 * levels[1] + levels[2];
+					(Color::Indexed(index as u8), cube)
+				}
+			}
+			Palette::Ansi16 => *ANSI_16
+				.iter()
+				.min_by_key(|(_, color)| distance(rgb, *color))
+				.unwrap(),
+		}
+	}
+
+	/// The nearest color to `rgb` (each channel from 0 to 1).
+	pub fn color(self, rgb: [f32; 3]) -> Color {
+		self.nearest(rgb).0
+	}
+
+	/// About how far apart neighbouring colors are, each channel from 0 to 1.
+	fn spacing(self) -> f32 {
+		match self {
+			Palette::TrueColor => 1.0 / 255.0,
+			Palette::Xterm256 => 40.0 / 255.0,
+			Palette::Ansi16 => 0.5,
+		}
+	}
+}
+
+/// Replace every pixel of `img`, already composited so alpha is ignored, with a color from
+/// `palette`, spreading out the difference as `dither` says.
+pub fn dither(img: &RgbaImage, palette: Palette, dither: Dither) -> RgbaImage {
+	let (width, height) = img.dimensions();
+	let mut out = RgbaImage::new(width, height);
+	match dither {
+		Dither::None | Dither::Ordered => {
+			for (x, y, p) in img.enumerate_pixels() {
+				let mut rgb = to_f32(p);
+				if dither == Dither::Ordered {
+					let threshold = BAYER_4X4[(y % 4) as usize][(x % 4) as usize] as f32;
+					let offset = (threshold + 0.5) / 16.0 - 0.5;
+					for channel in &mut rgb {
+						*channel += offset * palette.spacing();
+					}
+				}
+				out.put_pixel(x, y, opaque(palette.nearest(rgb).1));
+			}
+		}
+		Dither::FloydSteinberg => {
+			let mut pixels: Vec<[f32; 3]> = img.pixels().map(to_f32).collect();
+			for y in 0..height {
+				for x in 0..width {
+					let i = (y * width + x) as usize;
+					let wanted = pixels[i];
+					let got = palette.nearest(wanted).1;
+					out.put_pixel(x, y, opaque(got));
+					let error = [
+						wanted[0] - got[0] as f32 / 255.0,
+						wanted[1] - got[1] as f32 / 255.0,
+						wanted[2] - got[2] as f32 / 255.0,
+					];
+					let mut spread = |dx: i32, dy: u32, share: f32| {
+						let nx = x as i32 + dx;
+						if nx < 0 || nx >= width as i32 || y + dy >= height {
+							return;
+						}
+						let pixel = &mut pixels[((y + dy) * width) as usize + nx as usize];
+						for c in 0..3 {
+							pixel[c] += error[c] * share;
+						}
+					};
+					spread(1, 0, 7.0 / 16.0);
+					spread(-1, 1, 3.0 / 16.0);
+					spread(0, 1, 5.0 / 16.0);
+					spread(1, 1, 1.0 / 16.0);
+				}
+			}
+		}
+	}
+	out
+}
+
+fn cube_level(channel: u8) -> usize {
+	CUBE_LEVELS
+		.iter()
+		.enumerate()
+		.min_by_key(|(_, level)| (**level as i32 - channel as i32).abs())
+		.map(|(i, _)| i)
+		.unwrap()
+}
+
+fn distance(a: [u8; 3], b: [u8; 3]) -> u32 {
+	(0..3)
+		.map(|c| {
+			let d = a[c] as i32 - b[c] as i32;
+			(d * d) as u32
+		})
+		.sum()
+}
+
+fn to_u8(channel: f32) -> u8 {
+	(255.0 * channel).round().clamp(0.0, 255.0) as u8
+}
+
+fn to_f32(p: &Rgba<u8>) -> [f32; 3] {
+	[
+		p[0] as f32 / 255.0,
+		p[1] as f32 / 255.0,
+		p[2] as f32 / 255.0,
+	]
+}
+
+fn opaque(rgb: [u8; 3]) -> Rgba<u8> {
+	Rgba([rgb[0], rgb[1], rgb[2], 255])
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn colors_snap_to_the_nearest_in_the_palette() {
+		let orange = [1.0, 0.5, 0.0];
+		assert_eq!(Palette::TrueColor.color(orange), Color::Rgb(255, 128, 0));
+		// 255, 135, 0 in the cube
+		assert_eq!(Palette::Xterm256.color(orange), Color::Indexed(208));
+		assert_eq!(
+			Palette::Xterm256.color([0.5, 0.5, 0.5]),
+			Color::Indexed(244)
+		);
+		assert_eq!(Palette::Xterm256.color([0.0, 0.0, 0.0]), Color::Indexed(16));
+		assert_eq!(Palette::Ansi16.color(orange), Color::Yellow);
+		assert_eq!(Palette::Ansi16.color([0.1, 0.1, 0.1]), Color::Black);
+	}
+
+	#[test]
+	fn dithering_mixes_palette_colors_to_match_the_average() {
+		let grey = RgbaImage::from_pixel(8, 8, Rgba([40, 40, 40, 255]));
+		let mean = |img: &RgbaImage| {
+			img.pixels().map(|p| p[0] as f32).sum::<f32>() / img.pixels().len() as f32
+		};
+
+		// without dithering it's all black
+		let flat = dither(&grey, Palette::Ansi16, Dither::None);
+		assert!(flat.pixels().all(|p| p.0 == [0, 0, 0, 255]));
+
+		for method in [Dither::Ordered, Dither::FloydSteinberg].iter() {
+			let dithered = dither(&grey, Palette::Ansi16, *method);
+			assert!(dithered
+				.pixels()
+				.all(|p| ANSI_16.iter().any(|(_, color)| p.0[..3] == color[..])));
+			assert!((mean(&dithered) - 40.0).abs() < 15.0, "{:?}", method);
+		}
+	}
+
+	#[test]
+	fn ordered_dithering_repeats_every_four_pixels() {
+		let teal = RgbaImage::from_pixel(8, 1, Rgba([100, 150, 200, 255]));
+		let dithered = dither(&teal, Palette::Xterm256, Dither::Ordered);
+		for x in 0..4 {
+			assert_eq!(dithered.get_pixel(x, 0), dithered.get_pixel(x + 4, 0));
+		}
+	}
+}
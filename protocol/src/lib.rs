@@ -0,0 +1,439 @@
+//! Message types shared by the server and client, plus the framing used to send them over TCP.
+//!
+//! Every message on the wire is a frame of the form
+//! ```text
+//! [ # of bytes of body ][ body (serialized via serde) ]
+//! [        u32         ][              x              ]
+//! ```
+//! where the length is big-endian and the body is bincode.
+
+use chrono::serde::ts_milliseconds;
+use chrono::{DateTime, Utc};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::io;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Largest body we are willing to allocate for, guards against garbage length headers.
+pub const MAX_FRAME_LEN: u32 = 16 * 1024 * 1024;
+
+/// Room every client is placed in when it first connects.
+pub const DEFAULT_ROOM: &str = "general";
+/// Longest room name the server accepts, in chars.
+pub const MAX_ROOM_NAME_LEN: usize = 32;
+/// Longest nickname the server accepts, in chars.
+pub const MAX_NICKNAME_LEN: usize = 24;
+/// Longest chat message, action or direct message the server relays, in chars. Well short of
+/// `MAX_FRAME_LEN` so a page of history full of them still fits in a frame.
+pub const MAX_MESSAGE_LEN: usize = 4000;
+
+/// Audio is sent as mono 16 bit PCM at this rate, before being compressed by the client.
+pub const AUDIO_SAMPLE_RATE: u32 = 16_000;
+/// Samples in each `AudioFrame`, 20ms worth.
+pub const AUDIO_FRAME_SAMPLES: usize = 320;
+
+/// Id the server hands out to each connection, never reused while the server is running.
+pub type UserId = u64;
+
+/// Who sent something, attached by the server to everything it relays.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct UserInfo {
+    pub id: UserId,
+    pub name: String,
+}
+
+/// What a user is currently sending, so everyone else can tell a turned off camera from a
+/// broken one.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MediaState {
+    pub camera: bool,
+    pub microphone: bool,
+}
+
+/// A chat message as the server stored it, sent to catch up on what was said before we joined.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct StoredMessage {
+    /// Goes up with every message the server stores, whatever room it's in.
+    pub id: u64,
+    pub sender: UserInfo,
+    pub message: String,
+    /// Sent with `/me`, so `message` is something the sender did rather than said.
+    #[serde(default)]
+    pub action: bool,
+    /// When the server relayed it, the same as the timestamp it went out with at the time.
+    #[serde(with = "ts_milliseconds")]
+    pub timestamp: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ServerNetworkData {
+    #[serde(with = "ts_milliseconds")]
+    pub timestamp: DateTime<Utc>,
+    pub chat_data: ServerChatData,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ServerChatData {
+    Welcome(UserInfo),                        // our identity, as assigned by the server
+    OtherClientChatMessage(UserInfo, String), // sender, message
+    ReturnToSenderChatMessage(String, usize), // message, id
+    VideoFrame(UserInfo, Vec<u8>, u32, u32),  // sender, (stream_data, width, height)
+    JoinedRoom(String, Vec<(UserInfo, MediaState)>), // room name, everyone else already in it
+    JoinRoomRejected(String, JoinRoomError),  // room name, reason
+    UserJoined(UserInfo, MediaState),         // someone arrived in our room
+    UserLeft(UserInfo),                       // someone disconnected or moved to another room
+    AudioFrame(UserInfo, u64, Vec<u8>),       // sender, (sequence number, encoded samples)
+    MediaState(UserInfo, MediaState),         // someone in our room turned something on or off
+    History(String, Vec<StoredMessage>, bool), // room name, messages oldest first, whether there are older ones
+    UserRenamed(UserInfo, String), // someone in our room, or us, with their new name, (old name)
+    Emote(UserInfo, String),       // sender, what they did
+    DirectMessage(UserInfo, String), // sender, message only we were sent
+    DirectMessageSent(UserInfo, String), // recipient, message we sent only them
+    CommandRejected(CommandError), // why the last nickname change or direct message failed
+}
+
+/// Why the server refused a `ClientChatData::JoinRoom`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Error)]
+pub enum JoinRoomError {
+    #[error("wrong passcode")]
+    WrongPasscode,
+    #[error("room names must be 1 to {MAX_ROOM_NAME_LEN} characters")]
+    InvalidName,
+}
+
+/// Why the server refused a nickname change or direct message.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Error)]
+pub enum CommandError {
+    #[error("nicknames need at least one printable character")]
+    InvalidNickname,
+    #[error("nobody called {0} is in this room")]
+    NoSuchUser(String),
+    #[error("more than one person in this room is called {0}")]
+    AmbiguousUser(String),
+    #[error("{0} is too far behind to be sent anything, try again in a moment")]
+    Undelivered(String),
+    #[error("messages can be at most {MAX_MESSAGE_LEN} characters")]
+    MessageTooLong,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ClientNetworkData {
+    pub chat_data: ClientChatData,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ClientChatData {
+    Hello(String),                    // nickname, must be the first thing a client sends
+    ChatMessage(String, usize),       // message, id
+    VideoFrame(Vec<u8>, u32, u32),    // (stream_data, width, height)
+    JoinRoom(String, Option<String>), // room name, passcode
+    SubscribeVideo(Vec<UserId>),      // users whose video we're displaying, replaces the last list
+    AudioFrame(u64, Vec<u8>),         // sequence number, encoded samples
+    MediaState(MediaState),           // what we're sending now, passed on to our room
+    RequestHistory(u64),              // stored messages in our room from before this id
+    SetNickname(String),              // what to be called from now on
+    Emote(String),                    // something we did, passed on to our room
+    DirectMessage(String, String),    // nickname of someone in our room, message only they see
+}
+
+#[derive(Debug, Error)]
+pub enum FrameError {
+    /// The peer closed the connection, either cleanly or part way through a frame.
+    #[error("connection closed")]
+    Disconnected,
+    /// The length header is larger than `MAX_FRAME_LEN`, the stream can't be trusted after this.
+    #[error("frame body of {0} bytes is larger than the {MAX_FRAME_LEN} byte limit")]
+    TooLarge(u32),
+    /// The whole frame was read but its body didn't deserialize, the stream is still in sync.
+    #[error("could not decode frame body: {0}")]
+    Malformed(#[from] bincode::Error),
+    #[error("io error: {0}")]
+    Io(io::Error),
+}
+
+impl From<io::Error> for FrameError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe => FrameError::Disconnected,
+            _ => FrameError::Io(e),
+        }
+    }
+}
+
+/// Serialize `data` and prefix it with its length, ready to be written to a socket.
+pub fn encode_frame<T: Serialize>(data: &T) -> Result<Vec<u8>, FrameError> {
+    let body = bincode::serialize(data)?;
+    let len = u32::try_from(body.len()).unwrap_or(u32::MAX);
+    if len > MAX_FRAME_LEN {
+        return Err(FrameError::TooLarge(len));
+    }
+    Ok([&len.to_be_bytes(), &body[..]].concat())
+}
+
+/// Deserialize a frame body (without its length header).
+pub fn decode_body<T: DeserializeOwned>(body: &[u8]) -> Result<T, FrameError> {
+    Ok(bincode::deserialize(body)?)
+}
+
+/// Read one frame from `reader` and deserialize its body.
+pub async fn read_frame<T, R>(reader: &mut R) -> Result<T, FrameError>
+where
+    T: DeserializeOwned,
+    R: AsyncRead + Unpin,
+{
+    let len = reader.read_u32().await?;
+    if len > MAX_FRAME_LEN {
+        return Err(FrameError::TooLarge(len));
+    }
+    let mut body = vec![0u8; len as usize];
+    reader.read_exact(&mut body).await?;
+    decode_body(&body)
+}
+
+/// Encode `data` as a frame and write all of it to `writer`.
+pub async fn write_frame<T, W>(writer: &mut W, data: &T) -> Result<(), FrameError>
+where
+    T: Serialize,
+    W: AsyncWrite + Unpin,
+{
+    let frame = encode_frame(data)?;
+    writer.write_all(&frame).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn server_data(chat_data: ServerChatData) -> ServerNetworkData {
+        ServerNetworkData {
+            timestamp: Utc.timestamp_millis_opt(1_650_000_000_123).unwrap(),
+            chat_data,
+        }
+    }
+
+    #[test]
+    fn frame_header_is_big_endian_body_length() {
+        let data = ClientNetworkData {
+            chat_data: ClientChatData::ChatMessage(String::from("hi"), 3),
+        };
+        let frame = encode_frame(&data).unwrap();
+        let len = u32::from_be_bytes(frame[..4].try_into().unwrap());
+        assert_eq!(len as usize, frame.len() - 4);
+        assert_eq!(decode_body::<ClientNetworkData>(&frame[4..]).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn server_messages_round_trip() {
+        let alice = UserInfo {
+            id: 7,
+            name: String::from("alice"),
+        };
+        let messages = vec![
+            server_data(ServerChatData::Welcome(alice.clone())),
+            server_data(ServerChatData::OtherClientChatMessage(
+                alice.clone(),
+                String::from("hello"),
+            )),
+            server_data(ServerChatData::ReturnToSenderChatMessage(
+                String::from("héllo 👋"),
+                42,
+            )),
+            server_data(ServerChatData::VideoFrame(
+                alice.clone(),
+                vec![0xff, 0xd8, 0, 1],
+                176,
+                144,
+            )),
+            server_data(ServerChatData::JoinedRoom(
+                String::from("general"),
+                vec![(alice.clone(), MediaState::default())],
+            )),
+            server_data(ServerChatData::AudioFrame(
+                alice.clone(),
+                3,
+                vec![0, 0, 12, 0x88],
+            )),
+            server_data(ServerChatData::MediaState(
+                alice.clone(),
+                MediaState {
+                    camera: true,
+                    microphone: false,
+                },
+            )),
+            server_data(ServerChatData::History(
+                String::from("general"),
+                vec![StoredMessage {
+                    id: 12,
+                    sender: alice.clone(),
+                    message: String::from("earlier"),
+                    action: false,
+                    timestamp: Utc.timestamp_millis_opt(1_649_999_999_000).unwrap(),
+                }],
+                true,
+            )),
+            server_data(ServerChatData::UserJoined(
+                alice,
+                MediaState {
+                    camera: false,
+                    microphone: true,
+                },
+            )),
+            server_data(ServerChatData::UserRenamed(
+                UserInfo {
+                    id: 7,
+                    name: String::from("alicia"),
+                },
+                String::from("alice"),
+            )),
+            server_data(ServerChatData::Emote(
+                UserInfo {
+                    id: 7,
+                    name: String::from("alicia"),
+                },
+                String::from("waves"),
+            )),
+            server_data(ServerChatData::DirectMessage(
+                UserInfo {
+                    id: 8,
+                    name: String::from("bob"),
+                },
+                String::from("psst"),
+            )),
+            server_data(ServerChatData::DirectMessageSent(
+                UserInfo {
+                    id: 8,
+                    name: String::from("bob"),
+                },
+                String::from("psst"),
+            )),
+            server_data(ServerChatData::CommandRejected(CommandError::NoSuchUser(
+                String::from("carol"),
+            ))),
+            server_data(ServerChatData::UserLeft(UserInfo {
+                id: 8,
+                name: String::from("bob"),
+            })),
+            server_data(ServerChatData::JoinRoomRejected(
+                String::from("secret"),
+                JoinRoomError::WrongPasscode,
+            )),
+        ];
+        let mut stream = Vec::new();
+        for message in &messages {
+            write_frame(&mut stream, message).await.unwrap();
+        }
+
+        let mut reader = &stream[..];
+        for message in &messages {
+            let read: ServerNetworkData = read_frame(&mut reader).await.unwrap();
+            assert_eq!(&read, message);
+        }
+        assert!(matches!(
+            read_frame::<ServerNetworkData, _>(&mut reader).await,
+            Err(FrameError::Disconnected)
+        ));
+    }
+
+    #[tokio::test]
+    async fn client_messages_round_trip() {
+        let messages = vec![
+            ClientNetworkData {
+                chat_data: ClientChatData::Hello(String::from("bob")),
+            },
+            ClientNetworkData {
+                chat_data: ClientChatData::ChatMessage(String::from("hey"), 1),
+            },
+            ClientNetworkData {
+                chat_data: ClientChatData::VideoFrame(vec![1, 2, 3], 2, 2),
+            },
+            ClientNetworkData {
+                chat_data: ClientChatData::SubscribeVideo(vec![7, 9]),
+            },
+            ClientNetworkData {
+                chat_data: ClientChatData::AudioFrame(u64::MAX, vec![0x7f; 164]),
+            },
+            ClientNetworkData {
+                chat_data: ClientChatData::MediaState(MediaState {
+                    camera: true,
+                    microphone: true,
+                }),
+            },
+            ClientNetworkData {
+                chat_data: ClientChatData::JoinRoom(
+                    String::from("secret"),
+                    Some(String::from("hunter2")),
+                ),
+            },
+            ClientNetworkData {
+                chat_data: ClientChatData::RequestHistory(12),
+            },
+            ClientNetworkData {
+                chat_data: ClientChatData::SetNickname(String::from("robert")),
+            },
+            ClientNetworkData {
+                chat_data: ClientChatData::Emote(String::from("waves")),
+            },
+            ClientNetworkData {
+                chat_data: ClientChatData::DirectMessage(
+                    String::from("alice"),
+                    String::from("psst"),
+                ),
+            },
+        ];
+        let mut stream = Vec::new();
+        for message in &messages {
+            stream.extend(encode_frame(message).unwrap());
+        }
+
+        let mut reader = &stream[..];
+        for message in &messages {
+            let read: ClientNetworkData = read_frame(&mut reader).await.unwrap();
+            assert_eq!(&read, message);
+        }
+    }
+
+    #[tokio::test]
+    async fn truncated_frame_is_a_disconnect() {
+        let frame = encode_frame(&ClientNetworkData {
+            chat_data: ClientChatData::ChatMessage(String::from("cut off"), 1),
+        })
+        .unwrap();
+        let mut reader = &frame[..frame.len() - 2];
+        assert!(matches!(
+            read_frame::<ClientNetworkData, _>(&mut reader).await,
+            Err(FrameError::Disconnected)
+        ));
+    }
+
+    #[tokio::test]
+    async fn oversized_length_is_rejected_before_allocating() {
+        let header = (MAX_FRAME_LEN + 1).to_be_bytes();
+        let mut reader = &header[..];
+        assert!(matches!(
+            read_frame::<ClientNetworkData, _>(&mut reader).await,
+            Err(FrameError::TooLarge(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn malformed_body_leaves_stream_in_sync() {
+        let good = ClientNetworkData {
+            chat_data: ClientChatData::ChatMessage(String::from("after"), 2),
+        };
+        let mut stream = vec![0, 0, 0, 1, 0xff];
+        stream.extend(encode_frame(&good).unwrap());
+
+        let mut reader = &stream[..];
+        assert!(matches!(
+            read_frame::<ClientNetworkData, _>(&mut reader).await,
+            Err(FrameError::Malformed(_))
+        ));
+        let read: ClientNetworkData = read_frame(&mut reader).await.unwrap();
+        assert_eq!(read, good);
+    }
+}
@@ -0,0 +1,148 @@
+use clap::Parser;
+use serde::Deserialize;
+use std::path::PathBuf;
+use thiserror::Error;
+
+use crate::history::DEFAULT_PAGE_LEN;
+
+#[derive(Parser, Debug)]
+#[command(about = "Chat and video relay server for terminal-video-chat")]
+struct Args {
+    /// TOML file to read settings from, command line options take precedence over it
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+    /// Host or address to listen on [default: localhost]
+    #[arg(long)]
+    host: Option<String>,
+    /// Port to listen on [default: 8080]
+    #[arg(short, long)]
+    port: Option<u16>,
+    /// File chat messages are kept in between restarts [default: chat_history.jsonl]
+    #[arg(long)]
+    history_file: Option<PathBuf>,
+    /// How many old messages a client is sent on joining a room, and in each page after [default: 50]
+    #[arg(long)]
+    history_backlog: Option<usize>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub host: String,
+    pub port: u16,
+    pub history_file: PathBuf,
+    pub history_backlog: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            host: String::from("localhost"),
+            port: 8080,
+            history_file: PathBuf::from("chat_history.jsonl"),
+            history_backlog: DEFAULT_PAGE_LEN,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("could not read config file {0}: {1}")]
+    Read(PathBuf, std::io::Error),
+    #[error("could not parse config file {0}: {1}")]
+    Parse(PathBuf, toml::de::Error),
+    #[error("{0} must be at least 1")]
+    TooSmall(&'static str),
+}
+
+impl Config {
+    /// Build the config from the command line, falling back to the config file then the defaults.
+    pub fn load() -> Result<Self, ConfigError> {
+        Config::from_args(Args::parse())
+    }
+
+    fn from_args(args: Args) -> Result<Self, ConfigError> {
+        let mut config = match &args.config {
+            Some(path) => {
+                let contents = std::fs::read_to_string(path)
+                    .map_err(|e| ConfigError::Read(path.clone(), e))?;
+                toml::from_str(&contents).map_err(|e| ConfigError::Parse(path.clone(), e))?
+            }
+            None => Config::default(),
+        };
+
+        if let Some(host) = args.host {
+            config.host = host;
+        }
+        if let Some(port) = args.port {
+            config.port = port;
+        }
+        if let Some(history_file) = args.history_file {
+            config.history_file = history_file;
+        }
+        if let Some(history_backlog) = args.history_backlog {
+            config.history_backlog = history_backlog;
+        }
+
+        // an empty page would always say there's more before it
+        if config.history_backlog < 1 {
+            return Err(ConfigError::TooSmall("history_backlog"));
+        }
+        Ok(config)
+    }
+
+    pub fn bind_addr(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Load `toml` as the config file, with `args` after it on the command line.
+    fn load(name: &str, toml: &str, args: &[&str]) -> Result<Config, ConfigError> {
+        let path = std::env::temp_dir().join(format!(
+            "server-config-test-{}-{}.toml",
+            name,
+            std::process::id()
+        ));
+        std::fs::write(&path, toml).unwrap();
+        let mut command_line = vec!["server", "--config", path.to_str().unwrap()];
+        command_line.extend_from_slice(args);
+        let config = Config::from_args(Args::try_parse_from(command_line).unwrap());
+        std::fs::remove_file(&path).unwrap();
+        config
+    }
+
+    #[test]
+    fn command_line_beats_the_file_beats_the_defaults() {
+        let config = load(
+            "precedence",
+            "port = 9000\nhost = \"0.0.0.0\"",
+            &["--port", "9100"],
+        )
+        .unwrap();
+        assert_eq!(config.port, 9100);
+        assert_eq!(config.host, "0.0.0.0");
+        assert_eq!(config.history_backlog, DEFAULT_PAGE_LEN);
+        assert_eq!(config.bind_addr(), "0.0.0.0:9100");
+
+        assert!(matches!(
+            load("unknown", "colour = \"red\"", &[]),
+            Err(ConfigError::Parse(..))
+        ));
+    }
+
+    #[test]
+    fn empty_history_pages_are_rejected() {
+        assert!(matches!(
+            load("backlog", "history_backlog = 0", &[]),
+            Err(ConfigError::TooSmall("history_backlog"))
+        ));
+        assert!(matches!(
+            load("cli-backlog", "", &["--history-backlog", "0"]),
+            Err(ConfigError::TooSmall("history_backlog"))
+        ));
+    }
+}
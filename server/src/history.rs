@@ -0,0 +1,288 @@
+use chrono::{DateTime, Utc};
+use protocol::{StoredMessage, UserInfo};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::mpsc::{self, Sender};
+use std::thread::{self, JoinHandle};
+
+/// Messages sent on joining and in each older page asked for, unless the config says otherwise.
+pub const DEFAULT_PAGE_LEN: usize = 50;
+
+/// Most messages kept in memory for each room. Older ones stay in the log file but can no longer
+/// be paged back to.
+const MAX_KEPT: usize = 10_000;
+
+/// One line of the log file.
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    room: String,
+    #[serde(flatten)]
+    message: StoredMessage,
+}
+
+/// What's been said in each room, appended to a file of JSON lines so it outlives the server.
+///
+/// Rooms with a passcode are never written to the file and are forgotten once everyone has left,
+/// so whoever claims the name next can't read back what was said in them. They're kept apart from
+/// the public room of the same name, so neither sees what was said in the other.
+pub struct ChatLog {
+    writer: Option<Writer>,
+    /// Keyed by room name and whether it has a passcode.
+    rooms: HashMap<(String, bool), VecDeque<StoredMessage>>,
+    next_id: u64,
+    page_len: usize,
+}
+
+impl Default for ChatLog {
+    /// A log that only lasts as long as the server does.
+    fn default() -> Self {
+        ChatLog {
+            writer: None,
+            rooms: HashMap::new(),
+            next_id: 1,
+            page_len: DEFAULT_PAGE_LEN,
+        }
+    }
+}
+
+impl ChatLog {
+    /// Load the log at `path`, creating it if it doesn't exist yet, and append to it from now on.
+    pub fn open(path: &Path, page_len: usize) -> io::Result<Self> {
+        let mut log = ChatLog {
+            page_len,
+            ..ChatLog::default()
+        };
+        let contents = match std::fs::read(path) {
+            Ok(contents) => String::from_utf8_lossy(&contents).into_owned(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        let mut skipped = 0;
+        for line in contents.lines() {
+            match serde_json::from_str::<Entry>(line) {
+                Ok(entry) => log.remember(entry.room, false, entry.message),
+                // most likely cut short by the server going down part way through writing it
+                Err(_) => skipped += 1,
+            }
+        }
+        if skipped > 0 {
+            println!("skipped {} unreadable lines in {}", skipped, path.display());
+        }
+
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        if !contents.is_empty() && !contents.ends_with('\n') {
+            // keep the next entry off the end of a broken one
+            file.write_all(b"\n")?;
+        }
+        log.writer = Some(Writer::spawn(file));
+        Ok(log)
+    }
+
+    /// Store `message` from `sender` in `room`, writing it out unless the room is `private`.
+    /// An `action` is something they did with `/me`.
+    ///
+    /// The file is written on a thread of its own, so a slow disk never holds up the rooms. The
+    /// message is kept in memory even if writing it fails, so it can still be paged back to until
+    /// the server restarts.
+    pub fn record(
+        &mut self,
+        room: &str,
+        private: bool,
+        sender: &UserInfo,
+        message: &str,
+        action: bool,
+        timestamp: DateTime<Utc>,
+    ) -> io::Result<()> {
+        let stored = StoredMessage {
+            id: self.next_id,
+            sender: sender.clone(),
+            message: message.to_string(),
+            action,
+            timestamp,
+        };
+        self.remember(room.to_string(), private, stored.clone());
+        match &self.writer {
+            Some(writer) if !private => {
+                let entry = Entry {
+                    room: room.to_string(),
+                    message: stored,
+                };
+                let mut line = serde_json::to_string(&entry)?;
+                line.push('\n');
+                writer.write(line);
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The newest messages in `room`, oldest first, and whether there are any before them.
+    pub fn latest(&self, room: &str, private: bool) -> (Vec<StoredMessage>, bool) {
+        self.before(room, private, u64::MAX)
+    }
+
+    /// The page of messages in `room` from just before the one with `id`, oldest first, and
+    /// whether there are any before them.
+    pub fn before(&self, room: &str, private: bool, id: u64) -> (Vec<StoredMessage>, bool) {
+        let messages = match self.rooms.get(&(room.to_string(), private)) {
+            Some(messages) => messages,
+            None => return (Vec::new(), false),
+        };
+        let end = messages.partition_point(|message| message.id < id);
+        let start = end.saturating_sub(self.page_len);
+        (messages.range(start..end).cloned().collect(), start > 0)
+    }
+
+    /// Drop everything said in `room` while it had a passcode.
+    pub fn forget_private(&mut self, room: &str) {
+        self.rooms.remove(&(room.to_string(), true));
+    }
+
+    fn remember(&mut self, room: String, private: bool, message: StoredMessage) {
+        self.next_id = std::cmp::max(self.next_id, message.id + 1);
+        let messages = self.rooms.entry((room, private)).or_default();
+        messages.push_back(message);
+        if messages.len() > MAX_KEPT {
+            messages.pop_front();
+        }
+    }
+}
+
+/// Appends lines to the log file on its own thread, finishing them all before it's dropped.
+struct Writer {
+    lines: Option<Sender<String>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Writer {
+    fn spawn(mut file: File) -> Self {
+        let (lines, rx) = mpsc::channel::<String>();
+        let thread = thread::spawn(move || {
+            for line in rx {
+                // one write, so a crash can only ever break the last line
+                if let Err(e) = file.write_all(line.as_bytes()) {
+                    println!("couldn't write to the chat log: {}", e);
+                }
+            }
+        });
+        Writer {
+            lines: Some(lines),
+            thread: Some(thread),
+        }
+    }
+
+    fn write(&self, line: String) {
+        if let Some(lines) = &self.lines {
+            // only fails if the thread panicked, which it has already said
+            let _ = lines.send(line);
+        }
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        // closing the channel lets the thread finish what's queued and stop
+        self.lines.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alice() -> UserInfo {
+        UserInfo {
+            id: 1,
+            name: String::from("alice"),
+        }
+    }
+
+    fn texts(messages: &[StoredMessage]) -> Vec<&str> {
+        messages
+            .iter()
+            .map(|message| message.message.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn pages_go_back_from_the_newest() {
+        let mut log = ChatLog {
+            page_len: 2,
+            ..ChatLog::default()
+        };
+        for message in ["one", "two", "three"] {
+            log.record("a", false, &alice(), message, false, Utc::now())
+                .unwrap();
+        }
+        log.record("b", false, &alice(), "waves", true, Utc::now())
+            .unwrap();
+
+        let (latest, more) = log.latest("a", false);
+        assert_eq!(texts(&latest), ["two", "three"]);
+        assert!(more);
+        let (older, more) = log.before("a", false, latest[0].id);
+        assert_eq!(texts(&older), ["one"]);
+        assert!(!more);
+        assert!(log.latest("b", false).0[0].action);
+        assert_eq!(log.latest("nobody here", false), (Vec::new(), false));
+    }
+
+    #[test]
+    fn a_name_reused_with_a_passcode_starts_empty() {
+        let mut log = ChatLog::default();
+        log.record("a", false, &alice(), "public", false, Utc::now())
+            .unwrap();
+        assert!(log.latest("a", true).0.is_empty());
+
+        log.record("a", true, &alice(), "private", false, Utc::now())
+            .unwrap();
+        assert_eq!(texts(&log.latest("a", true).0), ["private"]);
+        assert_eq!(texts(&log.latest("a", false).0), ["public"]);
+
+        log.forget_private("a");
+        assert!(log.latest("a", true).0.is_empty());
+        assert_eq!(texts(&log.latest("a", false).0), ["public"]);
+    }
+
+    #[test]
+    fn reopening_loads_what_was_written() {
+        let path = std::env::temp_dir().join(format!("chat-log-test-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut log = ChatLog::open(&path, 10).unwrap();
+        log.record("a", false, &alice(), "kept", false, Utc::now())
+            .unwrap();
+        log.record("secret", true, &alice(), "not written", false, Utc::now())
+            .unwrap();
+        drop(log);
+        // as if the server died part way through a line
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"{\"room\":\"a\",\"id\"")
+            .unwrap();
+
+        let mut log = ChatLog::open(&path, 10).unwrap();
+        assert_eq!(texts(&log.latest("a", false).0), ["kept"]);
+        assert!(log.latest("secret", true).0.is_empty());
+        assert!(log.latest("secret", false).0.is_empty());
+        log.record("a", false, &alice(), "after", false, Utc::now())
+            .unwrap();
+        // waits for the writer to finish
+        drop(log);
+
+        let log = ChatLog::open(&path, 10).unwrap();
+        let (messages, _) = log.latest("a", false);
+        assert_eq!(texts(&messages), ["kept", "after"]);
+        // ids carry on from where they got to
+        assert!(messages[0].id < messages[1].id);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
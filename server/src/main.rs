@@ -1,57 +1,48 @@
-use chrono::serde::ts_milliseconds;
-use chrono::{DateTime, Utc};
-use serde::{Deserialize, Serialize};
+use protocol::{
+    ClientChatData, ClientNetworkData, CommandError, FrameError, MediaState, ServerChatData,
+    ServerNetworkData, StoredMessage, UserInfo, DEFAULT_ROOM,
+};
+use std::net::SocketAddr;
+use std::sync::Arc;
 use tokio::{
-    io::AsyncBufReadExt,
-    io::AsyncWriteExt,
-    io::{AsyncReadExt, BufReader},
-    net::TcpListener,
-    sync::broadcast,
-    time::sleep,
-    time::Duration,
+    io::{AsyncWrite, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::{broadcast::error::RecvError, mpsc},
 };
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct ServerNetworkData {
-    #[serde(with = "ts_milliseconds")]
-    timestamp: DateTime<Utc>,
-    // user id/struct
-    chat_data: ServerChatData,
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub enum ServerChatData {
-    OtherClientChatMessage(String),           // message
-    ReturnToSenderChatMessage(String, usize), // message, id
-    VideoFrame(Vec<u8>, u32, u32),            // (stream_data, width, height)
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct ClientNetworkData {
-    chat_data: ClientChatData,
-}
+use config::Config;
+use history::ChatLog;
+use rooms::{RoomEvent, RoomHandle, RoomRegistry, DIRECT_CHANNEL_CAPACITY};
+use video::{VideoFrame, VideoInbox};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub enum ClientChatData {
-    ChatMessage(String, usize),    // message, id
-    VideoFrame(Vec<u8>, u32, u32), // (stream_data, width, height)
-}
-
-fn convert_to_stream_data(network_data: &ServerNetworkData) -> Vec<u8> {
-    let buf = bincode::serialize(network_data).expect("serialize failed");
-    let buf_with_header = [&(buf.len() as u32).to_be_bytes(), &buf[..]].concat();
-    return buf_with_header;
-}
+mod config;
+mod history;
+mod rooms;
+mod users;
+mod video;
 
 #[tokio::main]
 async fn main() {
-    let listener = TcpListener::bind("localhost:8080")
+    let config = Config::load().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    let listener = TcpListener::bind(config.bind_addr())
         .await
         .expect("could not establish TCP connection");
-    let (tx_orig, _rx) = broadcast::channel(16);
+    println!("listening on {}", config.bind_addr());
+    let history = ChatLog::open(&config.history_file, config.history_backlog).unwrap_or_else(|e| {
+        eprintln!(
+            "could not open chat history {}: {}",
+            config.history_file.display(),
+            e
+        );
+        std::process::exit(1);
+    });
+    let rooms = RoomRegistry::new(history);
 
     loop {
-        let (mut socket, addr) = match listener.accept().await {
+        let (socket, addr) = match listener.accept().await {
             Ok((socket, addr)) => {
                 println!("new client: {:?}", addr);
                 (socket, addr)
@@ -62,86 +53,374 @@ async fn main() {
             }
         };
 
-        // if you put this above you get all the messages in the queue that came since the last client connected
-        let tx = tx_orig.clone();
-        let mut rx = tx.subscribe();
-
-        tokio::spawn(async move {
-            let (reader, mut writer) = socket.split();
-            let mut buf_reader = BufReader::new(reader);
-
-            loop {
-                tokio::select! {
-                    // get incoming message from client
-                    res = buf_reader.read_u32() => {
-                        match res {
-                            Ok(size) => {
-                                // println!("got data size {}", size);
-                                let mut buf = vec![0u8; size as usize];
-                                let res = buf_reader.read_exact(&mut buf).await;
-                                match res {
-                                    Ok(bytes_read) if bytes_read == size as usize => {
-                                        let timestamp = chrono::offset::Utc::now();
-                                        let data = bincode::deserialize(&buf).expect("deserialize should work");
-
-                                        tx.send((data, addr, timestamp)).unwrap();
-                                    }
-                                    Ok(_) => {
-                                        println!("didn't read right number of bytes");
-                                    }
-                                    Err(e) => match e.kind() {
-                                        tokio::io::ErrorKind::UnexpectedEof => {
-                                         println!("client disconnected");
-                                         break;
-                                        }
-                                         _ => println!("read u64: {}", e),
-                                     },
+        tokio::spawn(handle_client(socket, addr, rooms.clone()));
+    }
+}
+
+async fn handle_client(socket: TcpStream, addr: SocketAddr, rooms: RoomRegistry) {
+    let (reader, mut writer) = socket.into_split();
+
+    // reading gets its own task since a frame read can't be safely cancelled by select!
+    let (client_tx, mut client_rx) = mpsc::channel(16);
+    tokio::spawn(async move {
+        let mut buf_reader = BufReader::new(reader);
+        loop {
+            // get incoming message from client
+            match protocol::read_frame::<ClientNetworkData, _>(&mut buf_reader).await {
+                Ok(data) => {
+                    if client_tx.send(data).await.is_err() {
+                        break;
+                    }
+                }
+                Err(FrameError::Disconnected) => {
+                    println!("client disconnected: {:?}", addr);
+                    break;
+                }
+                // the whole frame was consumed so we can just skip it and keep reading
+                Err(e @ FrameError::Malformed(_)) => {
+                    println!("rejected frame from {:?}: {}", addr, e);
+                }
+                Err(e) => {
+                    println!(
+                        "couldn't read frame from {:?}, dropping client: {}",
+                        addr, e
+                    );
+                    break;
+                }
+            }
+        }
+    });
+
+    // nothing is relayed until the client has told us who it is
+    let mut user = loop {
+        match client_rx.recv().await {
+            Some(ClientNetworkData {
+                chat_data: ClientChatData::Hello(nickname),
+            }) => break users::register(&nickname),
+            Some(_) => println!("ignoring data from {:?} before hello", addr),
+            None => return,
+        }
+    };
+    println!("{:?} is {:?}", addr, user);
+    let response = ServerNetworkData {
+        timestamp: chrono::offset::Utc::now(),
+        chat_data: ServerChatData::Welcome(user.clone()),
+    };
+    if let Err(e) = protocol::write_frame(&mut writer, &response).await {
+        println!("couldn't write to {:?}, dropping client: {}", user, e);
+        return;
+    }
+
+    // video from the users this client is displaying, nobody else's frames are worth sending it
+    let video = Arc::new(VideoInbox::default());
+    // direct messages come straight here rather than round the room, so none get lost
+    let (direct_tx, mut direct_rx) = mpsc::channel(DIRECT_CHANNEL_CAPACITY);
+    // nothing is being sent until the client says otherwise, this follows it from room to room
+    let mut media = MediaState::default();
+    // every client starts off in the default room until it asks to join another
+    let mut room_name = String::from(DEFAULT_ROOM);
+    let RoomHandle {
+        mut rx,
+        mut audio_tx,
+        mut audio_rx,
+        members,
+        history,
+        more_history,
+        private: mut private_room,
+    } = rooms
+        .join(&room_name, None, &user, &video, &direct_tx, media)
+        .expect("default room is always joinable");
+    if let Err(e) = write_joined_room(
+        &mut writer,
+        &user,
+        &room_name,
+        members,
+        history,
+        more_history,
+    )
+    .await
+    {
+        println!("couldn't write to {:?}, dropping client: {}", user, e);
+        rooms.leave(&room_name, &user);
+        return;
+    }
+    // broadcast messages this client fell too far behind to receive
+    let mut dropped_messages: u64 = 0;
+
+    loop {
+        let response = tokio::select! {
+            // chat and room changes always go ahead of audio, and audio ahead of video
+            biased;
+            data = client_rx.recv() => {
+                let data = match data {
+                    Some(data) => data,
+                    None => break,
+                };
+                match data.chat_data {
+                    ClientChatData::Hello(_) => {
+                        println!("{:?} sent a second hello, ignoring", user);
+                        continue;
+                    }
+                    ClientChatData::SubscribeVideo(user_ids) => {
+                        video.subscribe(user_ids);
+                        continue;
+                    }
+                    ClientChatData::JoinRoom(name, passcode) => {
+                        match rooms.join(&name, passcode, &user, &video, &direct_tx, media) {
+                            Ok(handle) => {
+                                if name != room_name {
+                                    rooms.leave(&room_name, &user);
+                                    room_name = name;
+                                }
+                                // dropping the old receiver is what stops us hearing the old room
+                                rx = handle.rx;
+                                audio_tx = handle.audio_tx;
+                                audio_rx = handle.audio_rx;
+                                private_room = handle.private;
+                                video.subscribe([]);
+                                let written = write_joined_room(
+                                    &mut writer,
+                                    &user,
+                                    &room_name,
+                                    handle.members,
+                                    handle.history,
+                                    handle.more_history,
+                                )
+                                .await;
+                                if let Err(e) = written {
+                                    println!("couldn't write to {:?}, dropping client: {}", user, e);
+                                    break;
                                 }
+                                continue;
                             }
-                            Err(e) => match e.kind() {
-                               tokio::io::ErrorKind::UnexpectedEof => {
-                                println!("client disconnected");
-                                break;
-                               }
-                                _ => println!("read u64: {}", e),
+                            Err(e) => ServerNetworkData {
+                                timestamp: chrono::offset::Utc::now(),
+                                chat_data: ServerChatData::JoinRoomRejected(name, e),
                             },
                         }
-                        // if bytes_read.expect("unrecognized chars found, handle properly") == 0 {
-                        //     // EOF found
-                        //     break;
-                        // }
-
-                        // tx.send((ChatData::ChatMessage(line.clone()), addr)).unwrap();
-                        // line.clear();
-                    }
-                    res = rx.recv() => {
-                        let (data, incoming_addr, timestamp) = res.expect("has errors for running behind, or senders dropped, handle properly");
-                            match data {
-                                ClientNetworkData { chat_data: ClientChatData::ChatMessage(message, uid) } => {
-                                    let response =
-                                        if incoming_addr != addr {
-                                            // from another client
-                                            ServerNetworkData { timestamp: timestamp, chat_data: ServerChatData::OtherClientChatMessage(message) }
-                                        } else {
-                                            // from this client
-                                            ServerNetworkData { timestamp: timestamp, chat_data: ServerChatData::ReturnToSenderChatMessage(message, uid) }
-                                        };
-                                    let response = convert_to_stream_data(&response);
-                                    writer.write_all(&response).await.expect("should handle properly, just ignore, maybe send 'message failed to send' in future");
-                                }
-                                ClientNetworkData { chat_data: ClientChatData::VideoFrame(data, width, height) } => {
-                                    let response = ServerNetworkData { timestamp: timestamp, chat_data: ServerChatData::VideoFrame(data, width, height)};
-                                    let response = convert_to_stream_data(&response);
-                                    writer.write_all(&response).await.expect("should handle properly, just ignore, maybe send 'message failed to send' in future");
-                                }
-                                _ => {
-                                    println!("Got ClientNetworkData that couldn't be recognized");
-                                }
+                    }
+                    ClientChatData::RequestHistory(id) => {
+                        let (history, more_history) = rooms.history(&room_name, private_room, id);
+                        ServerNetworkData {
+                            timestamp: chrono::offset::Utc::now(),
+                            chat_data: ServerChatData::History(room_name.clone(), history, more_history),
+                        }
+                    }
+                    ClientChatData::MediaState(state) => {
+                        media = state;
+                        rooms.set_media(&room_name, &user, media);
+                        continue;
+                    }
+                    ClientChatData::VideoFrame(data, width, height) => {
+                        let frame = VideoFrame {
+                            sender: user.clone(),
+                            data,
+                            width,
+                            height,
+                            timestamp: chrono::offset::Utc::now(),
+                        };
+                        rooms.send_video(&room_name, frame);
+                        continue;
+                    }
+                    ClientChatData::AudioFrame(sequence, data) => {
+                        // can only fail if nobody is listening, and we always are
+                        let _ = audio_tx.send((user.clone(), sequence, data));
+                        continue;
+                    }
+                    ClientChatData::ChatMessage(message, uid) => {
+                        match rooms.send_message(&room_name, &user, message, uid) {
+                            Ok(()) => continue,
+                            Err(e) => ServerNetworkData {
+                                timestamp: chrono::offset::Utc::now(),
+                                chat_data: ServerChatData::CommandRejected(e),
+                            },
+                        }
+                    }
+                    ClientChatData::Emote(action) => {
+                        match rooms.send_action(&room_name, &user, action) {
+                            Ok(()) => continue,
+                            Err(e) => ServerNetworkData {
+                                timestamp: chrono::offset::Utc::now(),
+                                chat_data: ServerChatData::CommandRejected(e),
+                            },
+                        }
+                    }
+                    ClientChatData::DirectMessage(to, message) => {
+                        match rooms.send_direct(&room_name, &user, &to, message.clone()) {
+                            Ok(recipient) => ServerNetworkData {
+                                timestamp: chrono::offset::Utc::now(),
+                                chat_data: ServerChatData::DirectMessageSent(recipient, message),
+                            },
+                            Err(e) => ServerNetworkData {
+                                timestamp: chrono::offset::Utc::now(),
+                                chat_data: ServerChatData::CommandRejected(e),
+                            },
+                        }
+                    }
+                    ClientChatData::SetNickname(requested) => {
+                        match users::clean_nickname(&requested) {
+                            Some(name) => {
+                                let old_name = std::mem::replace(&mut user.name, name);
+                                println!("{:?} was {:?}", user, old_name);
+                                rooms.rename(&room_name, &user, old_name);
+                                continue;
                             }
-
+                            None => ServerNetworkData {
+                                timestamp: chrono::offset::Utc::now(),
+                                chat_data: ServerChatData::CommandRejected(CommandError::InvalidNickname),
+                            },
+                        }
+                    }
+                }
+            }
+            // never closes, we hold a sender ourselves
+            Some((sender, message, timestamp)) = direct_rx.recv() => ServerNetworkData {
+                timestamp,
+                chat_data: ServerChatData::DirectMessage(sender, message),
+            },
+            res = rx.recv() => {
+                let (event, sender, timestamp) = match res {
+                    Ok(message) => message,
+                    Err(RecvError::Lagged(skipped)) => {
+                        dropped_messages += skipped;
+                        println!("{:?} is running behind, dropped {} messages ({} total)", user, skipped, dropped_messages);
+                        continue;
+                    }
+                    // we hold a sender for the room ourselves so this shouldn't happen
+                    Err(RecvError::Closed) => break,
+                };
+                let chat_data = match event {
+                    RoomEvent::Data(ClientNetworkData { chat_data: ClientChatData::ChatMessage(message, uid) }) => {
+                        if sender.id != user.id {
+                            // from another client
+                            ServerChatData::OtherClientChatMessage(sender, message)
+                        } else {
+                            // from this client
+                            ServerChatData::ReturnToSenderChatMessage(message, uid)
+                        }
                     }
+                    RoomEvent::Data(ClientNetworkData { chat_data: ClientChatData::Emote(action) }) => {
+                        ServerChatData::Emote(sender, action)
+                    }
+                    // everything else is handled as soon as it's read and never broadcast
+                    RoomEvent::Data(_) => continue,
+                    RoomEvent::Renamed(old_name) => ServerChatData::UserRenamed(sender, old_name),
+                    // the client already knows what it's doing itself
+                    RoomEvent::Joined(_) | RoomEvent::Left | RoomEvent::Media(_) if sender.id == user.id => continue,
+                    RoomEvent::Joined(state) => ServerChatData::UserJoined(sender, state),
+                    RoomEvent::Left => ServerChatData::UserLeft(sender),
+                    RoomEvent::Media(state) => ServerChatData::MediaState(sender, state),
+                };
+                ServerNetworkData { timestamp, chat_data }
+            }
+            res = audio_rx.recv() => {
+                match res {
+                    // never echo our own audio back
+                    Ok((sender, _, _)) if sender.id == user.id => continue,
+                    Ok((sender, sequence, data)) => ServerNetworkData {
+                        timestamp: chrono::offset::Utc::now(),
+                        chat_data: ServerChatData::AudioFrame(sender, sequence, data),
+                    },
+                    // the jitter buffer on the other end copes with gaps
+                    Err(RecvError::Lagged(skipped)) => {
+                        println!("{:?} is running behind, dropped {} audio frames", user, skipped);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+            frame = video.next() => {
+                let VideoFrame { sender, data, width, height, timestamp } = frame;
+                ServerNetworkData {
+                    timestamp,
+                    chat_data: ServerChatData::VideoFrame(sender, data, width, height),
                 }
             }
-        });
+        };
+        if let Err(e) = write_or_skip(&mut writer, &response, &user).await {
+            println!("couldn't write to {:?}, dropping client: {}", user, e);
+            break;
+        }
+    }
+
+    rooms.leave(&room_name, &user);
+    println!(
+        "{:?} left after dropping {} messages for running behind",
+        user, dropped_messages
+    );
+}
+
+/// Tell a client it's made it into `room`, followed by what was last said there.
+async fn write_joined_room<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    user: &UserInfo,
+    room: &str,
+    members: Vec<(UserInfo, MediaState)>,
+    history: Vec<StoredMessage>,
+    more_history: bool,
+) -> Result<(), FrameError> {
+    let timestamp = chrono::offset::Utc::now();
+    let joined = ServerNetworkData {
+        timestamp,
+        chat_data: ServerChatData::JoinedRoom(room.to_string(), members),
+    };
+    write_or_skip(writer, &joined, user).await?;
+    let history = ServerNetworkData {
+        timestamp,
+        chat_data: ServerChatData::History(room.to_string(), history, more_history),
+    };
+    write_or_skip(writer, &history, user).await
+}
+
+/// Write `data` to `user`'s client. Something we can't encode is our problem rather than the
+/// connection's, so it's left out instead of dropping them.
+async fn write_or_skip<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    data: &ServerNetworkData,
+    user: &UserInfo,
+) -> Result<(), FrameError> {
+    let frame = match protocol::encode_frame(data) {
+        Ok(frame) => frame,
+        Err(e) => {
+            println!(
+                "couldn't encode a message for {:?}, skipping it: {}",
+                user, e
+            );
+            return Ok(());
+        }
+    };
+    writer.write_all(&frame).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::MAX_FRAME_LEN;
+
+    #[tokio::test]
+    async fn messages_too_big_to_encode_are_skipped() {
+        let user = UserInfo {
+            id: 1,
+            name: String::from("user1"),
+        };
+        let message = |text: String| ServerNetworkData {
+            timestamp: chrono::offset::Utc::now(),
+            chat_data: ServerChatData::OtherClientChatMessage(user.clone(), text),
+        };
+        let mut written = Vec::new();
+        let huge = message("x".repeat(MAX_FRAME_LEN as usize));
+        write_or_skip(&mut written, &huge, &user).await.unwrap();
+        assert!(written.is_empty());
+
+        // and the connection carries on as normal
+        let hello = message(String::from("hello"));
+        write_or_skip(&mut written, &hello, &user).await.unwrap();
+        let mut reader = &written[..];
+        let read = protocol::read_frame::<ServerNetworkData, _>(&mut reader)
+            .await
+            .unwrap();
+        assert_eq!(read.chat_data, hello.chat_data);
+        assert!(reader.is_empty());
     }
 }
@@ -0,0 +1,665 @@
+use chrono::{DateTime, Utc};
+use protocol::{
+    ClientChatData, ClientNetworkData, CommandError, JoinRoomError, MediaState, StoredMessage,
+    UserInfo, DEFAULT_ROOM, MAX_MESSAGE_LEN, MAX_ROOM_NAME_LEN,
+};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{broadcast, mpsc};
+
+use crate::history::ChatLog;
+use crate::video::{VideoFrame, VideoInbox};
+
+#[derive(Clone, Debug)]
+pub enum RoomEvent {
+    Data(ClientNetworkData),
+    /// Arrived sending this.
+    Joined(MediaState),
+    Left,
+    /// Turned their camera or microphone on or off.
+    Media(MediaState),
+    /// Changed their nickname from this.
+    Renamed(String),
+}
+
+/// What gets fanned out to every client in a room: the event, who it's from and when the server got it.
+/// Video doesn't go through here, see `RoomRegistry::send_video`.
+pub type RoomMessage = (RoomEvent, UserInfo, DateTime<Utc>);
+
+/// Audio from one member: the sender, the sequence number they gave it and the encoded samples.
+/// Audio gets a channel of its own so a backlog of it can't push chat out of the room channel.
+pub type AudioMessage = (UserInfo, u64, Vec<u8>);
+
+/// A message meant for one member only: who it's from, what it says and when the server got it.
+/// These go straight to the member's own task so a busy room can't push them out.
+pub type DirectMessage = (UserInfo, String, DateTime<Utc>);
+
+/// How many direct messages a client can have waiting before more are turned away.
+pub const DIRECT_CHANNEL_CAPACITY: usize = 16;
+
+/// How many audio frames a client can fall behind by before it misses some, about a second.
+const AUDIO_CHANNEL_CAPACITY: usize = 64;
+
+/// A client's membership of a room, the receiver is created while the registry is locked so no
+/// messages sent after joining are missed.
+pub struct RoomHandle {
+    pub rx: broadcast::Receiver<RoomMessage>,
+    pub audio_tx: broadcast::Sender<AudioMessage>,
+    pub audio_rx: broadcast::Receiver<AudioMessage>,
+    /// Everyone who was already in the room when we joined, and what they're sending.
+    pub members: Vec<(UserInfo, MediaState)>,
+    /// The last few messages said in the room, oldest first.
+    pub history: Vec<StoredMessage>,
+    /// Whether the room's history goes back further than `history`.
+    pub more_history: bool,
+    /// Whether the room has a passcode, which keeps its history apart from the public room's.
+    pub private: bool,
+}
+
+struct Member {
+    user: UserInfo,
+    video: Arc<VideoInbox>,
+    direct: mpsc::Sender<DirectMessage>,
+    media: MediaState,
+}
+
+struct Room {
+    passcode: Option<String>,
+    tx: broadcast::Sender<RoomMessage>,
+    audio_tx: broadcast::Sender<AudioMessage>,
+    members: Vec<Member>,
+}
+
+/// All rooms currently known to the server, shared between client tasks.
+#[derive(Clone, Default)]
+pub struct RoomRegistry {
+    rooms: Arc<Mutex<HashMap<String, Room>>>,
+    /// Held while a message is both recorded and sent, and while a joiner reads the latest
+    /// history and subscribes, so the two can't overlap or leave a gap. Taken after `rooms` when
+    /// both are needed, never before.
+    history: Arc<Mutex<ChatLog>>,
+}
+
+impl RoomRegistry {
+    pub fn new(history: ChatLog) -> Self {
+        RoomRegistry {
+            rooms: Arc::default(),
+            history: Arc::new(Mutex::new(history)),
+        }
+    }
+
+    /// Add `user` to `name`, creating the room (with `passcode`) if nobody is in it, and tell the
+    /// rest of the room they've arrived. Joining a room you're already in just resubscribes.
+    /// Either way the handle comes with the latest of the room's history.
+    ///
+    /// A room only lives as long as it has members, so once everyone leaves the name and
+    /// passcode are free to be claimed again. The default room never has a passcode.
+    pub fn join(
+        &self,
+        name: &str,
+        passcode: Option<String>,
+        user: &UserInfo,
+        video: &Arc<VideoInbox>,
+        direct: &mpsc::Sender<DirectMessage>,
+        media: MediaState,
+    ) -> Result<RoomHandle, JoinRoomError> {
+        let name_len = name.chars().count();
+        if name.trim() != name || name_len == 0 || name_len > MAX_ROOM_NAME_LEN {
+            return Err(JoinRoomError::InvalidName);
+        }
+
+        let mut rooms = self.rooms.lock().unwrap();
+        let mut log = self.history.lock().unwrap();
+        // in case a client task went away without leaving
+        rooms.retain(|name, room| {
+            let open = room.tx.receiver_count() > 0;
+            if !open && room.passcode.is_some() {
+                log.forget_private(name);
+            }
+            open
+        });
+
+        let room = match rooms.get_mut(name) {
+            Some(room) => {
+                let is_member = room.members.iter().any(|member| member.user.id == user.id);
+                match &room.passcode {
+                    Some(room_passcode)
+                        if !is_member && passcode.as_ref() != Some(room_passcode) =>
+                    {
+                        return Err(JoinRoomError::WrongPasscode);
+                    }
+                    _ => room,
+                }
+            }
+            None => rooms.entry(name.to_string()).or_insert_with(|| Room {
+                passcode: passcode.filter(|_| name != DEFAULT_ROOM),
+                tx: broadcast::channel(16).0,
+                audio_tx: broadcast::channel(AUDIO_CHANNEL_CAPACITY).0,
+                members: Vec::new(),
+            }),
+        };
+
+        let private = room.passcode.is_some();
+        let (history, more_history) = log.latest(name, private);
+        let handle = RoomHandle {
+            rx: room.tx.subscribe(),
+            audio_tx: room.audio_tx.clone(),
+            audio_rx: room.audio_tx.subscribe(),
+            members: room
+                .members
+                .iter()
+                .filter(|member| member.user.id != user.id)
+                .map(|member| (member.user.clone(), member.media))
+                .collect(),
+            history,
+            more_history,
+            private,
+        };
+        if handle.members.len() == room.members.len() {
+            room.members.push(Member {
+                user: user.clone(),
+                video: video.clone(),
+                direct: direct.clone(),
+                media,
+            });
+            let _ = room
+                .tx
+                .send((RoomEvent::Joined(media), user.clone(), Utc::now()));
+        }
+        Ok(handle)
+    }
+
+    /// Take `user` out of `name` and tell the rest of the room they're gone.
+    pub fn leave(&self, name: &str, user: &UserInfo) {
+        let mut rooms = self.rooms.lock().unwrap();
+        if let Some(room) = rooms.get_mut(name) {
+            room.members.retain(|member| member.user.id != user.id);
+            let _ = room.tx.send((RoomEvent::Left, user.clone(), Utc::now()));
+            if room.members.is_empty() {
+                if room.passcode.is_some() {
+                    self.history.lock().unwrap().forget_private(name);
+                }
+                rooms.remove(name);
+            }
+        }
+    }
+
+    /// Remember what `user` is sending now, for anyone who joins later, and tell the room.
+    pub fn set_media(&self, name: &str, user: &UserInfo, media: MediaState) {
+        let mut rooms = self.rooms.lock().unwrap();
+        if let Some(room) = rooms.get_mut(name) {
+            if let Some(member) = room
+                .members
+                .iter_mut()
+                .find(|member| member.user.id == user.id)
+            {
+                member.media = media;
+                let _ = room
+                    .tx
+                    .send((RoomEvent::Media(media), user.clone(), Utc::now()));
+            }
+        }
+    }
+
+    /// Relay a chat message from `user` to everyone in `name`, storing it for anyone who joins
+    /// later.
+    pub fn send_message(
+        &self,
+        name: &str,
+        user: &UserInfo,
+        message: String,
+        uid: usize,
+    ) -> Result<(), CommandError> {
+        check_len(&message)?;
+        self.relay(
+            name,
+            user,
+            &message,
+            false,
+            ClientChatData::ChatMessage(message.clone(), uid),
+        );
+        Ok(())
+    }
+
+    /// Relay something `user` did, from `/me`, the same way as a chat message.
+    pub fn send_action(
+        &self,
+        name: &str,
+        user: &UserInfo,
+        action: String,
+    ) -> Result<(), CommandError> {
+        check_len(&action)?;
+        self.relay(
+            name,
+            user,
+            &action,
+            true,
+            ClientChatData::Emote(action.clone()),
+        );
+        Ok(())
+    }
+
+    /// Pass `message` from `user` on to only the member of `name` called `to`, matched ignoring
+    /// case, returning who that turned out to be. It's refused rather than dropped if they have
+    /// too many waiting already.
+    pub fn send_direct(
+        &self,
+        name: &str,
+        user: &UserInfo,
+        to: &str,
+        message: String,
+    ) -> Result<UserInfo, CommandError> {
+        check_len(&message)?;
+        let rooms = self.rooms.lock().unwrap();
+        let room = match rooms.get(name) {
+            Some(room) => room,
+            None => return Err(CommandError::NoSuchUser(to.to_string())),
+        };
+        let mut matches = room
+            .members
+            .iter()
+            .filter(|member| member.user.name.to_lowercase() == to.to_lowercase());
+        let recipient = match (matches.next(), matches.next()) {
+            (Some(recipient), None) => recipient,
+            (Some(_), Some(_)) => return Err(CommandError::AmbiguousUser(to.to_string())),
+            (None, _) => return Err(CommandError::NoSuchUser(to.to_string())),
+        };
+        recipient
+            .direct
+            .try_send((user.clone(), message, Utc::now()))
+            .map_err(|_| CommandError::Undelivered(recipient.user.name.clone()))?;
+        Ok(recipient.user.clone())
+    }
+
+    /// Note that `user`, who was called `old_name`, has a new name, and tell the room, them
+    /// included.
+    pub fn rename(&self, name: &str, user: &UserInfo, old_name: String) {
+        let mut rooms = self.rooms.lock().unwrap();
+        if let Some(room) = rooms.get_mut(name) {
+            if let Some(member) = room
+                .members
+                .iter_mut()
+                .find(|member| member.user.id == user.id)
+            {
+                member.user = user.clone();
+                let _ = room
+                    .tx
+                    .send((RoomEvent::Renamed(old_name), user.clone(), Utc::now()));
+            }
+        }
+    }
+
+    fn relay(
+        &self,
+        name: &str,
+        user: &UserInfo,
+        message: &str,
+        action: bool,
+        chat_data: ClientChatData,
+    ) {
+        // `user` is in the room, and only their own task can take them out of it, so it can't
+        // close and be claimed by someone else before the message is recorded
+        let (private, tx) = match self.rooms.lock().unwrap().get(name) {
+            Some(room) => (room.passcode.is_some(), room.tx.clone()),
+            None => return,
+        };
+        let mut log = self.history.lock().unwrap();
+        // stored with the same timestamp it goes out with, so clients can tell it's the same
+        let timestamp = Utc::now();
+        if let Err(e) = log.record(name, private, user, message, action, timestamp) {
+            println!(
+                "couldn't write message from {:?} to the chat log: {}",
+                user, e
+            );
+        }
+        let data = ClientNetworkData { chat_data };
+        let _ = tx.send((RoomEvent::Data(data), user.clone(), timestamp));
+    }
+
+    /// The page of `name`'s history from before the message with `id`, and whether there's more.
+    /// `private` is whether the room has a passcode, as given in its [`RoomHandle`].
+    pub fn history(&self, name: &str, private: bool, id: u64) -> (Vec<StoredMessage>, bool) {
+        self.history.lock().unwrap().before(name, private, id)
+    }
+
+    /// Hand `frame` to everyone else in `name`, each of whom only keeps it if they're subscribed.
+    pub fn send_video(&self, name: &str, frame: VideoFrame) {
+        let rooms = self.rooms.lock().unwrap();
+        if let Some(room) = rooms.get(name) {
+            for member in room.members.iter() {
+                if member.user.id != frame.sender.id {
+                    member.video.offer(frame.clone());
+                }
+            }
+        }
+    }
+}
+
+/// Turn away anything too long to relay, or to send back as part of a page of history.
+fn check_len(message: &str) -> Result<(), CommandError> {
+    if message.chars().count() > MAX_MESSAGE_LEN {
+        return Err(CommandError::MessageTooLong);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inbox() -> Arc<VideoInbox> {
+        Arc::new(VideoInbox::default())
+    }
+
+    /// Somewhere for direct messages to go that nobody reads.
+    fn direct() -> mpsc::Sender<DirectMessage> {
+        mpsc::channel(DIRECT_CHANNEL_CAPACITY).0
+    }
+
+    fn user(id: u64) -> UserInfo {
+        UserInfo {
+            id,
+            name: format!("user{}", id),
+        }
+    }
+
+    /// `user(id)` joins `room`, sending nothing, with a passcode if it's given.
+    fn join_with(
+        rooms: &RoomRegistry,
+        room: &str,
+        passcode: Option<&str>,
+        id: u64,
+    ) -> Result<RoomHandle, JoinRoomError> {
+        rooms.join(
+            room,
+            passcode.map(String::from),
+            &user(id),
+            &inbox(),
+            &direct(),
+            MediaState::default(),
+        )
+    }
+
+    /// `user(id)` joins `room`, which has to let them in.
+    fn join(rooms: &RoomRegistry, room: &str, id: u64) -> RoomHandle {
+        join_with(rooms, room, None, id).unwrap()
+    }
+
+    #[test]
+    fn passcode_is_checked_for_later_joiners() {
+        let rooms = RoomRegistry::default();
+        let _owner = join_with(&rooms, "secret", Some("pass"), 1).unwrap();
+
+        assert_eq!(
+            join_with(&rooms, "secret", None, 2).err(),
+            Some(JoinRoomError::WrongPasscode)
+        );
+        assert_eq!(
+            join_with(&rooms, "secret", Some("nope"), 2).err(),
+            Some(JoinRoomError::WrongPasscode)
+        );
+        assert!(join_with(&rooms, "secret", Some("pass"), 2).is_ok());
+    }
+
+    #[test]
+    fn rooms_are_freed_once_empty() {
+        let rooms = RoomRegistry::default();
+        let _owner = join_with(&rooms, "secret", Some("pass"), 1).unwrap();
+        rooms.leave("secret", &user(1));
+
+        assert!(join_with(&rooms, "secret", None, 2).is_ok());
+    }
+
+    #[test]
+    fn members_and_arrivals_are_announced() {
+        let rooms = RoomRegistry::default();
+        let mut first = join(&rooms, "a", 1);
+        assert!(first.members.is_empty());
+        // our own arrival
+        assert!(matches!(
+            first.rx.try_recv().unwrap().0,
+            RoomEvent::Joined(_)
+        ));
+
+        let second = join(&rooms, "a", 2);
+        assert_eq!(second.members, vec![(user(1), MediaState::default())]);
+        let (event, from, _) = first.rx.try_recv().unwrap();
+        assert!(matches!(event, RoomEvent::Joined(_)));
+        assert_eq!(from, user(2));
+
+        rooms.leave("a", &user(2));
+        let (event, from, _) = first.rx.try_recv().unwrap();
+        assert!(matches!(event, RoomEvent::Left));
+        assert_eq!(from, user(2));
+    }
+
+    #[test]
+    fn rejoining_keeps_a_single_membership() {
+        let rooms = RoomRegistry::default();
+        let _owner = join_with(&rooms, "secret", Some("pass"), 1).unwrap();
+        let mut again = join(&rooms, "secret", 1);
+        assert!(again.members.is_empty());
+        assert!(again.rx.try_recv().is_err());
+
+        let second = join_with(&rooms, "secret", Some("pass"), 2).unwrap();
+        assert_eq!(second.members, vec![(user(1), MediaState::default())]);
+    }
+
+    #[test]
+    fn messages_stay_within_a_room() {
+        let rooms = RoomRegistry::default();
+        let mut a = join(&rooms, "a", 1);
+        let mut b = join(&rooms, "b", 2);
+        a.rx.try_recv().unwrap();
+        b.rx.try_recv().unwrap();
+
+        rooms
+            .send_message("a", &user(1), String::from("hi"), 1)
+            .unwrap();
+        let data = ClientNetworkData {
+            chat_data: ClientChatData::ChatMessage(String::from("hi"), 1),
+        };
+
+        assert!(matches!(a.rx.try_recv().unwrap().0, RoomEvent::Data(d) if d == data));
+        assert!(b.rx.try_recv().is_err());
+
+        a.audio_tx.send((user(1), 0, vec![1, 2])).unwrap();
+        assert_eq!(a.audio_rx.try_recv().unwrap(), (user(1), 0, vec![1, 2]));
+        assert!(b.audio_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn video_only_reaches_other_members() {
+        let rooms = RoomRegistry::default();
+        let (first_video, second_video, elsewhere_video) = (inbox(), inbox(), inbox());
+        let join_watching = |room, id, video: &Arc<VideoInbox>| {
+            rooms
+                .join(
+                    room,
+                    None,
+                    &user(id),
+                    video,
+                    &direct(),
+                    MediaState::default(),
+                )
+                .unwrap()
+        };
+        let _first = join_watching("a", 1, &first_video);
+        let _second = join_watching("a", 2, &second_video);
+        let _elsewhere = join_watching("b", 3, &elsewhere_video);
+        for video in [&first_video, &second_video, &elsewhere_video] {
+            video.subscribe([1, 2]);
+        }
+
+        let frame = VideoFrame {
+            sender: user(1),
+            data: vec![1, 2, 3],
+            width: 1,
+            height: 1,
+            timestamp: Utc::now(),
+        };
+        rooms.send_video("a", frame.clone());
+
+        assert_eq!(second_video.next().await, frame);
+        rooms.send_video("a", frame.clone());
+        tokio::select! {
+            biased;
+            _ = first_video.next() => panic!("got our own video back"),
+            _ = elsewhere_video.next() => panic!("video leaked into another room"),
+            frame = second_video.next() => assert_eq!(frame.sender, user(1)),
+        }
+    }
+
+    #[test]
+    fn bad_names_and_default_room_passcodes() {
+        let rooms = RoomRegistry::default();
+        assert_eq!(
+            join_with(&rooms, "", None, 1).err(),
+            Some(JoinRoomError::InvalidName)
+        );
+        assert_eq!(
+            join_with(&rooms, " padded ", None, 1).err(),
+            Some(JoinRoomError::InvalidName)
+        );
+
+        let _first = join_with(&rooms, DEFAULT_ROOM, Some("mine"), 1).unwrap();
+        assert!(join_with(&rooms, DEFAULT_ROOM, None, 2).is_ok());
+    }
+
+    #[test]
+    fn media_state_is_announced_and_remembered() {
+        let rooms = RoomRegistry::default();
+        let camera_only = MediaState {
+            camera: true,
+            microphone: false,
+        };
+        let mut first = join(&rooms, "a", 1);
+        first.rx.try_recv().unwrap();
+
+        rooms.set_media("a", &user(1), camera_only);
+        let (event, from, _) = first.rx.try_recv().unwrap();
+        assert!(matches!(event, RoomEvent::Media(media) if media == camera_only));
+        assert_eq!(from, user(1));
+
+        let second = rooms
+            .join("a", None, &user(2), &inbox(), &direct(), camera_only)
+            .unwrap();
+        assert_eq!(second.members, vec![(user(1), camera_only)]);
+        let (event, from, _) = first.rx.try_recv().unwrap();
+        assert!(matches!(event, RoomEvent::Joined(media) if media == camera_only));
+        assert_eq!(from, user(2));
+    }
+
+    #[test]
+    fn history_comes_with_joining_and_private_rooms_forget_it() {
+        let rooms = RoomRegistry::default();
+        let _first = join(&rooms, "a", 1);
+        let _owner = join_with(&rooms, "secret", Some("pass"), 1).unwrap();
+        rooms
+            .send_message("a", &user(1), String::from("before you came"), 1)
+            .unwrap();
+        rooms
+            .send_message("secret", &user(1), String::from("shh"), 2)
+            .unwrap();
+
+        let second = join(&rooms, "a", 2);
+        assert_eq!(second.history.len(), 1);
+        assert_eq!(second.history[0].message, "before you came");
+        assert_eq!(second.history[0].sender, user(1));
+        assert!(!second.more_history);
+        assert!(rooms.history("a", false, second.history[0].id).0.is_empty());
+
+        rooms.leave("secret", &user(1));
+        let reclaimed = join(&rooms, "secret", 2);
+        assert!(reclaimed.history.is_empty());
+
+        // a public room's name taken with a passcode doesn't bring its history along
+        rooms.leave("a", &user(1));
+        rooms.leave("a", &user(2));
+        let private = join_with(&rooms, "a", Some("pass"), 3).unwrap();
+        assert!(private.private);
+        assert!(private.history.is_empty());
+    }
+
+    #[test]
+    fn direct_messages_find_one_member_by_name() {
+        let rooms = RoomRegistry::default();
+        let mut first = join(&rooms, "a", 1);
+        let (second_direct, mut second_inbox) = mpsc::channel(1);
+        let _second = rooms
+            .join(
+                "a",
+                None,
+                &user(2),
+                &inbox(),
+                &second_direct,
+                MediaState::default(),
+            )
+            .unwrap();
+        while first.rx.try_recv().is_ok() {}
+
+        assert_eq!(
+            rooms.send_direct("a", &user(1), "USER2", String::from("psst")),
+            Ok(user(2))
+        );
+        let (from, message, _) = second_inbox.try_recv().unwrap();
+        assert_eq!((from, message.as_str()), (user(1), "psst"));
+        // the rest of the room never sees it
+        assert!(first.rx.try_recv().is_err());
+
+        // one they haven't got round to yet is refused instead of lost
+        rooms
+            .send_direct("a", &user(1), "user2", String::from("one"))
+            .unwrap();
+        assert_eq!(
+            rooms.send_direct("a", &user(1), "user2", String::from("two")),
+            Err(CommandError::Undelivered(String::from("user2")))
+        );
+        assert_eq!(
+            rooms.send_direct("a", &user(1), "user3", String::from("hello?")),
+            Err(CommandError::NoSuchUser(String::from("user3")))
+        );
+
+        // a second user2 makes the name ambiguous
+        let renamed = UserInfo {
+            id: 1,
+            name: String::from("user2"),
+        };
+        rooms.rename("a", &renamed, String::from("user1"));
+        let (event, from, _) = first.rx.try_recv().unwrap();
+        assert!(matches!(event, RoomEvent::Renamed(old) if old == "user1"));
+        assert_eq!(from, renamed);
+        assert_eq!(
+            rooms.send_direct("a", &renamed, "user2", String::from("which?")),
+            Err(CommandError::AmbiguousUser(String::from("user2")))
+        );
+    }
+
+    #[test]
+    fn overlong_messages_are_refused_before_anyone_sees_them() {
+        let rooms = RoomRegistry::default();
+        let mut first = join(&rooms, "a", 1);
+        let _second = join(&rooms, "a", 2);
+        while first.rx.try_recv().is_ok() {}
+
+        let longest = "x".repeat(MAX_MESSAGE_LEN);
+        let too_long = "x".repeat(MAX_MESSAGE_LEN + 1);
+        assert_eq!(
+            rooms.send_message("a", &user(1), too_long.clone(), 1),
+            Err(CommandError::MessageTooLong)
+        );
+        assert_eq!(
+            rooms.send_action("a", &user(1), too_long.clone()),
+            Err(CommandError::MessageTooLong)
+        );
+        assert_eq!(
+            rooms.send_direct("a", &user(1), "user2", too_long),
+            Err(CommandError::MessageTooLong)
+        );
+        // nothing went out or into the history
+        assert!(first.rx.try_recv().is_err());
+        assert!(join(&rooms, "a", 3).history.is_empty());
+
+        assert_eq!(rooms.send_message("a", &user(1), longest, 2), Ok(()));
+    }
+}
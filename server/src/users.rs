@@ -0,0 +1,51 @@
+use protocol::{UserId, UserInfo, MAX_NICKNAME_LEN};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+fn next_user_id() -> UserId {
+    static USER_ID: AtomicU64 = AtomicU64::new(1);
+    USER_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Give a newly connected client an id, cleaning up the nickname it asked for.
+///
+/// Control characters are dropped and the name is cut to `MAX_NICKNAME_LEN`, if nothing usable
+/// is left the client becomes `guest<id>`.
+pub fn register(requested_name: &str) -> UserInfo {
+    let id = next_user_id();
+    let name = clean_nickname(requested_name).unwrap_or_else(|| format!("guest{}", id));
+    UserInfo { id, name }
+}
+
+/// Drop control characters and cut `requested_name` to `MAX_NICKNAME_LEN`, `None` if that leaves
+/// nothing.
+pub fn clean_nickname(requested_name: &str) -> Option<String> {
+    let name: String = requested_name
+        .chars()
+        .filter(|c| !c.is_control())
+        .collect::<String>()
+        .trim()
+        .chars()
+        .take(MAX_NICKNAME_LEN)
+        .collect();
+    Some(name).filter(|name| !name.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_are_unique_and_names_cleaned() {
+        let alice = register("  alice\n");
+        let guest = register("\t");
+        assert_eq!(alice.name, "alice");
+        assert_ne!(alice.id, guest.id);
+        assert_eq!(guest.name, format!("guest{}", guest.id));
+        assert_eq!(
+            register(&"x".repeat(100)).name.chars().count(),
+            MAX_NICKNAME_LEN
+        );
+        assert_eq!(clean_nickname(" bob\u{7}"), Some(String::from("bob")));
+        assert_eq!(clean_nickname("\n"), None);
+    }
+}
@@ -0,0 +1,116 @@
+use chrono::{DateTime, Utc};
+use protocol::{UserId, UserInfo};
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use tokio::sync::Notify;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct VideoFrame {
+    pub sender: UserInfo,
+    pub data: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+    pub timestamp: DateTime<Utc>,
+}
+
+#[derive(Default)]
+struct Inbox {
+    subscriptions: HashSet<UserId>,
+    frames: HashMap<UserId, VideoFrame>,
+}
+
+/// The video waiting to be written to one client, holding only the newest frame from each sender
+/// it's subscribed to so a slow client skips frames instead of falling further and further behind.
+#[derive(Default)]
+pub struct VideoInbox {
+    inbox: Mutex<Inbox>,
+    notify: Notify,
+}
+
+impl VideoInbox {
+    /// Only accept frames from `user_ids` from now on, dropping any others still waiting.
+    pub fn subscribe(&self, user_ids: impl IntoIterator<Item = UserId>) {
+        let mut inbox = self.inbox.lock().unwrap();
+        inbox.subscriptions = user_ids.into_iter().collect();
+        let Inbox {
+            subscriptions,
+            frames,
+        } = &mut *inbox;
+        frames.retain(|sender, _| subscriptions.contains(sender));
+    }
+
+    /// Queue `frame` if we're subscribed to its sender, replacing their previous frame if it
+    /// hasn't been sent yet.
+    pub fn offer(&self, frame: VideoFrame) {
+        let mut inbox = self.inbox.lock().unwrap();
+        if inbox.subscriptions.contains(&frame.sender.id) {
+            inbox.frames.insert(frame.sender.id, frame);
+            self.notify.notify_one();
+        }
+    }
+
+    /// Wait for the oldest waiting frame. Safe to use in `select!`, nothing is lost if cancelled.
+    pub async fn next(&self) -> VideoFrame {
+        loop {
+            if let Some(frame) = self.take_oldest() {
+                return frame;
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    fn take_oldest(&self) -> Option<VideoFrame> {
+        let mut inbox = self.inbox.lock().unwrap();
+        let sender = inbox
+            .frames
+            .values()
+            .min_by_key(|frame| frame.timestamp)
+            .map(|frame| frame.sender.id)?;
+        inbox.frames.remove(&sender)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(id: u64, data: u8) -> VideoFrame {
+        VideoFrame {
+            sender: UserInfo {
+                id,
+                name: format!("user{}", id),
+            },
+            data: vec![data],
+            width: 1,
+            height: 1,
+            timestamp: Utc::now(),
+        }
+    }
+
+    #[tokio::test]
+    async fn only_the_newest_frame_per_sender_is_kept() {
+        let inbox = VideoInbox::default();
+        inbox.subscribe([1, 2]);
+        inbox.offer(frame(1, 1));
+        inbox.offer(frame(2, 1));
+        inbox.offer(frame(1, 2));
+        inbox.offer(frame(3, 1));
+
+        assert_eq!(inbox.next().await.data, vec![1]);
+        let newest = inbox.next().await;
+        assert_eq!((newest.sender.id, newest.data), (1, vec![2]));
+        assert!(inbox.take_oldest().is_none());
+    }
+
+    #[test]
+    fn unsubscribing_drops_waiting_frames() {
+        let inbox = VideoInbox::default();
+        inbox.subscribe([1, 2]);
+        inbox.offer(frame(1, 1));
+        inbox.offer(frame(2, 1));
+        inbox.subscribe([2]);
+
+        assert_eq!(inbox.take_oldest().map(|frame| frame.sender.id), Some(2));
+        assert!(inbox.take_oldest().is_none());
+    }
+}